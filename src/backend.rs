@@ -0,0 +1,162 @@
+//! Where the data of a [`crate::FAlloc`] is kept.
+//!
+//! Databases loaded from files use a [`FileBackend`], and [`crate::FAlloc::in_memory`] uses a
+//! [`MemoryBackend`]. Anything else that can store bytes at offsets, like a block device or a
+//! test double that injects faults, can be used by implementing [`StorageBackend`] and passing it
+//! to [`crate::FAlloc::create_with_backend`].
+
+use std::{
+    fmt::{self, Debug},
+    fs::File,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+};
+
+/// Storage addressed by byte offsets, like a file.
+///
+/// Once [`Self::sync`] returned, everything written before must survive a crash, as the
+/// database relies on this to stay consistent.
+// mirrors File, which has no is_empty either
+#[allow(clippy::len_without_is_empty)]
+pub trait StorageBackend: Send {
+    /// Fills `buf` with the bytes starting at `offset`. Fails with
+    /// [`ErrorKind::UnexpectedEof`] if the storage ends before `buf` is full.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), io::Error>;
+
+    /// Writes all of `buf` starting at `offset`, growing the storage if it ends before that.
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), io::Error>;
+
+    /// Makes everything that was written durable.
+    fn sync(&mut self) -> Result<(), io::Error>;
+
+    /// The length of the storage in bytes.
+    fn len(&mut self) -> Result<u64, io::Error>;
+
+    /// Cuts the storage off at `len`, or grows it to `len` with zeros.
+    fn set_len(&mut self, len: u64) -> Result<(), io::Error>;
+}
+
+impl Debug for dyn StorageBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StorageBackend")
+    }
+}
+
+/// Keeps the data in a file.
+#[derive(Debug)]
+pub struct FileBackend {
+    pub(crate) file: File,
+}
+
+impl FileBackend {
+    /// Uses a file that was opened for reading and writing.
+    pub fn new(file: File) -> Self {
+        Self { file }
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), io::Error> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        self.file.sync_all()
+    }
+
+    fn len(&mut self) -> Result<u64, io::Error> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), io::Error> {
+        self.file.set_len(len)
+    }
+}
+
+/// Keeps the data in RAM, so it is gone once it is dropped.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    bytes: Vec<u8>,
+}
+
+impl StorageBackend for MemoryBackend {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        let start = offset as usize;
+        let Some(bytes) = self.bytes.get(start..start + buf.len()) else {
+            return Err(ErrorKind::UnexpectedEof.into());
+        };
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), io::Error> {
+        let start = offset as usize;
+        if self.bytes.len() < start + buf.len() {
+            self.bytes.resize(start + buf.len(), 0);
+        }
+        self.bytes[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn len(&mut self) -> Result<u64, io::Error> {
+        Ok(self.bytes.len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), io::Error> {
+        self.bytes.resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{faults::Faulty, Error, FAlloc, MemoryBackend};
+
+    #[test]
+    fn custom_backend() {
+        let backend = Faulty::default();
+        let log = MemoryBackend::default();
+        let db = FAlloc::create_with_backend(backend.clone(), log, 0, 16).unwrap();
+        db.set("a", vec![1; 100]).unwrap();
+        db.sync().unwrap();
+        backend.fail_reads(true);
+        assert!(matches!(db.get("a"), Err(Error::Io(_))));
+        backend.fail_reads(false);
+        assert_eq!(db.get("a").unwrap(), Some(vec![1; 100]));
+        db.shutdown().unwrap();
+
+        let db = FAlloc::new_with_backend(backend, MemoryBackend::default(), 0).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some(vec![1; 100]));
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn log_in_backend() {
+        let (data, log) = (Faulty::default(), Faulty::default());
+        let db = FAlloc::create_with_backend(data.clone(), log.clone(), 60_000, 16).unwrap();
+        // nothing is old enough to be written to the data, so every checkpoint keeps it all
+        for i in 0..5 {
+            db.set(&i.to_string(), vec![i; 100]).unwrap();
+            db.save().unwrap();
+        }
+        db.set("5", vec![5; 100]).unwrap();
+
+        // as if the process crashed now
+        let (data_copy, log_copy) = (data.crashed(), log.crashed());
+        db.shutdown().unwrap();
+        let db = FAlloc::new_with_backend(data_copy, log_copy, 0).unwrap();
+        for i in 0..6 {
+            assert_eq!(db.get(&i.to_string()).unwrap(), Some(vec![i; 100]));
+        }
+        db.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+//! Serves a database over TCP. See `microdb-server --help`.
+
+use std::{env, process};
+
+use microdb::{Error, FAlloc, Server};
+
+const USAGE: &str = "\
+Usage: microdb-server [OPTIONS] <ADDRESS> <DATA> [META]
+
+Serves the database in DATA and META over TCP on ADDRESS, for example 127.0.0.1:7878.
+Without META, DATA is a single-file database.
+
+Options:
+  --create <BLOCK_SIZE>  Create the database instead of loading it
+  --cache-period <MS>    How long values stay cached, in milliseconds (default: 1000)
+  --help                 Print this message";
+
+struct Options {
+    create: Option<usize>,
+    cache_period: u128,
+    address: String,
+    data: String,
+    meta: Option<String>,
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut create = None;
+    let mut cache_period = 1000;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" => return Err(String::new()),
+            "--create" => {
+                let value = args.next().ok_or("--create needs a block size")?;
+                create = Some(value.parse().map_err(|_| "invalid block size")?);
+            }
+            "--cache-period" => {
+                let value = args.next().ok_or("--cache-period needs a value")?;
+                cache_period = value.parse().map_err(|_| "invalid cache period")?;
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let (Some(address), Some(data), meta, None) = (
+        positional.next(),
+        positional.next(),
+        positional.next(),
+        positional.next(),
+    ) else {
+        return Err("expected an address and one or two files".to_owned());
+    };
+    Ok(Options {
+        create,
+        cache_period,
+        address,
+        data,
+        meta,
+    })
+}
+
+fn open(options: &Options) -> Result<FAlloc, Error> {
+    let Options {
+        create,
+        cache_period,
+        data,
+        meta,
+        ..
+    } = options;
+    match (create, meta) {
+        (None, None) => FAlloc::new_single_file(data, *cache_period),
+        (None, Some(meta)) => FAlloc::new(data, meta, *cache_period),
+        (Some(block_size), None) => FAlloc::create_single_file(data, *cache_period, *block_size),
+        (Some(block_size), Some(meta)) => FAlloc::create(data, meta, *cache_period, *block_size),
+    }
+}
+
+fn main() {
+    let options = match parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("error: {e}\n");
+            }
+            eprintln!("{USAGE}");
+            process::exit(2);
+        }
+    };
+    let result = open(&options).and_then(|db| {
+        let server = Server::bind(db, &options.address)?;
+        println!("Serving on {}", server.local_addr()?);
+        server.run()
+    });
+    // the write-ahead log makes it safe to simply kill the server
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        process::exit(1);
+    }
+}
@@ -0,0 +1,655 @@
+//! Integrity checking of a database.
+//!
+//! A check goes through the allocation table and the data file and reports every inconsistency
+//! it finds as a [`Problem`]. In repair mode, values that are damaged are moved below
+//! [`QUARANTINE`] with whatever data could still be read, and the free space is recomputed.
+
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Display},
+    io::ErrorKind,
+};
+
+use crate::{
+    backend::{FileBackend, StorageBackend},
+    compression,
+    data::{Escape, Path},
+    encryption::Keys,
+    format::DATA_HEADER_LEN,
+    storage::{self, Access, Allocation, AllocationTable, InvalidAllocations},
+    Corruption, Error, FormatError,
+};
+
+#[cfg(feature = "encryption")]
+use crate::Key;
+
+/// The path below which repairs put damaged values.
+pub const QUARANTINE: &str = "microdb-quarantine";
+/// The name problems use for the allocation table of a single-file database.
+const TABLE: &str = "(allocation table)";
+
+/// An inconsistency found by a check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Problem {
+    /// A path in the allocation table is not valid UTF-8.
+    InvalidPath { path: Vec<u8> },
+    /// Two values (or two parts of the same value) are stored in the same place. `path` is the
+    /// one that no longer matches its checksum, as the other one was written over it. If that
+    /// doesn't tell them apart, the overlap is reported for each of them.
+    Overlap { path: String, other: String },
+    /// A value is stored in space that is also marked as free.
+    InFreeSpace { path: String },
+    /// A value is stored outside of the space reserved in the data file.
+    OutOfBounds {
+        path: String,
+        start: usize,
+        len: usize,
+    },
+    /// Free space is recorded outside of the space reserved in the data file.
+    FreeOutOfBounds { start: usize, len: usize },
+    /// Space that is neither used nor free.
+    Leaked { start: usize, len: usize },
+    /// A value is larger than the space allocated for it.
+    TooSmall {
+        path: String,
+        full_size: usize,
+        capacity: usize,
+    },
+    /// The data file ends before a value does.
+    Truncated { path: String },
+    /// A value does not match its checksum.
+    Checksum {
+        path: String,
+        expected: u32,
+        found: u32,
+    },
+    /// A value matches its checksum, but could not be decompressed.
+    Compression { path: String },
+    /// A value of an encrypted database was not sealed with any of its keys for this path.
+    Authentication { path: String },
+}
+
+impl Problem {
+    /// The path whose value this problem damages, if any. Problems without one only affect the
+    /// bookkeeping of free space.
+    pub fn damaged(&self) -> Option<String> {
+        match self {
+            Problem::InvalidPath { path } => Some(String::from_utf8_lossy(path).into_owned()),
+            Problem::Overlap { path, .. }
+            | Problem::OutOfBounds { path, .. }
+            | Problem::TooSmall { path, .. }
+            | Problem::Truncated { path }
+            | Problem::Checksum { path, .. }
+            | Problem::Compression { path }
+            | Problem::Authentication { path } => Some(path.to_owned()),
+            Problem::InFreeSpace { .. }
+            | Problem::FreeOutOfBounds { .. }
+            | Problem::Leaked { .. } => None,
+        }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::InvalidPath { path } => write!(f, "The path {path:?} is not UTF-8."),
+            Problem::Overlap { path, other } if path == other => {
+                write!(f, "The value of {path:?} overlaps itself.")
+            }
+            Problem::Overlap { path, other } => {
+                write!(f, "The values of {path:?} and {other:?} overlap.")
+            }
+            Problem::InFreeSpace { path } => {
+                write!(f, "The value of {path:?} is stored in free space.")
+            }
+            Problem::OutOfBounds { path, start, len } => write!(
+                f,
+                "The value of {path:?} is stored at {start}+{len}, outside of the data file."
+            ),
+            Problem::FreeOutOfBounds { start, len } => {
+                write!(f, "Free space at {start}+{len} is outside of the data file.")
+            }
+            Problem::Leaked { start, len } => {
+                write!(f, "Space at {start}+{len} is neither used nor free.")
+            }
+            Problem::TooSmall {
+                path,
+                full_size,
+                capacity,
+            } => write!(
+                f,
+                "The value of {path:?} has {full_size} bytes, but only {capacity} are allocated."
+            ),
+            Problem::Truncated { path } => {
+                write!(f, "The data file ends before the value of {path:?} does.")
+            }
+            Problem::Checksum {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "The data of {path:?} is corrupt: expected checksum {expected:08x}, found {found:08x}."
+            ),
+            Problem::Compression { path } => {
+                write!(f, "The data of {path:?} could not be decompressed.")
+            }
+            Problem::Authentication { path } => {
+                write!(f, "The data of {path:?} was not encrypted for this path.")
+            }
+        }
+    }
+}
+
+/// The result of a check.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Everything that was found, in no particular order.
+    pub problems: Vec<Problem>,
+    /// Damaged values that were moved by a repair, as (old path, new path). Values that had no
+    /// data left to save are removed and not listed here.
+    pub quarantined: Vec<(String, String)>,
+}
+
+impl Report {
+    /// Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl AllocationTable {
+    /// Finds all problems in the table and the data file. `invalid` are the allocations whose
+    /// path could not be loaded.
+    pub(crate) fn check(
+        &self,
+        data: &mut dyn StorageBackend,
+        invalid: &[(Vec<u8>, Allocation)],
+    ) -> Result<Vec<Problem>, Error> {
+        let mut problems = Vec::new();
+        let table = self.extent().map(|extent| Allocation {
+            full_size: extent.1,
+            locations: vec![extent],
+            checksum: None,
+            codec: compression::NONE,
+        });
+        let names: Vec<_> = self
+            .map
+            .iter()
+            .map(|(path, allocation)| (path.to_owned(), allocation))
+            .chain(invalid.iter().map(|(path, allocation)| {
+                problems.push(Problem::InvalidPath {
+                    path: path.to_owned(),
+                });
+                (String::from_utf8_lossy(path).into_owned(), allocation)
+            }))
+            .chain(table.iter().map(|table| (TABLE.to_owned(), table)))
+            .collect();
+
+        let end = self.blocks_reserved * self.block_size;
+        let mut extents = Vec::new(); // start, length, index of the owner or None for free space
+        for (i, (path, allocation)) in names.iter().enumerate() {
+            for &(start, len) in &allocation.locations {
+                if start + len > end {
+                    problems.push(Problem::OutOfBounds {
+                        path: path.to_owned(),
+                        start,
+                        len,
+                    });
+                }
+                if len > 0 {
+                    extents.push((start, len, Some(i)));
+                }
+            }
+        }
+        for (start, len) in self.free.iter() {
+            if start + len > end {
+                problems.push(Problem::FreeOutOfBounds { start, len });
+            }
+            extents.push((start, len, None));
+        }
+        extents.sort();
+
+        // walk through the file, remembering the extent that reaches the furthest so far
+        let mut overlaps = BTreeSet::new();
+        let mut in_free = BTreeSet::new();
+        let mut reached = 0;
+        let mut reached_by = None;
+        for (start, len, owner) in extents {
+            if start < reached {
+                match (owner, reached_by) {
+                    (Some(a), Some(b)) => {
+                        overlaps.insert((a.min(b), a.max(b)));
+                    }
+                    (Some(a), None) | (None, Some(a)) => {
+                        in_free.insert(a);
+                    }
+                    // free space never overlaps itself
+                    (None, None) => (),
+                }
+            } else if start > reached && reached < end {
+                problems.push(Problem::Leaked {
+                    start: reached,
+                    len: start.min(end) - reached,
+                });
+            }
+            if start + len > reached {
+                reached = start + len;
+                reached_by = owner;
+            }
+        }
+        if reached < end {
+            problems.push(Problem::Leaked {
+                start: reached,
+                len: end - reached,
+            });
+        }
+        for (a, b) in overlaps {
+            let damaged = match (a == b, intact(&names[a], data), intact(&names[b], data)) {
+                (true, ..) => vec![(a, b)],
+                (false, true, false) => vec![(b, a)],
+                (false, false, true) => vec![(a, b)],
+                _ => vec![(a, b), (b, a)],
+            };
+            for (path, other) in damaged {
+                problems.push(Problem::Overlap {
+                    path: names[path].0.to_owned(),
+                    other: names[other].0.to_owned(),
+                });
+            }
+        }
+        for a in in_free {
+            problems.push(Problem::InFreeSpace {
+                path: names[a].0.to_owned(),
+            });
+        }
+
+        let damaged: BTreeSet<_> = problems.iter().filter_map(Problem::damaged).collect();
+        for (path, allocation) in &self.map {
+            let capacity = allocation.locations.iter().map(|x| x.1).sum();
+            if allocation.full_size > capacity {
+                problems.push(Problem::TooSmall {
+                    path: path.to_owned(),
+                    full_size: allocation.full_size,
+                    capacity,
+                });
+                continue;
+            }
+            if damaged.contains(path) {
+                continue;
+            }
+            let decoded = allocation
+                .get_stored(path, data)
+                .and_then(|stored| allocation.decode(path, stored, &self.keys));
+            match decoded {
+                Ok(_) => (),
+                Err(Error::Corrupt(Corruption::Checksum {
+                    path,
+                    expected,
+                    found,
+                })) => problems.push(Problem::Checksum {
+                    path,
+                    expected,
+                    found,
+                }),
+                Err(Error::Corrupt(Corruption::Compression { path })) => {
+                    problems.push(Problem::Compression { path })
+                }
+                Err(Error::Corrupt(Corruption::Authentication { path })) => {
+                    problems.push(Problem::Authentication { path })
+                }
+                // only the stored bytes can be checked without support for the codec
+                Err(Error::Format(FormatError::UnsupportedCodec(_))) => (),
+                Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    problems.push(Problem::Truncated {
+                        path: path.to_owned(),
+                    })
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Reads whatever is left of all values damaged by the problems, and of the invalid
+    /// allocations. The table isn't changed.
+    pub(crate) fn salvage_damaged(
+        &self,
+        data: &mut dyn StorageBackend,
+        problems: &[Problem],
+        invalid: &InvalidAllocations,
+    ) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let damaged: BTreeSet<_> = problems
+            .iter()
+            .filter(|x| !matches!(x, Problem::InvalidPath { .. }))
+            .filter_map(Problem::damaged)
+            .collect();
+        let mut salvaged = Vec::new();
+        for path in damaged {
+            if let Some(allocation) = self.map.get(&path) {
+                let bytes = salvage(&path, allocation, data, &self.keys)?;
+                salvaged.push((path, bytes));
+            }
+        }
+        for (path, allocation) in invalid {
+            let path = String::from_utf8_lossy(path).into_owned();
+            let bytes = salvage(&path, allocation, data, &self.keys)?;
+            salvaged.push((path, bytes));
+        }
+        Ok(salvaged)
+    }
+
+    /// Picks where each salvaged value goes below [`QUARANTINE`]. Values that are empty are
+    /// only removed.
+    pub(crate) fn quarantine_targets(&self, salvaged: &[(String, Vec<u8>)]) -> Vec<Option<String>> {
+        let mut taken = BTreeSet::new();
+        salvaged
+            .iter()
+            .map(|(path, bytes)| {
+                if bytes.is_empty() {
+                    return None;
+                }
+                let mut target = QUARANTINE.sub_path(Escape(path.as_str()));
+                while self.map.contains_key(&target) || taken.contains(&target) {
+                    target.push('~');
+                }
+                taken.insert(target.clone());
+                Some(target)
+            })
+            .collect()
+    }
+
+    /// Moves all values damaged by the problems below [`QUARANTINE`] and recomputes the free
+    /// space, directly in the table. Only for databases that aren't open, as nothing is logged.
+    /// The table must be saved afterwards.
+    pub(crate) fn repair(
+        &mut self,
+        data: &mut dyn StorageBackend,
+        problems: &[Problem],
+        invalid: InvalidAllocations,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let salvaged = self.salvage_damaged(data, problems, &invalid)?;
+        let targets = self.quarantine_targets(&salvaged);
+        for (path, _) in &salvaged {
+            self.remove(path);
+        }
+        self.rebuild_free();
+
+        let mut quarantined = Vec::new();
+        for ((path, bytes), target) in salvaged.into_iter().zip(targets) {
+            let Some(target) = target else {
+                continue;
+            };
+            let mut allocation = Allocation {
+                full_size: 0,
+                locations: Vec::new(),
+                checksum: None,
+                codec: compression::NONE,
+            };
+            self.store(&mut allocation, &target, data, bytes)?;
+            self.insert(target.to_owned(), allocation);
+            quarantined.push((path, target));
+        }
+        Ok(quarantined)
+    }
+}
+
+/// Whether a value still matches its checksum.
+fn intact((path, allocation): &(String, &Allocation), data: &mut dyn StorageBackend) -> bool {
+    allocation.get_stored(path, data).is_ok()
+}
+
+/// Reads whatever is left of a damaged value. Parts that can't be read are zeroed. Encrypted or
+/// compressed values are decoded if they still can be, and kept as they are stored otherwise.
+fn salvage(
+    path: &str,
+    allocation: &Allocation,
+    data: &mut dyn StorageBackend,
+    keys: &Keys,
+) -> Result<Vec<u8>, Error> {
+    let file_len = data.len()?.saturating_sub(DATA_HEADER_LEN) as usize;
+    let mut bytes = vec![0_u8; allocation.full_size];
+    let mut i = 0;
+    for &(start, len) in &allocation.locations {
+        let end = (i + len).min(allocation.full_size);
+        let readable = end.min(i + file_len.saturating_sub(start));
+        if readable > i {
+            data.read_at(DATA_HEADER_LEN + start as u64, &mut bytes[i..readable])?;
+        }
+        i = end;
+    }
+    Ok(allocation
+        .decode(path, bytes.clone(), keys)
+        .unwrap_or(bytes))
+}
+
+/// Checks a database that is not currently open. With `repair`, damaged values are moved below
+/// [`QUARANTINE`] and the free space is recomputed. Mutations that are still in the write-ahead
+/// log are not checked; they are applied the next time the database is loaded.
+pub fn check_files<S: ToString>(data: S, alloc: S, repair: bool) -> Result<Report, Error> {
+    check_files_with(data.to_string(), alloc.to_string(), repair, Keys::default())
+}
+
+/// Checks an encrypted database that is not currently open with its current key, like
+/// [`check_files`].
+#[cfg(feature = "encryption")]
+pub fn check_files_encrypted<S: ToString>(
+    data: S,
+    alloc: S,
+    repair: bool,
+    key: Key,
+) -> Result<Report, Error> {
+    check_files_with(data.to_string(), alloc.to_string(), repair, Keys::new(key))
+}
+
+fn check_files_with(
+    data: String,
+    alloc: String,
+    repair: bool,
+    keys: Keys,
+) -> Result<Report, Error> {
+    let (table, invalid, data) = storage::open_pair(&data, alloc, keys, Access::Exclusive)?;
+    check_loaded(table, data, invalid, repair)
+}
+
+/// Checks a single-file database that is not currently open, like [`check_files`].
+pub fn check_single_file<S: ToString>(path: S, repair: bool) -> Result<Report, Error> {
+    check_single_file_with(path.to_string(), repair, Keys::default())
+}
+
+/// Checks an encrypted single-file database that is not currently open with its current key,
+/// like [`check_files`].
+#[cfg(feature = "encryption")]
+pub fn check_single_file_encrypted<S: ToString>(
+    path: S,
+    repair: bool,
+    key: Key,
+) -> Result<Report, Error> {
+    check_single_file_with(path.to_string(), repair, Keys::new(key))
+}
+
+fn check_single_file_with(path: String, repair: bool, keys: Keys) -> Result<Report, Error> {
+    let mut data = storage::open_data(&path, Access::Exclusive)?;
+    let (table, invalid) = AllocationTable::load_embedded(&mut data, keys)?;
+    check_loaded(table, data, invalid, repair)
+}
+
+fn check_loaded(
+    mut table: AllocationTable,
+    mut data: FileBackend,
+    invalid: InvalidAllocations,
+    repair: bool,
+) -> Result<Report, Error> {
+    let problems = table.check(&mut data, &invalid)?;
+    let mut report = Report {
+        problems,
+        quarantined: Vec::new(),
+    };
+    if repair {
+        report.quarantined = table.repair(&mut data, &report.problems, invalid)?;
+        data.sync()?;
+        table.save(&mut data)?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::File,
+        io::{Read, Seek, SeekFrom, Write},
+    };
+
+    use crate::{
+        backend::FileBackend, check_files, cleanup::Files, encryption::Keys,
+        format::DATA_HEADER_LEN, storage::AllocationTable, FAlloc, Problem,
+    };
+
+    #[test]
+    fn detect_and_repair() {
+        let _files = Files::db("check");
+        let db = FAlloc::create("check.dat", "check.alloc", 0, 16).unwrap();
+        db.set("a", vec![1; 40]).unwrap();
+        db.set("b", vec![2; 40]).unwrap();
+        db.set("c", vec![3; 40]).unwrap();
+        db.shutdown().unwrap();
+        assert!(check_files("check.dat", "check.alloc", false)
+            .unwrap()
+            .is_ok());
+
+        // point b at a's data, leaking b's old space, and damage c
+        let (mut table, _) =
+            AllocationTable::load("check.alloc".to_owned(), Keys::default()).unwrap();
+        let a = table.map["a"].locations.clone();
+        let c = table.map["c"].locations[0];
+        table.get_mut("b").unwrap().locations = a;
+        let mut data = File::options()
+            .read(true)
+            .write(true)
+            .open("check.dat")
+            .unwrap();
+        let mut backend = FileBackend::new(data.try_clone().unwrap());
+        table.save(&mut backend).unwrap();
+        let mut byte = [0];
+        data.seek(SeekFrom::Start(DATA_HEADER_LEN + c.0 as u64 + 5))
+            .unwrap();
+        data.read_exact(&mut byte).unwrap();
+        data.seek(SeekFrom::Start(DATA_HEADER_LEN + c.0 as u64 + 5))
+            .unwrap();
+        data.write_all(&[!byte[0]]).unwrap();
+        drop(data);
+
+        let report = check_files("check.dat", "check.alloc", false).unwrap();
+        // b was written over by a, which is intact
+        assert!(report.problems.contains(&Problem::Overlap {
+            path: "b".to_owned(),
+            other: "a".to_owned()
+        }));
+        assert!(!report
+            .problems
+            .iter()
+            .any(|x| x.damaged().as_deref() == Some("a")));
+        assert!(report
+            .problems
+            .contains(&Problem::Leaked { start: 48, len: 48 }));
+        assert!(report
+            .problems
+            .iter()
+            .any(|x| matches!(x, Problem::Checksum { path, .. } if path == "c")));
+        assert!(report.quarantined.is_empty());
+
+        let report = check_files("check.dat", "check.alloc", true).unwrap();
+        assert_eq!(report.quarantined.len(), 2);
+        let db = FAlloc::new("check.dat", "check.alloc", 0).unwrap();
+        assert!(db.check(false).unwrap().is_ok());
+        assert_eq!(db.get("a").unwrap(), Some(vec![1; 40]));
+        assert_eq!(db.get("microdb-quarantine/b").unwrap(), Some(vec![1; 40]));
+        let mut c = vec![3; 40];
+        c[5] = !3;
+        assert_eq!(db.get("microdb-quarantine/c").unwrap(), Some(c));
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn repair_while_open() {
+        let _files = Files::db("check.open");
+        let db = FAlloc::create("check.open.dat", "check.open.alloc", 0, 16).unwrap();
+        db.set("a", vec![1; 40]).unwrap();
+        db.set("c", vec![3; 40]).unwrap();
+        db.shutdown().unwrap();
+        let (table, _) =
+            AllocationTable::load("check.open.alloc".to_owned(), Keys::default()).unwrap();
+        let c = table.map["c"].locations[0];
+        let mut data = File::options()
+            .read(true)
+            .write(true)
+            .open("check.open.dat")
+            .unwrap();
+        data.seek(SeekFrom::Start(DATA_HEADER_LEN + c.0 as u64 + 5))
+            .unwrap();
+        data.write_all(&[!3]).unwrap();
+        drop(data);
+        let db = FAlloc::new("check.open.dat", "check.open.alloc", 0).unwrap();
+
+        // the repair is a mutation like any other, so snapshots keep the old state
+        let snapshot = db.snapshot().unwrap();
+        let report = db.check(true).unwrap();
+        let target = "microdb-quarantine/c".to_owned();
+        assert_eq!(report.quarantined, vec![("c".to_owned(), target.clone())]);
+        let mut damaged = vec![3; 40];
+        damaged[5] = !3;
+        assert_eq!(
+            db.snapshot_get(&snapshot, "c").unwrap(),
+            Some(damaged.clone())
+        );
+        assert_eq!(db.snapshot_get(&snapshot, &target).unwrap(), None);
+        assert_eq!(db.get("c").unwrap(), None);
+        assert_eq!(db.get(&target).unwrap(), Some(damaged.clone()));
+        assert!(db.check(false).unwrap().is_ok());
+        db.shutdown().unwrap();
+
+        let db = FAlloc::new("check.open.dat", "check.open.alloc", 0).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some(vec![1; 40]));
+        assert_eq!(db.get(&target).unwrap(), Some(damaged));
+        db.shutdown().unwrap();
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn undecodable() {
+        let _files = Files::db("check.lz4");
+        let db = FAlloc::create("check.lz4.dat", "check.lz4.alloc", 0, 16).unwrap();
+        db.set("a", vec![1; 4096]).unwrap();
+        db.shutdown().unwrap();
+
+        // garbage that matches its checksum, so only decompressing it fails
+        let (mut table, _) =
+            AllocationTable::load("check.lz4.alloc".to_owned(), Keys::default()).unwrap();
+        let mut data = FileBackend::new(
+            File::options()
+                .read(true)
+                .write(true)
+                .open("check.lz4.dat")
+                .unwrap(),
+        );
+        let a = table.get_mut("a").unwrap();
+        assert_ne!(a.codec, crate::compression::NONE);
+        let garbage = vec![0xff; a.full_size];
+        a.set_data(&mut data, garbage).unwrap();
+        table.save(&mut data).unwrap();
+        drop(data);
+
+        let report = check_files("check.lz4.dat", "check.lz4.alloc", true).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![Problem::Compression {
+                path: "a".to_owned()
+            }]
+        );
+        assert_eq!(report.quarantined.len(), 1);
+        assert!(check_files("check.lz4.dat", "check.lz4.alloc", false)
+            .unwrap()
+            .is_ok());
+    }
+}
@@ -0,0 +1,35 @@
+//! Removing the files a test creates, whether it passes or not.
+
+use std::fs;
+
+/// Removes its files when it is dropped, so they are gone even after a failed assertion. They
+/// are removed when it is created as well, in case an earlier run was killed. Files that don't
+/// exist are skipped.
+pub(crate) struct Files(Vec<String>);
+
+impl Files {
+    /// The files `<name>.<extension>`, for each of the extensions.
+    pub(crate) fn new(name: &str, extensions: &[&str]) -> Self {
+        let files = Self(extensions.iter().map(|x| format!("{name}.{x}")).collect());
+        files.remove();
+        files
+    }
+
+    /// The files of a database made with [`crate::FAlloc::create`]`("<name>.dat",
+    /// "<name>.alloc", ..)`, including its log.
+    pub(crate) fn db(name: &str) -> Self {
+        Self::new(name, &["dat", "alloc", "alloc.wal"])
+    }
+
+    fn remove(&self) {
+        for file in &self.0 {
+            let _ = fs::remove_file(file);
+        }
+    }
+}
+
+impl Drop for Files {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
@@ -0,0 +1,353 @@
+//! Using a database that is served by a [`crate::Server`].
+
+use std::{
+    io::{self, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    ops::Deref,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    protocol::{Request, Response, MAGIC, PROTOCOL_VERSION},
+    Error, MicroDB,
+};
+
+/// How many idle connections are kept open for later requests.
+const MAX_IDLE: usize = 8;
+/// How often a request is tried with a new connection before giving up.
+const ATTEMPTS: u32 = 3;
+/// How long to wait before the first retry. Doubles with every attempt.
+const BACKOFF: Duration = Duration::from_millis(50);
+/// How long to wait for the server to accept a connection, or to take or answer a request.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A database on a [`crate::Server`]. It dereferences to a [`MicroDB`], so it is used exactly
+/// like a local one, and existing [`crate::data::ComObj`] implementations work with it.
+///
+/// Connections are pooled, so it can be shared between threads. A request that fails because
+/// its connection broke is retried with a new one, which makes the client survive restarts of the
+/// server. Writes are only retried if they weren't sent yet: the server may already have applied
+/// one whose answer was lost, and sending it again could overwrite a newer write of another
+/// client. They fail with the [`Error::Io`] instead, and the caller has to find out whether they
+/// happened. Transactions, snapshots, and maintenance like [`MicroDB::compact`] fail with
+/// [`Error::Unsupported`].
+pub struct RemoteDB {
+    db: MicroDB,
+}
+
+impl RemoteDB {
+    /// Connects to a server, failing if it can't be reached.
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<Self, Error> {
+        let client = Client {
+            pool: Arc::new(Pool {
+                addresses: address.to_socket_addrs()?.collect(),
+                idle: Mutex::new(Vec::new()),
+            }),
+        };
+        let connection = client.pool.open()?;
+        client.pool.release(connection)?;
+        Ok(Self {
+            db: MicroDB::remote(client),
+        })
+    }
+}
+
+impl Deref for RemoteDB {
+    type Target = MicroDB;
+
+    fn deref(&self) -> &MicroDB {
+        &self.db
+    }
+}
+
+type Connection = BufReader<TcpStream>;
+
+struct Pool {
+    addresses: Vec<SocketAddr>,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl Pool {
+    /// Connects to the first address of the server that accepts the connection in time.
+    fn open(&self) -> Result<Connection, io::Error> {
+        let mut error = io::Error::from(ErrorKind::InvalidInput);
+        let mut stream = None;
+        for address in &self.addresses {
+            match TcpStream::connect_timeout(address, TIMEOUT) {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                }
+                Err(e) => error = e,
+            }
+        }
+        let stream = stream.ok_or(error)?;
+        stream.set_nodelay(true)?;
+        // a hung server must fail the request instead of blocking the caller forever
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut connection = BufReader::new(stream);
+        let mut handshake = [0_u8; 16];
+        connection.read_exact(&mut handshake)?;
+        if &handshake[..8] != MAGIC || handshake[8..] != PROTOCOL_VERSION.to_be_bytes() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "not a compatible MicroDB server",
+            ));
+        }
+        Ok(connection)
+    }
+
+    /// Takes an idle connection, dropping the ones the server has closed in the meantime.
+    fn take(&self) -> Result<Option<Connection>, Error> {
+        let mut idle = self.idle.lock().map_err(|_| Error::Poisoned)?;
+        while let Some(connection) = idle.pop() {
+            if alive(&connection) {
+                return Ok(Some(connection));
+            }
+        }
+        Ok(None)
+    }
+
+    fn release(&self, connection: Connection) -> Result<(), Error> {
+        let mut idle = self.idle.lock().map_err(|_| Error::Poisoned)?;
+        if idle.len() < MAX_IDLE {
+            idle.push(connection);
+        }
+        Ok(())
+    }
+}
+
+/// Whether an idle connection is still open. The server never sends anything unasked, so anything
+/// to read means it closed the connection or broke the protocol.
+fn alive(connection: &Connection) -> bool {
+    let stream = connection.get_ref();
+    if !connection.buffer().is_empty() || stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let idle = matches!(stream.peek(&mut [0]), Err(e) if e.kind() == ErrorKind::WouldBlock);
+    stream.set_nonblocking(false).is_ok() && idle
+}
+
+/// The storage of a [`RemoteDB`].
+#[derive(Clone)]
+pub(crate) struct Client {
+    pool: Arc<Pool>,
+}
+
+impl Client {
+    /// Sends a request, retrying it on new connections if that fails. Reads are always retried,
+    /// writes only if they weren't sent: another client may have written the same path since the
+    /// server applied one, so sending it twice doesn't have the same effect as sending it once.
+    fn call(&self, request: Request) -> Result<Response, Error> {
+        let encoded = request.encode();
+        let read = matches!(request, Request::Get { .. } | Request::Paths { .. });
+        let mut attempts = 0;
+        loop {
+            let pooled = self.pool.take()?;
+            let fresh = pooled.is_none();
+            let mut sent = false;
+            let result = pooled
+                .map_or_else(|| self.pool.open(), Ok)
+                .and_then(|mut c| {
+                    c.get_mut().write_all(&encoded)?;
+                    sent = true;
+                    let response = Response::read(&request, &mut c)?;
+                    Ok((c, response))
+                });
+            match result {
+                Ok((connection, response)) => {
+                    self.pool.release(connection)?;
+                    return response;
+                }
+                Err(e) if sent && !read => return Err(e.into()),
+                // idle connections may have been closed by the server in the meantime
+                Err(_) if !fresh => (),
+                Err(e) => {
+                    attempts += 1;
+                    if attempts == ATTEMPTS {
+                        return Err(e.into());
+                    }
+                    thread::sleep(BACKOFF * 2_u32.pow(attempts - 1));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        let path = path.to_owned();
+        match self.call(Request::Get { path })? {
+            Response::Value(value) => Ok(value),
+            _ => Err(io::Error::from(ErrorKind::InvalidData).into()),
+        }
+    }
+
+    pub(crate) fn set(&self, path: &str, data: Vec<u8>) -> Result<(), Error> {
+        let path = path.to_owned();
+        let request = if data.is_empty() {
+            Request::Remove { path }
+        } else {
+            Request::Set { path, data }
+        };
+        self.call(request)?;
+        Ok(())
+    }
+
+    pub(crate) fn delete_substructure(&self, path: &str) -> Result<(), Error> {
+        let path = path.to_owned();
+        self.call(Request::DeleteSubstructure { path })?;
+        Ok(())
+    }
+
+    pub(crate) fn paths(&self, path: Option<&str>, direct: bool) -> Result<Vec<String>, Error> {
+        let path = path.map(str::to_owned);
+        match self.call(Request::Paths { path, all: !direct })? {
+            Response::Paths(paths) => Ok(paths),
+            _ => Err(io::Error::from(ErrorKind::InvalidData).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Write,
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Instant,
+    };
+
+    use super::TIMEOUT;
+    use crate::{
+        cleanup::Files,
+        data::{ComObj, Path},
+        extract,
+        protocol::{MAGIC, PROTOCOL_VERSION},
+        Error, FAlloc, MicroDB, RemoteDB, Server,
+    };
+
+    #[derive(Debug, PartialEq)]
+    struct User {
+        name: String,
+        friends: Vec<u64>,
+    }
+
+    impl ComObj for User {
+        fn to_db<P: Path>(self, path: P, db: &MicroDB) -> Result<(), std::io::Error> {
+            db.set_raw(path.sub_path("name"), self.name)?;
+            db.set_com(path.sub_path("friends"), self.friends)?;
+            Ok(())
+        }
+
+        fn remove<P: Path>(path: P, db: &MicroDB) -> Result<(), std::io::Error> {
+            db.remove_raw(path.sub_path("name"))?;
+            db.remove_com::<Vec<u64>, _>(path.sub_path("friends"))?;
+            Ok(())
+        }
+
+        fn from_db<P: Path>(path: P, db: &MicroDB) -> Result<Option<Self>, std::io::Error> {
+            Ok(Some(Self {
+                name: extract!(db.get_raw(path.sub_path("name"))),
+                friends: extract!(db.get_com(path.sub_path("friends"))),
+            }))
+        }
+    }
+
+    /// Serves the database until `f` returns.
+    fn serve(db: FAlloc, address: SocketAddr, f: impl FnOnce(SocketAddr) + Send) -> FAlloc {
+        let server = Server::bind(db, address).unwrap();
+        let address = server.local_addr().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| server.run().unwrap());
+            let result = s.spawn(|| f(address)).join();
+            server.stop();
+            result.unwrap();
+        });
+        server.into_inner()
+    }
+
+    #[test]
+    fn remote() {
+        let _files = Files::new("client.test", &["dmdb", "mmdb", "mmdb.wal"]);
+        let db = FAlloc::create("client.test.dmdb", "client.test.mmdb", 100, 16).unwrap();
+        let mut remote = None;
+        let mut address = "127.0.0.1:0".parse().unwrap();
+        let db = serve(db, address, |a| {
+            address = a;
+            let db = RemoteDB::connect(a).unwrap();
+            thread::scope(|s| {
+                for i in 0..4_u64 {
+                    let db = &db;
+                    s.spawn(move || {
+                        let user = User {
+                            name: format!("user {i}"),
+                            friends: (0..i).collect(),
+                        };
+                        db.set_com(format!("users/{i}"), user).unwrap();
+                    });
+                }
+            });
+            assert_eq!(db.get_paths(Some("users/1")).unwrap().len(), 2);
+            db.remove_com::<User, _>("users/0").unwrap();
+            assert_eq!(db.get_com::<User, _>("users/0").unwrap(), None);
+            assert!(matches!(db.compact(), Err(Error::Unsupported)));
+            remote = Some(db);
+        });
+
+        // the pooled connections were closed with the server
+        let db = serve(db, address, |_| {
+            let remote = remote.unwrap();
+            assert_eq!(
+                remote.get_com::<User, _>("users/3").unwrap(),
+                Some(User {
+                    name: "user 3".to_owned(),
+                    friends: vec![0, 1, 2],
+                })
+            );
+        });
+
+        // errors arrive as the variant they were on the server
+        db.shutdown().unwrap();
+        let db = FAlloc::new_shared("client.test.dmdb", "client.test.mmdb", 100).unwrap();
+        let db = serve(db, address, |a| {
+            let remote = RemoteDB::connect(a).unwrap();
+            assert!(matches!(
+                remote.set_raw("users/3/name", "user 4".to_owned()),
+                Err(Error::ReadOnly)
+            ));
+        });
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn stalled_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = AtomicUsize::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                // takes requests without ever answering them
+                let mut streams = Vec::new();
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    if connections.fetch_add(1, Ordering::SeqCst) == 1 {
+                        break;
+                    }
+                    stream.write_all(MAGIC).unwrap();
+                    stream.write_all(&PROTOCOL_VERSION.to_be_bytes()).unwrap();
+                    streams.push(stream);
+                }
+            });
+            let db = RemoteDB::connect(address).unwrap();
+            let start = Instant::now();
+            assert!(matches!(db.set_raw("a", 1_u8), Err(Error::Io(_))));
+            assert!(start.elapsed() < TIMEOUT * 2);
+            // the write may have happened, so it wasn't sent again
+            assert_eq!(connections.load(Ordering::SeqCst), 1);
+            TcpStream::connect(address).unwrap();
+        });
+    }
+}
@@ -0,0 +1,85 @@
+//! Compression of stored values (the `compression` feature).
+//!
+//! Every allocation records the codec its value was stored with, so values written without the
+//! feature, or before it existed, are read back as they are. Only large values that
+//! actually shrink are compressed; the rest are stored uncompressed.
+
+#[cfg(feature = "compression")]
+use crate::Corruption;
+use crate::{Error, FormatError};
+
+/// The value is stored as it is.
+pub(crate) const NONE: u8 = 0;
+/// The value is LZ4 compressed, prefixed with its uncompressed length.
+#[cfg_attr(not(feature = "compression"), allow(dead_code))]
+pub(crate) const LZ4: u8 = 1;
+
+/// Values smaller than this are not worth compressing.
+#[cfg(feature = "compression")]
+const THRESHOLD: usize = 512;
+
+/// Returns the codec and bytes to store a value with.
+#[cfg(feature = "compression")]
+pub(crate) fn compress(value: Vec<u8>) -> (u8, Vec<u8>) {
+    if value.len() >= THRESHOLD {
+        let compressed = lz4_flex::compress_prepend_size(&value);
+        if compressed.len() < value.len() {
+            return (LZ4, compressed);
+        }
+    }
+    (NONE, value)
+}
+
+/// Returns the codec and bytes to store a value with.
+#[cfg(not(feature = "compression"))]
+pub(crate) fn compress(value: Vec<u8>) -> (u8, Vec<u8>) {
+    (NONE, value)
+}
+
+/// Restores a value from the bytes it was stored as.
+#[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+pub(crate) fn decompress(codec: u8, stored: Vec<u8>, path: &str) -> Result<Vec<u8>, Error> {
+    match codec {
+        NONE => Ok(stored),
+        #[cfg(feature = "compression")]
+        LZ4 => lz4_flex::decompress_size_prepended(&stored).map_err(|_| {
+            Corruption::Compression {
+                path: path.to_owned(),
+            }
+            .into()
+        }),
+        codec => Err(FormatError::UnsupportedCodec(codec).into()),
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod test {
+    use std::fs;
+
+    use crate::{cleanup::Files, FAlloc};
+
+    #[test]
+    fn compressed_values() {
+        let _files = Files::db("compression");
+        // not compressible, so it is stored as it is
+        let noise: Vec<u8> = (0..10_000_u32)
+            .map(|x| (x.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let db = FAlloc::create("compression.dat", "compression.alloc", 0, 16).unwrap();
+        db.set("small", vec![1; 100]).unwrap();
+        db.set("large", vec![2; 1_000_000]).unwrap();
+        db.set("noise", noise.clone()).unwrap();
+        db.save().unwrap();
+        let len = fs::metadata("compression.dat").unwrap().len();
+        assert!(len < 100_000, "{len}");
+        db.compact().unwrap();
+        assert!(db.check(false).unwrap().problems.is_empty());
+        db.shutdown().unwrap();
+
+        let db = FAlloc::new("compression.dat", "compression.alloc", 0).unwrap();
+        assert_eq!(db.get("small").unwrap(), Some(vec![1; 100]));
+        assert_eq!(db.get("large").unwrap(), Some(vec![2; 1_000_000]));
+        assert_eq!(db.get("noise").unwrap(), Some(noise));
+        db.shutdown().unwrap();
+    }
+}
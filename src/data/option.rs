@@ -42,10 +42,11 @@ where
     fn to_db<P: Path>(self, path: P, db: &MicroDB) -> Result<(), std::io::Error> {
         db.set_raw(path.sub_path("type"), self.is_some())?;
         if let Some(x) = self {
-            db.set_com(path.sub_path("data"), x)
+            db.set_com(path.sub_path("data"), x)?;
         } else {
-            db.remove_raw(path.sub_path("data"))
+            db.remove_raw(path.sub_path("data"))?;
         }
+        Ok(())
     }
 
     fn remove<P: Path>(path: P, db: &MicroDB) -> Result<(), std::io::Error> {
@@ -48,9 +48,10 @@ where
     fn to_db<P: Path>(self, path: P, db: &MicroDB) -> Result<(), io::Error> {
         db.set_raw(path.sub_path("type"), self.is_ok())?;
         match self {
-            Ok(x) => db.set_com(path.sub_path("data"), x),
-            Err(x) => db.set_com(path.sub_path("data"), x),
+            Ok(x) => db.set_com(path.sub_path("data"), x)?,
+            Err(x) => db.set_com(path.sub_path("data"), x)?,
         }
+        Ok(())
     }
 
     fn remove<P: Path>(path: P, db: &MicroDB) -> Result<(), io::Error> {
@@ -56,7 +56,7 @@ pub trait ComObj: Sized {
     /// function [`MicroDB::get_paths`]. MUST NOT return indirect sub-paths (sub-paths of
     /// sub-paths)
     fn paths<P: Path>(path: P, db: &MicroDB) -> Result<Vec<String>, io::Error> {
-        db.get_paths(Some(path))
+        Ok(db.get_paths(Some(path))?)
     }
 }
 
@@ -69,15 +69,15 @@ where
     T: AutoComObj,
 {
     fn to_db<P: Path>(self, path: P, db: &MicroDB) -> Result<(), io::Error> {
-        db.set_raw(path, self)
+        Ok(db.set_raw(path, self)?)
     }
 
     fn remove<P: Path>(path: P, db: &MicroDB) -> Result<(), io::Error> {
-        db.remove_raw(path)
+        Ok(db.remove_raw(path)?)
     }
 
     fn from_db<P: Path>(path: P, db: &MicroDB) -> Result<Option<Self>, io::Error> {
-        db.get_raw(path)
+        Ok(db.get_raw(path)?)
     }
 
     fn paths<P: Path>(_path: P, _db: &MicroDB) -> Result<Vec<String>, io::Error> {
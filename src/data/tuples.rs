@@ -45,7 +45,7 @@ macro_rules! impl_tuple {
                     db.set_com(path.sub_path(i), ident!(v $tvarn))?;
                     i += 1;
                 )*
-                db.set_raw_hard(path, i)
+                Ok(db.set_raw_hard(path, i)?)
             }
 
             fn remove<P: Path>(path: P, db: &MicroDB) -> Result<(), io::Error> {
@@ -103,34 +103,28 @@ impl_tuple!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, Q, R, S, T);
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
-
     use crate::MicroDB;
 
     #[test]
     fn test_0() {
-        let db = MicroDB::create("tuples.test0.dmdb", "tuples.test0.mmdb", 100, 100).unwrap();
+        let db = MicroDB::in_memory().unwrap();
         db.set_raw("0tuple", ()).unwrap();
         assert_eq!(db.get_raw("0tuple").unwrap() as Option<()>, None);
         db.shutdown().unwrap();
-        fs::remove_file("tuples.test0.dmdb").unwrap();
-        fs::remove_file("tuples.test0.mmdb").unwrap();
     }
     #[test]
     fn test_5() {
-        let db = MicroDB::create("tuples.test5.dmdb", "tuples.test5.mmdb", 100, 100).unwrap();
+        let db = MicroDB::in_memory().unwrap();
         db.set_raw("5tuple", (1u8, 2u16, 3u8, 4u32, 5u128)).unwrap();
         assert_eq!(
             db.get_raw("5tuple").unwrap(),
             Some((1u8, 2u16, 3u8, 4u32, 5u128))
         );
         db.shutdown().unwrap();
-        fs::remove_file("tuples.test5.dmdb").unwrap();
-        fs::remove_file("tuples.test5.mmdb").unwrap();
     }
     #[test]
     fn test_10() {
-        let db = MicroDB::create("tuples.test10.dmdb", "tuples.test10.mmdb", 100, 100).unwrap();
+        let db = MicroDB::in_memory().unwrap();
         db.set_raw(
             "10tuple",
             (
@@ -163,12 +157,10 @@ mod tests {
             ))
         );
         db.shutdown().unwrap();
-        fs::remove_file("tuples.test10.dmdb").unwrap();
-        fs::remove_file("tuples.test10.mmdb").unwrap();
     }
     #[test]
     fn test_10_com() {
-        let db = MicroDB::create("tuples.test10c.dmdb", "tuples.test10c.mmdb", 100, 100).unwrap();
+        let db = MicroDB::in_memory().unwrap();
         db.set_com(
             "10tuple",
             (
@@ -201,7 +193,5 @@ mod tests {
             ))
         );
         db.shutdown().unwrap();
-        fs::remove_file("tuples.test10c.dmdb").unwrap();
-        fs::remove_file("tuples.test10c.mmdb").unwrap();
     }
 }
@@ -1,18 +1,108 @@
-use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
 
-use crate::FAlloc;
+use crate::{
+    client::Client, storage::Backup, transaction::Overlay, Error, FAlloc, RecoveryPolicy, Report,
+    StorageBackend,
+};
 
 use crate::data::*;
 
+#[cfg(feature = "encryption")]
+use crate::Key;
+
 pub struct MicroDB {
-    storage: FAlloc,
+    storage: Storage,
+    view: View,
+}
+
+/// Where a [`MicroDB`] keeps its data.
+enum Storage {
+    Local(FAlloc),
+    /// A [`crate::Server`] on another machine or in another process.
+    Remote(Client),
+}
+
+/// What a [`MicroDB`] handle sees of its storage.
+enum View {
+    /// The storage itself.
+    Direct,
+    /// The storage with the changes of a transaction on top.
+    Transaction(Mutex<Overlay>),
+    /// The storage as it was when the snapshot was taken.
+    Snapshot(Arc<Mutex<Overlay>>),
 }
 
 impl MicroDB {
     /// Loads a database. Can NOT be used to create one.
-    pub fn new<S: ToString>(data: S, alloc: S, cache_period: u128) -> Result<Self, io::Error> {
+    pub fn new<S: ToString>(data: S, alloc: S, cache_period: u128) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::new(data, alloc, cache_period)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Loads an encrypted database with its current key. See [`FAlloc::new_encrypted`].
+    #[cfg(feature = "encryption")]
+    pub fn new_encrypted<S: ToString>(
+        data: S,
+        alloc: S,
+        cache_period: u128,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::new_encrypted(data, alloc, cache_period, key)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Loads a database for reading only, which other processes may do at the same time. See
+    /// [`FAlloc::new_shared`].
+    pub fn new_shared<S: ToString>(data: S, alloc: S, cache_period: u128) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::new_shared(data, alloc, cache_period)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Loads an encrypted database for reading only. See [`FAlloc::new_shared_encrypted`].
+    #[cfg(feature = "encryption")]
+    pub fn new_shared_encrypted<S: ToString>(
+        data: S,
+        alloc: S,
+        cache_period: u128,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::new_shared_encrypted(
+                data,
+                alloc,
+                cache_period,
+                key,
+            )?),
+            view: View::Direct,
+        })
+    }
+
+    /// Opens a database only for reading from it, without locking it. See
+    /// [`FAlloc::open_read_only`].
+    pub fn open_read_only<S: ToString>(data: S, alloc: S) -> Result<Self, Error> {
         Ok(Self {
-            storage: FAlloc::new(data, alloc, cache_period)?,
+            storage: Storage::Local(FAlloc::open_read_only(data, alloc)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Opens an encrypted database only for reading from it. See
+    /// [`FAlloc::open_read_only_encrypted`].
+    #[cfg(feature = "encryption")]
+    pub fn open_read_only_encrypted<S: ToString>(
+        data: S,
+        alloc: S,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::open_read_only_encrypted(data, alloc, key)?),
+            view: View::Direct,
         })
     }
 
@@ -22,9 +112,218 @@ impl MicroDB {
         alloc: S,
         cache_period: u128,
         block_size: usize,
-    ) -> Result<Self, io::Error> {
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::create(data, alloc, cache_period, block_size)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Creates a database that is encrypted with `key`. See [`FAlloc::create_encrypted`].
+    #[cfg(feature = "encryption")]
+    pub fn create_encrypted<S: ToString>(
+        data: S,
+        alloc: S,
+        cache_period: u128,
+        block_size: usize,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::create_encrypted(
+                data,
+                alloc,
+                cache_period,
+                block_size,
+                key,
+            )?),
+            view: View::Direct,
+        })
+    }
+
+    /// Loads a single-file database. Can NOT be used to create one. Its write-ahead log is kept
+    /// at `<path>.wal` until it is shut down, see [`FAlloc::new_single_file`].
+    pub fn new_single_file<S: ToString>(path: S, cache_period: u128) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::new_single_file(path, cache_period)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Loads an encrypted single-file database with its current key. See
+    /// [`FAlloc::new_single_file_encrypted`].
+    #[cfg(feature = "encryption")]
+    pub fn new_single_file_encrypted<S: ToString>(
+        path: S,
+        cache_period: u128,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::new_single_file_encrypted(path, cache_period, key)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Creates a database that is only kept in memory, for tests and temporary data. See
+    /// [`FAlloc::in_memory`].
+    pub fn in_memory() -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::in_memory()?),
+            view: View::Direct,
+        })
+    }
+
+    /// Loads a database from a [`crate::StorageBackend`]. See [`FAlloc::new_with_backend`].
+    pub fn new_with_backend<B: StorageBackend + 'static, L: StorageBackend + 'static>(
+        backend: B,
+        log: L,
+        cache_period: u128,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::new_with_backend(backend, log, cache_period)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Loads an encrypted database from a [`crate::StorageBackend`]. See
+    /// [`FAlloc::new_with_backend_encrypted`].
+    #[cfg(feature = "encryption")]
+    pub fn new_with_backend_encrypted<B: StorageBackend + 'static, L: StorageBackend + 'static>(
+        backend: B,
+        log: L,
+        cache_period: u128,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::new_with_backend_encrypted(
+                backend,
+                log,
+                cache_period,
+                key,
+            )?),
+            view: View::Direct,
+        })
+    }
+
+    /// Creates a database in a [`crate::StorageBackend`]. See [`FAlloc::create_with_backend`].
+    pub fn create_with_backend<B: StorageBackend + 'static, L: StorageBackend + 'static>(
+        backend: B,
+        log: L,
+        cache_period: u128,
+        block_size: usize,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::create_with_backend(
+                backend,
+                log,
+                cache_period,
+                block_size,
+            )?),
+            view: View::Direct,
+        })
+    }
+
+    /// Creates a database in a [`crate::StorageBackend`] that is encrypted with `key`. See
+    /// [`FAlloc::create_with_backend_encrypted`].
+    #[cfg(feature = "encryption")]
+    pub fn create_with_backend_encrypted<
+        B: StorageBackend + 'static,
+        L: StorageBackend + 'static,
+    >(
+        backend: B,
+        log: L,
+        cache_period: u128,
+        block_size: usize,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::create_with_backend_encrypted(
+                backend,
+                log,
+                cache_period,
+                block_size,
+                key,
+            )?),
+            view: View::Direct,
+        })
+    }
+
+    /// Loads a single-file database for reading only, like [`Self::new_shared`].
+    pub fn new_single_file_shared<S: ToString>(path: S, cache_period: u128) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::new_single_file_shared(path, cache_period)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Loads an encrypted single-file database for reading only, like
+    /// [`Self::new_shared_encrypted`].
+    #[cfg(feature = "encryption")]
+    pub fn new_single_file_shared_encrypted<S: ToString>(
+        path: S,
+        cache_period: u128,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::new_single_file_shared_encrypted(
+                path,
+                cache_period,
+                key,
+            )?),
+            view: View::Direct,
+        })
+    }
+
+    /// Opens a single-file database only for reading from it, like [`Self::open_read_only`].
+    pub fn open_read_only_single_file<S: ToString>(path: S) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::open_read_only_single_file(path)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Opens an encrypted single-file database only for reading from it, like
+    /// [`Self::open_read_only_encrypted`].
+    #[cfg(feature = "encryption")]
+    pub fn open_read_only_single_file_encrypted<S: ToString>(
+        path: S,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::open_read_only_single_file_encrypted(path, key)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Creates a database that keeps its allocation table inside of the data file, so that it
+    /// is a single file. Can NOT be used to load one.
+    pub fn create_single_file<S: ToString>(
+        path: S,
+        cache_period: u128,
+        block_size: usize,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            storage: Storage::Local(FAlloc::create_single_file(path, cache_period, block_size)?),
+            view: View::Direct,
+        })
+    }
+
+    /// Creates a single-file database that is encrypted with `key`. See
+    /// [`FAlloc::create_single_file_encrypted`].
+    #[cfg(feature = "encryption")]
+    pub fn create_single_file_encrypted<S: ToString>(
+        path: S,
+        cache_period: u128,
+        block_size: usize,
+        key: Key,
+    ) -> Result<Self, Error> {
         Ok(Self {
-            storage: FAlloc::create(data, alloc, cache_period, block_size)?,
+            storage: Storage::Local(FAlloc::create_single_file_encrypted(
+                path,
+                cache_period,
+                block_size,
+                key,
+            )?),
+            view: View::Direct,
         })
     }
 
@@ -42,60 +341,109 @@ impl MicroDB {
             / (requests_per_second * average_object_size_mb)) as u128
     }
 
+    /// Uses a connection pool to a [`crate::Server`] as the storage.
+    pub(crate) fn remote(client: Client) -> Self {
+        Self {
+            storage: Storage::Remote(client),
+            view: View::Direct,
+        }
+    }
+
     /// Expires the cache and flushes it.
-    pub fn sync(&self) -> Result<(), io::Error> {
-        self.storage.sync()
+    pub fn sync(&self) -> Result<(), Error> {
+        self.direct()?.sync()
     }
 
     /// Syncs, then saves metadata (allocations).
-    pub fn save(&self) -> Result<(), io::Error> {
-        self.storage.save()
+    pub fn save(&self) -> Result<(), Error> {
+        self.direct()?.save()
+    }
+
+    /// Defragments the database and shrinks the data file to the data that is still in use.
+    /// The database can be used normally while this runs.
+    pub fn compact(&self) -> Result<(), Error> {
+        self.direct()?.compact()
+    }
+
+    /// Switches an encrypted database to a new key, and re-encrypts it in the background. See
+    /// [`FAlloc::rotate_key`].
+    #[cfg(feature = "encryption")]
+    pub fn rotate_key(&self, key: Key) -> Result<(), Error> {
+        self.direct()?.rotate_key(key)
+    }
+
+    /// Checks the database for inconsistencies. With `repair`, damaged values are moved below
+    /// [`crate::QUARANTINE`] so the rest of the database can be used safely. Use
+    /// [`crate::check_files`] or [`crate::check_single_file`] for databases that aren't loaded.
+    pub fn check(&self, repair: bool) -> Result<Report, Error> {
+        self.direct()?.check(repair)
+    }
+
+    /// Sets what happens when saving in the background fails. See [`FAlloc::set_recovery_policy`].
+    pub fn set_recovery_policy(&self, policy: RecoveryPolicy) -> Result<(), Error> {
+        self.direct()?.set_recovery_policy(policy)
+    }
+
+    /// Calls `callback` with every error that saving in the background runs into. See
+    /// [`FAlloc::on_error`].
+    pub fn on_error<F: Fn(&Error) + Send + Sync + 'static>(
+        &self,
+        callback: F,
+    ) -> Result<(), Error> {
+        self.direct()?.on_error(callback)
     }
 
     /// Gracefully shuts down the DB, saving in the process.
     /// Please use [`Self::shutdown`] instead if possible. This variant
     /// will force a shutdown across all threads without the guarantee that
     /// this is the only thread with access to it.
-    pub fn shutdown_here(&self) -> Result<(), io::Error> {
-        self.storage.shutdown_here()
+    ///
+    /// Snapshots and transactions leave the database running.
+    pub fn shutdown_here(&self) -> Result<(), Error> {
+        if !matches!(self.view, View::Direct) {
+            return Ok(());
+        }
+        match &self.storage {
+            Storage::Local(storage) => storage.shutdown_here(),
+            Storage::Remote(_) => Ok(()),
+        }
     }
 
-    /// Gracefully shuts down the DB, saving in the process.
-    pub fn shutdown(self) -> Result<(), io::Error> {
-        self.storage.shutdown()
+    /// Gracefully shuts down the DB, saving in the process. A remote database only closes its
+    /// connections, and keeps running on the server. A snapshot is only dropped.
+    pub fn shutdown(self) -> Result<(), Error> {
+        if !matches!(self.view, View::Direct) {
+            return Ok(());
+        }
+        match self.storage {
+            Storage::Local(storage) => storage.shutdown(),
+            Storage::Remote(_) => Ok(()),
+        }
     }
 
     /// Returns the direct sub-paths of a path, or the direct root paths.
     /// Does NOT return sub-paths of sub-paths.
-    pub fn get_paths<P: Path>(&self, path: Option<P>) -> Result<Vec<String>, io::Error> {
-        if let Some(path) = path {
-            self.storage.paths(Some(&path.to_db_path()))
-        } else {
-            self.storage.paths(None)
-        }
+    pub fn get_paths<P: Path>(&self, path: Option<P>) -> Result<Vec<String>, Error> {
+        self.paths(path.map(Path::to_db_path).as_deref(), true)
     }
 
     /// Returns all sub-paths of a path, including indirect ones.
-    pub fn get_all_paths<P: Path>(&self, path: Option<P>) -> Result<Vec<String>, io::Error> {
-        if let Some(path) = path {
-            self.storage.all_paths(Some(&path.to_db_path()))
-        } else {
-            self.storage.all_paths(None)
-        }
+    pub fn get_all_paths<P: Path>(&self, path: Option<P>) -> Result<Vec<String>, Error> {
+        self.paths(path.map(Path::to_db_path).as_deref(), false)
     }
 
     /// Primitively parses the object just enough to know the paths it occupies directly.
     /// Does NOT return sub-paths of sub-paths.
-    pub fn get_paths_of<T: ComObj, P: Path>(&self, path: P) -> Result<Vec<String>, io::Error> {
-        T::paths(path, self)
+    pub fn get_paths_of<T: ComObj, P: Path>(&self, path: P) -> Result<Vec<String>, Error> {
+        Ok(T::paths(path, self)?)
     }
 
     /// Sets an item in the database at the path.
     /// Here, the item is saved in a single blob at the path.
-    pub fn set_raw<T: RawObj, P: Path>(&self, path: P, object: T) -> Result<(), io::Error> {
+    pub fn set_raw<T: RawObj, P: Path>(&self, path: P, object: T) -> Result<(), Error> {
         let path = path.to_db_path();
-        self.storage.delete_substructure(&path)?; // raw objects mustn't have substructure
-        self.storage.set(&path, object.to_db())
+        self.delete_substructure(&path)?; // raw objects mustn't have substructure
+        self.set(&path, object.to_db())
     }
 
     /// Sets an item in the database at the path.
@@ -108,17 +456,16 @@ impl MicroDB {
     /// other function. Use this only if you know that the types of the previous inhabitant
     /// and the new one are the same and that the types aren't dynamic (like [`Vec<T>`] is),
     /// or if you WANT to keep sub-structure (if you're implementing a serializer for example).
-    pub fn set_raw_hard<T: RawObj, P: Path>(&self, path: P, object: T) -> Result<(), io::Error> {
-        self.storage.set(&path.to_db_path(), object.to_db())
+    pub fn set_raw_hard<T: RawObj, P: Path>(&self, path: P, object: T) -> Result<(), Error> {
+        self.set(&path.to_db_path(), object.to_db())
     }
 
     /// Sets an item in the database at the path.
     /// Here, the item is a composite item, so multiple blobs on sub-paths
     /// may be created.
-    pub fn set_com<T: ComObj, P: Path>(&self, path: P, object: T) -> Result<(), io::Error> {
-        self.storage
-            .delete_substructure(&path.clone().to_db_path())?; // clean substructure
-        T::to_db(object, path, self)
+    pub fn set_com<T: ComObj, P: Path>(&self, path: P, object: T) -> Result<(), Error> {
+        self.delete_substructure(&path.clone().to_db_path())?; // clean substructure
+        Ok(T::to_db(object, path, self)?)
     }
 
     /// Sets an item in the database at the path.
@@ -131,36 +478,206 @@ impl MicroDB {
     /// the next time that substructure is cleaned by some other function. Use this only if you
     /// know that the types of the previous inhabitant and the new one are the same and that the
     /// types aren't dynamic (like [`Vec<T>`] is).
-    pub fn set_com_hard<T: ComObj, P: Path>(&self, path: P, object: T) -> Result<(), io::Error> {
-        T::to_db(object, path, self)
+    pub fn set_com_hard<T: ComObj, P: Path>(&self, path: P, object: T) -> Result<(), Error> {
+        Ok(T::to_db(object, path, self)?)
     }
 
-    /// Gets an item from the database.
-    pub fn get_raw<T: RawObj, P: Path>(&self, path: P) -> Result<Option<T>, io::Error> {
-        Ok(self.storage.get(&path.to_db_path())?.and_then(T::from_db))
+    /// Gets an item from the database. Returns [`Error::Decode`] if there is an item, but it
+    /// is not a `T`.
+    pub fn get_raw<T: RawObj, P: Path>(&self, path: P) -> Result<Option<T>, Error> {
+        let path = path.to_db_path();
+        match self.get(&path)? {
+            Some(x) => T::from_db(x).map(Some).ok_or(Error::Decode { path }),
+            None => Ok(None),
+        }
     }
 
     /// Gets a composite item from the database.
-    pub fn get_com<T: ComObj, P: Path>(&self, path: P) -> Result<Option<T>, io::Error> {
-        T::from_db(path, self)
+    pub fn get_com<T: ComObj, P: Path>(&self, path: P) -> Result<Option<T>, Error> {
+        Ok(T::from_db(path, self)?)
     }
 
     /// Removes any item from the database.
-    pub fn remove<P: Path>(&self, path: P) -> Result<(), io::Error> {
+    pub fn remove<P: Path>(&self, path: P) -> Result<(), Error> {
         let path = path.to_db_path();
-        self.storage.delete_substructure(&path)?;
-        self.storage.set(&path, Vec::new())
+        self.delete_substructure(&path)?;
+        self.set(&path, Vec::new())
     }
 
     /// Removes a single-blob item from the database gracefully.
-    pub fn remove_raw<P: Path>(&self, path: P) -> Result<(), io::Error> {
-        self.storage.set(&path.to_db_path(), Vec::new())
+    pub fn remove_raw<P: Path>(&self, path: P) -> Result<(), Error> {
+        self.set(&path.to_db_path(), Vec::new())
     }
 
     /// Removes a composite item from the database gracefully.
-    pub fn remove_com<T: ComObj, P: Path>(&self, path: P) -> Result<(), io::Error> {
-        T::remove(path, self)
+    pub fn remove_com<T: ComObj, P: Path>(&self, path: P) -> Result<(), Error> {
+        Ok(T::remove(path, self)?)
     }
+
+    /// Runs `f` in a transaction. Everything `f` writes through the handle it is given is only
+    /// visible to that handle until `f` returns Ok, and is then applied all at once, even if the
+    /// program crashes halfway. If `f` returns an error, nothing is applied.
+    ///
+    /// Reads of values the transaction didn't write see the current state of the database.
+    /// Remote databases don't support transactions.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&MicroDB) -> Result<T, Error>,
+    {
+        let overlay = match &self.view {
+            View::Direct => Overlay::default(),
+            View::Transaction(overlay) => lock(overlay)?.clone(),
+            // nothing can be written anyway
+            View::Snapshot(_) => return f(self),
+        };
+        let storage = self.local()?;
+        let tx = MicroDB {
+            storage: Storage::Local(storage.share()),
+            view: View::Transaction(Mutex::new(overlay)),
+        };
+        let result = f(&tx)?;
+        let View::Transaction(overlay) = tx.view else {
+            unreachable!()
+        };
+        let overlay = overlay.into_inner().map_err(|_| Error::Poisoned)?;
+        match &self.view {
+            View::Direct if overlay.records.is_empty() => (),
+            View::Direct => storage.commit(overlay.records)?,
+            // nested transactions commit into the outer one
+            View::Transaction(outer) => *lock(outer)? = overlay,
+            View::Snapshot(_) => unreachable!(),
+        }
+        Ok(result)
+    }
+
+    /// Copies the database to a new pair of files while it keeps being read from and written to.
+    /// The copy is consistent, containing exactly what a [`Self::snapshot`] taken at the time of
+    /// the call would see, and can be opened with [`Self::new`]. Fails if the data file already
+    /// exists. A backup that fails leaves no files behind.
+    pub fn backup_to<S: ToString>(&self, data: S, alloc: S) -> Result<(), Error> {
+        match &self.view {
+            View::Snapshot(snapshot) => self.local()?.backup(
+                snapshot,
+                Backup::Pair {
+                    data: data.to_string(),
+                    alloc: alloc.to_string(),
+                },
+            ),
+            _ => self.local()?.backup_to(data, alloc),
+        }
+    }
+
+    /// Copies the database to a new single-file database, like [`Self::backup_to`]. The copy
+    /// can be opened with [`Self::new_single_file`].
+    pub fn backup_to_single_file<S: ToString>(&self, path: S) -> Result<(), Error> {
+        match &self.view {
+            View::Snapshot(snapshot) => self
+                .local()?
+                .backup(snapshot, Backup::Single(path.to_string())),
+            _ => self.local()?.backup_to_single_file(path),
+        }
+    }
+
+    /// Returns a read-only handle that keeps seeing the database as it is now, while this handle
+    /// and others keep writing to it. Writing through the snapshot, or anything else that would
+    /// change the database, fails with [`Error::ReadOnly`], and shutting it down only drops it.
+    ///
+    /// Values are copied into the snapshot right before they change, so a snapshot that is kept
+    /// around for long holds on to more and more memory. Dropping it releases them. A snapshot
+    /// taken inside of a transaction does not see the transaction's uncommitted writes.
+    pub fn snapshot(&self) -> Result<MicroDB, Error> {
+        let storage = self.local()?;
+        let snapshot = match &self.view {
+            View::Snapshot(snapshot) => snapshot.clone(),
+            _ => storage.snapshot()?,
+        };
+        Ok(MicroDB {
+            storage: Storage::Local(storage.share()),
+            view: View::Snapshot(snapshot),
+        })
+    }
+
+    fn get(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        match &self.view {
+            View::Direct => (),
+            View::Transaction(overlay) => {
+                if let Some(data) = lock(overlay)?.get(path) {
+                    return Ok(data);
+                }
+            }
+            View::Snapshot(snapshot) => return self.local()?.snapshot_get(snapshot, path),
+        }
+        match &self.storage {
+            Storage::Local(storage) => storage.get(path),
+            Storage::Remote(client) => client.get(path),
+        }
+    }
+
+    fn set(&self, path: &str, data: Vec<u8>) -> Result<(), Error> {
+        match &self.view {
+            View::Direct => match &self.storage {
+                Storage::Local(storage) => storage.set(path, data),
+                Storage::Remote(client) => client.set(path, data),
+            },
+            View::Transaction(overlay) => {
+                lock(overlay)?.set(path, data);
+                Ok(())
+            }
+            View::Snapshot(_) => Err(Error::ReadOnly),
+        }
+    }
+
+    fn delete_substructure(&self, path: &str) -> Result<(), Error> {
+        match &self.view {
+            View::Direct => match &self.storage {
+                Storage::Local(storage) => storage.delete_substructure(path),
+                Storage::Remote(client) => client.delete_substructure(path),
+            },
+            View::Transaction(overlay) => {
+                lock(overlay)?.delete_substructure(path);
+                Ok(())
+            }
+            View::Snapshot(_) => Err(Error::ReadOnly),
+        }
+    }
+
+    fn paths(&self, path: Option<&str>, direct: bool) -> Result<Vec<String>, Error> {
+        if let View::Snapshot(snapshot) = &self.view {
+            return self.local()?.snapshot_paths(snapshot, path, direct);
+        }
+        let found = match &self.storage {
+            Storage::Local(storage) if direct => storage.paths(path)?,
+            Storage::Local(storage) => storage.all_paths(path)?,
+            Storage::Remote(client) => client.paths(path, direct)?,
+        };
+        match &self.view {
+            View::Direct => Ok(found),
+            View::Transaction(overlay) => Ok(lock(overlay)?.paths(found, path, direct)),
+            View::Snapshot(_) => unreachable!(),
+        }
+    }
+
+    /// The local storage, for what only a handle on the database itself may do: snapshots can't
+    /// change anything, and transactions only buffer writes.
+    fn direct(&self) -> Result<&FAlloc, Error> {
+        match &self.view {
+            View::Direct => self.local(),
+            View::Transaction(_) => Err(Error::Unsupported),
+            View::Snapshot(_) => Err(Error::ReadOnly),
+        }
+    }
+
+    /// The local storage, for everything a remote database doesn't support.
+    pub(crate) fn local(&self) -> Result<&FAlloc, Error> {
+        match &self.storage {
+            Storage::Local(storage) => Ok(storage),
+            Storage::Remote(_) => Err(Error::Unsupported),
+        }
+    }
+}
+
+fn lock(overlay: &Mutex<Overlay>) -> Result<MutexGuard<'_, Overlay>, Error> {
+    overlay.lock().map_err(|_| Error::Poisoned)
 }
 
 /// Convenience macro to extract a value from the database and return Ok(None) if not found.
@@ -0,0 +1,384 @@
+//! Authenticated encryption of databases at rest (the `encryption` feature).
+//!
+//! Values, the allocation table, its journal and the write-ahead log are sealed with
+//! XChaCha20-Poly1305, each under a fresh random nonce. Sealed bytes start with the generation of
+//! the key they were sealed with, so that after [`crate::FAlloc::rotate_key`] the ones that
+//! still use an older key can be read and found. The keys that are still in use are kept inside
+//! of the allocation table, which is always sealed with the current one. The headers of the files
+//! are not encrypted.
+
+use std::io::Read;
+#[cfg(feature = "encryption")]
+use std::{
+    fmt::{self, Debug},
+    io,
+};
+
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::{Error, FormatError};
+
+/// Associated data of the allocation table, its journal and the log. Values use their path.
+#[cfg(feature = "encryption")]
+pub(crate) const TABLE: &[u8] = b"\0table";
+pub(crate) const JOURNAL: &[u8] = b"\0journal";
+pub(crate) const LOG: &[u8] = b"\0log";
+
+/// A 256-bit key to encrypt a database with.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+#[cfg(feature = "encryption")]
+impl Key {
+    /// Uses the given bytes as the key.
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// A random key from the operating system's generator.
+    pub fn generate() -> Self {
+        Self(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    /// The bytes of the key, to keep it somewhere safe.
+    pub fn bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// The keys of a database, or none if it isn't encrypted.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Keys {
+    /// Every key still in use with its generation, oldest first. The last one is current.
+    #[cfg(feature = "encryption")]
+    ring: Vec<(u32, Key)>,
+}
+
+impl Keys {
+    /// Keys that encrypt with `key`, which is also all a loaded table needs to find the others.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn new(key: Key) -> Self {
+        Self {
+            ring: vec![(1, key)],
+        }
+    }
+
+    pub(crate) fn is_encrypted(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return !self.ring.is_empty();
+        #[cfg(not(feature = "encryption"))]
+        return false;
+    }
+
+    /// Whether older keys are still in use after a rotation.
+    pub(crate) fn retiring(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.ring.len() > 1;
+        #[cfg(not(feature = "encryption"))]
+        return false;
+    }
+
+    /// Makes `key` the current key. The older ones are kept until [`Self::retire`] is called.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn rotate(&mut self, key: Key) {
+        let generation = self.ring.last().map_or(0, |x| x.0) + 1;
+        self.ring.push((generation, key));
+    }
+
+    /// Forgets all keys but the current one.
+    pub(crate) fn retire(&mut self) {
+        #[cfg(feature = "encryption")]
+        {
+            let current = self.ring.pop();
+            self.ring.clear();
+            self.ring.extend(current);
+        }
+    }
+
+    /// Only the current key, for a copy of the database.
+    pub(crate) fn current(&self) -> Self {
+        let mut keys = self.clone();
+        keys.retire();
+        keys
+    }
+
+    /// Whether sealed bytes were sealed with the current key. Always true if not encrypted.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub(crate) fn is_current(&self, sealed: &[u8]) -> bool {
+        #[cfg(feature = "encryption")]
+        if let Some((generation, _)) = self.ring.last() {
+            return sealed.get(..4) == Some(&generation.to_be_bytes()[..]);
+        }
+        true
+    }
+
+    /// Encrypts bytes with the current key. Returns them unchanged if not encrypted.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub(crate) fn seal(&self, ad: &[u8], plain: Vec<u8>) -> Vec<u8> {
+        #[cfg(feature = "encryption")]
+        if let Some((generation, key)) = self.ring.last() {
+            let cipher = XChaCha20Poly1305::new(key.0.as_ref().into());
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let payload = Payload {
+                msg: &plain,
+                aad: ad,
+            };
+            // only fails for inputs of hundreds of gigabytes
+            let sealed = cipher.encrypt(&nonce, payload).unwrap();
+            let mut bytes = Vec::with_capacity(4 + nonce.len() + sealed.len());
+            bytes.extend_from_slice(&generation.to_be_bytes());
+            bytes.extend_from_slice(&nonce);
+            bytes.extend_from_slice(&sealed);
+            return bytes;
+        }
+        plain
+    }
+
+    /// Decrypts bytes sealed by [`Self::seal`], returning None if they were sealed with another
+    /// key or were tampered with. Returns them unchanged if not encrypted.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub(crate) fn open(&self, ad: &[u8], sealed: Vec<u8>) -> Option<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        if !self.ring.is_empty() {
+            let generation = u32::from_be_bytes(sealed.get(..4)?.try_into().unwrap());
+            let (_, key) = self.ring.iter().find(|x| x.0 == generation)?;
+            let cipher = XChaCha20Poly1305::new(key.0.as_ref().into());
+            let nonce = XNonce::from_slice(sealed.get(4..28)?);
+            let payload = Payload {
+                msg: &sealed[28..],
+                aad: ad,
+            };
+            return cipher.decrypt(nonce, payload).ok();
+        }
+        Some(sealed)
+    }
+
+    /// Appends the body of an allocation table. Encrypted tables store it sealed, behind its
+    /// length, together with all keys that are still in use.
+    pub(crate) fn write_table(&self, buf: &mut Vec<u8>, body: Vec<u8>) {
+        #[cfg(feature = "encryption")]
+        if self.is_encrypted() {
+            let mut plain = Vec::new();
+            plain.extend_from_slice(&(self.ring.len() as u64).to_be_bytes());
+            for (generation, key) in &self.ring {
+                plain.extend_from_slice(&generation.to_be_bytes());
+                plain.extend_from_slice(&key.0);
+            }
+            plain.extend_from_slice(&body);
+            let sealed = self.seal(TABLE, plain);
+            buf.extend_from_slice(&(sealed.len() as u64).to_be_bytes());
+            buf.extend_from_slice(&sealed);
+            return;
+        }
+        buf.extend_from_slice(&body);
+    }
+
+    /// Reads the body of an encrypted allocation table written by [`Self::write_table`], and
+    /// replaces the keys with the ones stored in it.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub(crate) fn read_table(&mut self, f: &mut impl Read) -> Result<Vec<u8>, Error> {
+        #[cfg(feature = "encryption")]
+        if let Some((_, key)) = self.ring.pop() {
+            let mut buf64 = [0_u8; 8];
+            let mut buf32 = [0_u8; 4];
+            f.read_exact(&mut buf64)?;
+            let len = u64::from_be_bytes(buf64);
+            let mut sealed = Vec::new();
+            f.take(len).read_to_end(&mut sealed)?;
+            if sealed.len() as u64 != len || len < 4 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            // the key that was given is the current one, whatever generation it has
+            buf32.copy_from_slice(&sealed[..4]);
+            self.ring = vec![(u32::from_be_bytes(buf32), key)];
+            let plain = self.open(TABLE, sealed).ok_or(Error::WrongKey)?;
+            let mut f = &plain[..];
+            f.read_exact(&mut buf64)?;
+            let mut ring = Vec::new();
+            for _ in 0..u64::from_be_bytes(buf64) {
+                let mut key = [0_u8; 32];
+                f.read_exact(&mut buf32)?;
+                f.read_exact(&mut key)?;
+                ring.push((u32::from_be_bytes(buf32), Key(key)));
+            }
+            self.ring = ring;
+            return Ok(f.to_vec());
+        }
+        Err(FormatError::Encrypted.into())
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod test {
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        check_files_encrypted, check_single_file_encrypted, cleanup::Files,
+        format::DATA_HEADER_LEN, Corruption, Error, FAlloc, FormatError, Key, MemoryBackend,
+        RecoveryPolicy,
+    };
+
+    /// Whether a file of the database contains the bytes anywhere.
+    fn leaks(ext: &str, bytes: &[u8]) -> bool {
+        let file = fs::read(format!("encryption.{ext}")).unwrap();
+        file.windows(bytes.len()).any(|x| x == bytes)
+    }
+
+    #[test]
+    fn encrypted() {
+        let _files = Files::db("encryption");
+        let old = Key::generate();
+        let new = Key::generate();
+        let db = FAlloc::create_encrypted("encryption.dat", "encryption.alloc", 0, 16, old.clone())
+            .unwrap();
+        db.set("secret/path", b"secret value".to_vec()).unwrap();
+        assert!(!leaks("alloc.wal", b"secret"));
+        db.save().unwrap();
+        db.set("logged", b"only in the log".to_vec()).unwrap();
+        for ext in ["dat", "alloc", "alloc.wal"] {
+            assert!(!leaks(ext, b"secret"), "{ext}");
+            assert!(!leaks(ext, b"only in the log"), "{ext}");
+        }
+        db.shutdown().unwrap();
+
+        assert!(matches!(
+            FAlloc::new("encryption.dat", "encryption.alloc", 0),
+            Err(Error::Format(FormatError::Encrypted))
+        ));
+        assert!(matches!(
+            FAlloc::new_encrypted("encryption.dat", "encryption.alloc", 0, new.clone()),
+            Err(Error::WrongKey)
+        ));
+        let db =
+            FAlloc::new_encrypted("encryption.dat", "encryption.alloc", 0, old.clone()).unwrap();
+        assert_eq!(db.get("logged").unwrap(), Some(b"only in the log".to_vec()));
+        db.rotate_key(new.clone()).unwrap();
+        while db.rotating_key().unwrap() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        db.shutdown().unwrap();
+
+        assert!(matches!(
+            FAlloc::new_encrypted("encryption.dat", "encryption.alloc", 0, old),
+            Err(Error::WrongKey)
+        ));
+        let db =
+            FAlloc::new_encrypted("encryption.dat", "encryption.alloc", 0, new.clone()).unwrap();
+        assert_eq!(
+            db.get("secret/path").unwrap(),
+            Some(b"secret value".to_vec())
+        );
+        assert_eq!(db.get("logged").unwrap(), Some(b"only in the log".to_vec()));
+        assert!(db.check(false).unwrap().problems.is_empty());
+        db.set("unsaved", b"still in the log".to_vec()).unwrap();
+
+        // tools that only read need the key as well
+        let reader =
+            FAlloc::open_read_only_encrypted("encryption.dat", "encryption.alloc", new.clone())
+                .unwrap();
+        assert_eq!(
+            reader.get("unsaved").unwrap(),
+            Some(b"still in the log".to_vec())
+        );
+        reader.shutdown().unwrap();
+        db.shutdown().unwrap();
+        let report =
+            check_files_encrypted("encryption.dat", "encryption.alloc", false, new).unwrap();
+        assert!(report.is_ok());
+    }
+
+    #[test]
+    fn rotate_past_damage() {
+        let _files = Files::db("rekey");
+        let old = Key::generate();
+        let new = Key::generate();
+        let db = FAlloc::create_encrypted("rekey.dat", "rekey.alloc", 0, 16, old.clone()).unwrap();
+        db.set("a", vec![1; 100]).unwrap();
+        db.set("b", vec![2; 100]).unwrap();
+        db.shutdown().unwrap();
+        let mut data = fs::read("rekey.dat").unwrap();
+        data[DATA_HEADER_LEN as usize + 10] ^= 1;
+        fs::write("rekey.dat", data).unwrap();
+
+        // a damaged value neither stops the database nor keeps the rotation from finishing
+        let db = FAlloc::new_encrypted("rekey.dat", "rekey.alloc", 0, old).unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let reported = errors.clone();
+        db.set_recovery_policy(RecoveryPolicy::Stop).unwrap();
+        db.on_error(move |e| reported.lock().unwrap().push(e.to_string()))
+            .unwrap();
+        db.rotate_key(new.clone()).unwrap();
+        let start = Instant::now();
+        // the error is reported once the database is unlocked again
+        while db.rotating_key().unwrap() || errors.lock().unwrap().is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(errors.lock().unwrap().len(), 1);
+        assert!(matches!(
+            db.get("a"),
+            Err(Error::Corrupt(Corruption::Checksum { path, .. })) if path == "a"
+        ));
+        db.shutdown().unwrap();
+
+        let db = FAlloc::new_encrypted("rekey.dat", "rekey.alloc", 0, new).unwrap();
+        assert_eq!(db.get("b").unwrap(), Some(vec![2; 100]));
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn encrypted_single_file() {
+        let _files = Files::new("encryption.single", &["db", "db.wal"]);
+        let key = Key::generate();
+        let db = FAlloc::create_single_file_encrypted("encryption.single.db", 0, 16, key.clone())
+            .unwrap();
+        db.set("a", b"secret value".to_vec()).unwrap();
+        db.shutdown().unwrap();
+        assert!(!fs::read("encryption.single.db")
+            .unwrap()
+            .windows(6)
+            .any(|x| x == b"secret"));
+
+        let db = FAlloc::new_single_file_encrypted("encryption.single.db", 0, key.clone()).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some(b"secret value".to_vec()));
+        db.shutdown().unwrap();
+        assert!(matches!(
+            FAlloc::new_single_file_encrypted("encryption.single.db", 0, Key::generate()),
+            Err(Error::WrongKey)
+        ));
+        let reader =
+            FAlloc::open_read_only_single_file_encrypted("encryption.single.db", key.clone())
+                .unwrap();
+        assert_eq!(reader.get("a").unwrap(), Some(b"secret value".to_vec()));
+        reader.shutdown().unwrap();
+        assert!(
+            check_single_file_encrypted("encryption.single.db", false, key)
+                .unwrap()
+                .is_ok()
+        );
+
+        let (data, log) = (MemoryBackend::default(), MemoryBackend::default());
+        let key = Key::generate();
+        let db = FAlloc::create_with_backend_encrypted(data, log, 0, 16, key).unwrap();
+        db.set("a", b"secret value".to_vec()).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some(b"secret value".to_vec()));
+        db.shutdown().unwrap();
+    }
+}
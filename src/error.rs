@@ -0,0 +1,248 @@
+use std::{
+    error,
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+};
+
+use crate::format::FORMAT_VERSION;
+
+/// Everything that can go wrong in a [`crate::MicroDB`] or [`crate::FAlloc`].
+///
+/// This converts to and from [`io::Error`], so it can be used with `?` inside of
+/// [`crate::data::ComObj`] implementations. Converting it back out of an [`io::Error`] restores
+/// the original variant.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The underlying files could not be read or written.
+    Io(io::Error),
+    /// The database has shut down, either because it was asked to or because saving failed with
+    /// [`crate::RecoveryPolicy::Stop`].
+    ShutDown,
+    /// Data on disk is damaged.
+    Corrupt(Corruption),
+    /// The files are not a database this version of MicroDB can load.
+    Format(FormatError),
+    /// A value was found at the path, but could not be decoded into the requested type.
+    Decode { path: String },
+    /// The database file does not exist.
+    NotFound { path: String },
+    /// A thread panicked while using the database, so its state can no longer be trusted.
+    Poisoned,
+    /// The handle can only be read from.
+    ReadOnly,
+    /// The operation isn't available for this kind of database, for example because it is
+    /// remote.
+    Unsupported,
+    /// A remote server failed to handle a request with an error that can't be sent as itself,
+    /// and sent this message instead.
+    Remote(String),
+    /// The database is already open, either in another process or through another handle.
+    InUse { path: String },
+    /// The database is encrypted with a different key.
+    WrongKey,
+}
+
+/// The ways in which data on disk can be damaged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Corruption {
+    /// A value does not match the checksum it was written with.
+    Checksum {
+        path: String,
+        expected: u32,
+        found: u32,
+    },
+    /// A value matches its checksum, but could not be decompressed.
+    Compression { path: String },
+    /// A value of an encrypted database was not sealed with any of its keys for this path, so it
+    /// was tampered with or moved.
+    Authentication { path: String },
+    /// The allocation table contains a path that is not valid UTF-8.
+    InvalidPath,
+    /// The allocation table contradicts itself.
+    Table(&'static str),
+}
+
+/// The ways in which the files can fail to be a loadable database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FormatError {
+    /// The data file is not a MicroDB data file.
+    NotADatabase,
+    /// The file was written by a newer version of MicroDB.
+    UnsupportedVersion(u64),
+    /// The data file and allocation table belong to different databases.
+    Mismatch { data: u128, meta: u128 },
+    /// A value was stored with a codec this build does not support, usually because the
+    /// `compression` feature is disabled.
+    UnsupportedCodec(u8),
+    /// The database is encrypted, but was loaded without a key.
+    Encrypted,
+    /// The database is not encrypted, but was loaded with a key.
+    NotEncrypted,
+}
+
+impl Error {
+    /// The [`ErrorKind`] this error has when converted to an [`io::Error`].
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io(e) => e.kind(),
+            Error::ShutDown => ErrorKind::BrokenPipe,
+            Error::Corrupt(_) | Error::Format(_) | Error::Decode { .. } => ErrorKind::InvalidData,
+            Error::NotFound { .. } => ErrorKind::NotFound,
+            Error::Poisoned => ErrorKind::Other,
+            Error::ReadOnly => ErrorKind::PermissionDenied,
+            Error::Unsupported => ErrorKind::Unsupported,
+            Error::Remote(_) => ErrorKind::Other,
+            Error::InUse { .. } => ErrorKind::ResourceBusy,
+            Error::WrongKey => ErrorKind::PermissionDenied,
+        }
+    }
+
+    /// Turns a failure to open a file into [`Error::NotFound`] if it didn't exist.
+    pub(crate) fn opening(e: io::Error, path: &str) -> Self {
+        if e.kind() == ErrorKind::NotFound {
+            Error::NotFound {
+                path: path.to_owned(),
+            }
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::ShutDown => write!(f, "The database has shut down. If you didn't do this, saving failed and its recovery policy stopped it. Mutations that weren't saved are replayed when it is loaded again."),
+            Error::Corrupt(c) => c.fmt(f),
+            Error::Format(e) => e.fmt(f),
+            Error::Decode { path } => write!(f, "The value at {path:?} could not be decoded."),
+            Error::NotFound { path } => write!(f, "The database file {path:?} does not exist."),
+            Error::Poisoned => write!(f, "A thread panicked while using the database. It can no longer be used."),
+            Error::ReadOnly => write!(f, "This handle to the database is read-only."),
+            Error::Unsupported => write!(f, "This operation is not supported by this kind of database."),
+            Error::Remote(message) => write!(f, "The server failed to handle the request: {message}"),
+            Error::InUse { path } => write!(f, "The database file {path:?} is already in use by another process or handle."),
+            Error::WrongKey => write!(f, "The database is encrypted with a different key."),
+        }
+    }
+}
+
+impl Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::Checksum {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "The data of {path:?} is corrupt: expected checksum {expected:08x}, found {found:08x}."
+            ),
+            Corruption::Compression { path } => {
+                write!(f, "The data of {path:?} could not be decompressed.")
+            }
+            Corruption::Authentication { path } => {
+                write!(f, "The data of {path:?} failed authentication and may have been tampered with.")
+            }
+            Corruption::InvalidPath => {
+                write!(f, "The allocation table contains a path that is not UTF-8.")
+            }
+            Corruption::Table(reason) => write!(f, "The allocation table is corrupt: {reason}"),
+        }
+    }
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::NotADatabase => write!(f, "The data file is not a MicroDB data file."),
+            FormatError::UnsupportedVersion(v) => write!(
+                f,
+                "The database has format version {v}, but only versions up to {FORMAT_VERSION} are supported."
+            ),
+            FormatError::Mismatch { data, meta } => write!(
+                f,
+                "The data file (database {data:032x}) and allocation table (database {meta:032x}) do not belong together."
+            ),
+            FormatError::UnsupportedCodec(codec) => write!(
+                f,
+                "A value was stored with codec {codec}, which is not supported. Enable the `compression` feature to read it."
+            ),
+            FormatError::Encrypted => write!(
+                f,
+                "The database is encrypted and can only be loaded with its key, using the `encryption` feature."
+            ),
+            FormatError::NotEncrypted => write!(f, "The database is not encrypted, but a key was given."),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|x| x.is::<Error>()) {
+            // this was an Error before, unwrap it again
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+impl From<Corruption> for Error {
+    fn from(c: Corruption) -> Self {
+        Error::Corrupt(c)
+    }
+}
+
+impl From<FormatError> for Error {
+    fn from(e: FormatError) -> Self {
+        Error::Format(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::{cleanup::Files, Error, MicroDB};
+
+    #[test]
+    fn decode_and_round_trip() {
+        let _files = Files::new("error.test", &["dmdb", "mmdb", "mmdb.wal"]);
+        let db = MicroDB::create("error.test.dmdb", "error.test.mmdb", 100, 100).unwrap();
+        db.set_raw("text", "hi".to_owned()).unwrap();
+        assert!(matches!(
+            db.get_raw::<u64, _>("text"),
+            Err(Error::Decode { path }) if path == "text"
+        ));
+        // errors passing through a ComObj come back out with the same variant
+        let e: io::Error = Error::ShutDown.into();
+        assert!(matches!(Error::from(e), Error::ShutDown));
+        db.shutdown().unwrap();
+        assert!(matches!(
+            MicroDB::new("error.test.dmdb", "error.test.none", 100),
+            Err(Error::NotFound { .. })
+        ));
+    }
+}
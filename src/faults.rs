@@ -0,0 +1,70 @@
+//! A [`StorageBackend`] for tests, which fails on demand.
+
+use std::{
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{MemoryBackend, StorageBackend};
+
+/// Keeps the data in memory, and fails the kinds of operations that are switched on. Clones
+/// share the data and the switches, so a test can keep one to break the one it handed out.
+#[derive(Clone, Default)]
+pub(crate) struct Faulty {
+    inner: Arc<Mutex<MemoryBackend>>,
+    reads: Arc<AtomicBool>,
+    syncs: Arc<AtomicBool>,
+}
+
+impl Faulty {
+    /// Makes reads fail, or work again.
+    pub(crate) fn fail_reads(&self, fail: bool) {
+        self.reads.store(fail, Ordering::Relaxed);
+    }
+
+    /// Makes syncs fail, or work again. This makes every save fail.
+    pub(crate) fn fail_syncs(&self, fail: bool) {
+        self.syncs.store(fail, Ordering::Relaxed);
+    }
+
+    /// A copy of what is stored right now, as if the process crashed.
+    pub(crate) fn crashed(&self) -> MemoryBackend {
+        let mut inner = self.inner.lock().unwrap();
+        let mut bytes = vec![0_u8; inner.len().unwrap() as usize];
+        inner.read_at(0, &mut bytes).unwrap();
+        let mut copy = MemoryBackend::default();
+        copy.write_at(0, &bytes).unwrap();
+        copy
+    }
+}
+
+impl StorageBackend for Faulty {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        if self.reads.load(Ordering::Relaxed) {
+            return Err(ErrorKind::Other.into());
+        }
+        self.inner.lock().unwrap().read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), io::Error> {
+        self.inner.lock().unwrap().write_at(offset, buf)
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        if self.syncs.load(Ordering::Relaxed) {
+            return Err(ErrorKind::Other.into());
+        }
+        Ok(())
+    }
+
+    fn len(&mut self) -> Result<u64, io::Error> {
+        self.inner.lock().unwrap().len()
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<(), io::Error> {
+        self.inner.lock().unwrap().set_len(len)
+    }
+}
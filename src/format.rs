@@ -0,0 +1,255 @@
+//! Identification headers of the data and allocation table files.
+//!
+//! Both files start with a magic number, the format version and the UUID of the database they
+//! belong to. Files written before headers existed are format version 0 and are upgraded when
+//! they are loaded.
+//!
+//! Single-file databases have a different magic number in the data file, and keep their
+//! allocation table inside of the data area. Two slots in the header point to the last two
+//! copies of it, and are written alternately so that one of them is always intact.
+
+use std::{
+    collections::hash_map::RandomState,
+    fs::{self, File},
+    hash::{BuildHasher, Hasher},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    process,
+    time::SystemTime,
+};
+
+use crate::{backend::StorageBackend, Error, FormatError};
+
+/// The format version written by this version of microdb.
+pub(crate) const FORMAT_VERSION: u64 = 4;
+/// Length of the reserved region at the start of the data file. Allocations start after it.
+pub(crate) const DATA_HEADER_LEN: u64 = 4096;
+
+const DATA_MAGIC: [u8; 8] = *b"MicroDBd";
+const META_MAGIC: [u8; 8] = *b"MicroDBm";
+/// The allocation table of an encrypted database, see [`crate::encryption`].
+const ENCRYPTED_META_MAGIC: [u8; 8] = *b"MicroDBe";
+const SINGLE_MAGIC: [u8; 8] = *b"MicroDBs";
+/// Positions of the two table slots in the header of a single-file database.
+const SLOT_OFFSETS: [u64; 2] = [1024, 2048];
+const SLOT_LEN: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub(crate) version: u64,
+    pub(crate) uuid: u128,
+}
+
+impl Header {
+    /// A header for a brand new database.
+    pub(crate) fn generate() -> Self {
+        // RandomState is seeded randomly per process, which is plenty for telling databases apart
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(SystemTime::UNIX_EPOCH.elapsed().unwrap().as_nanos());
+        hasher.write_u32(process::id());
+        let high = hasher.finish();
+        hasher.write_u64(high);
+        Self {
+            version: FORMAT_VERSION,
+            uuid: (high as u128) << 64 | hasher.finish() as u128,
+        }
+    }
+
+    fn write(&self, magic: [u8; 8], f: &mut impl Write) -> Result<(), io::Error> {
+        f.write_all(&magic)?;
+        f.write_all(&self.version.to_be_bytes())?;
+        f.write_all(&self.uuid.to_be_bytes())
+    }
+
+    /// Reads a header, returning None if the magic number is missing.
+    fn read(magic: [u8; 8], f: &mut impl Read) -> Result<Option<Self>, io::Error> {
+        let mut buf8 = [0_u8; 8];
+        match f.read_exact(&mut buf8) {
+            Ok(()) if buf8 == magic => (),
+            Ok(()) => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut buf16 = [0_u8; 16];
+        f.read_exact(&mut buf8)?;
+        f.read_exact(&mut buf16)?;
+        Ok(Some(Self {
+            version: u64::from_be_bytes(buf8),
+            uuid: u128::from_be_bytes(buf16),
+        }))
+    }
+
+    /// Writes the header of the allocation table at the current position.
+    pub(crate) fn write_meta(&self, f: &mut impl Write, encrypted: bool) -> Result<(), io::Error> {
+        match encrypted {
+            true => self.write(ENCRYPTED_META_MAGIC, f),
+            false => self.write(META_MAGIC, f),
+        }
+    }
+
+    /// Reads the header of an allocation table, and whether the table is encrypted. Returns
+    /// None for version 0 tables, which have no header; the reader is then rewound to the start.
+    pub(crate) fn read_meta(f: &mut (impl Read + Seek)) -> Result<Option<(Self, bool)>, io::Error> {
+        if let Some(header) = Self::read(META_MAGIC, f)? {
+            return Ok(Some((header, false)));
+        }
+        f.seek(SeekFrom::Start(0))?;
+        if let Some(header) = Self::read(ENCRYPTED_META_MAGIC, f)? {
+            return Ok(Some((header, true)));
+        }
+        f.seek(SeekFrom::Start(0))?;
+        Ok(None)
+    }
+
+    /// Writes the header of the data file, including the reserved space after it.
+    pub(crate) fn write_data(&self, f: &mut dyn StorageBackend) -> Result<(), io::Error> {
+        f.write_at(0, &self.data_bytes(DATA_MAGIC))
+    }
+
+    /// Writes the header of a single-file database, with both table slots empty.
+    pub(crate) fn write_single(&self, f: &mut dyn StorageBackend) -> Result<(), io::Error> {
+        f.write_at(0, &self.data_bytes(SINGLE_MAGIC))
+    }
+
+    fn data_bytes(&self, magic: [u8; 8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(DATA_HEADER_LEN as usize);
+        self.write(magic, &mut buf).unwrap();
+        buf.resize(DATA_HEADER_LEN as usize, 0);
+        buf
+    }
+
+    /// Reads the header of the data file. Returns None if the file has no header.
+    pub(crate) fn read_data(f: &mut dyn StorageBackend) -> Result<Option<Self>, io::Error> {
+        Self::read_at_start(DATA_MAGIC, f)
+    }
+
+    /// Reads the header of a single-file database. Returns None if the file isn't one.
+    pub(crate) fn read_single(f: &mut dyn StorageBackend) -> Result<Option<Self>, io::Error> {
+        Self::read_at_start(SINGLE_MAGIC, f)
+    }
+
+    fn read_at_start(
+        magic: [u8; 8],
+        f: &mut dyn StorageBackend,
+    ) -> Result<Option<Self>, io::Error> {
+        let mut buf = [0_u8; 32];
+        match f.read_at(0, &mut buf) {
+            Ok(()) => Self::read(magic, &mut &buf[..]),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Checks that a data file and allocation table belong together and can be read.
+    pub(crate) fn check_pair(data: Option<Self>, meta: Self) -> Result<(), Error> {
+        let error = match data {
+            None => FormatError::NotADatabase,
+            Some(data) if data.version > FORMAT_VERSION => {
+                FormatError::UnsupportedVersion(data.version)
+            }
+            Some(data) if data.uuid != meta.uuid => FormatError::Mismatch {
+                data: data.uuid,
+                meta: meta.uuid,
+            },
+            Some(_) => return Ok(()),
+        };
+        Err(error.into())
+    }
+}
+
+/// Points to a copy of the allocation table inside of a single-file database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Slot {
+    /// Incremented on every save. The valid slot with the highest one is the current table.
+    pub(crate) generation: u64,
+    /// Start and length of the space allocated for the table in the data area.
+    pub(crate) extent: (usize, usize),
+    /// Length and CRC32 of the table itself.
+    pub(crate) len: usize,
+    pub(crate) crc: u32,
+}
+
+impl Slot {
+    /// Writes the slot with the given index. Its own checksum makes a torn write detectable.
+    pub(crate) fn write(&self, f: &mut dyn StorageBackend, index: usize) -> Result<(), io::Error> {
+        let mut buf = Vec::with_capacity(SLOT_LEN);
+        buf.extend_from_slice(&self.generation.to_be_bytes());
+        buf.extend_from_slice(&(self.extent.0 as u64).to_be_bytes());
+        buf.extend_from_slice(&(self.extent.1 as u64).to_be_bytes());
+        buf.extend_from_slice(&(self.len as u64).to_be_bytes());
+        buf.extend_from_slice(&self.crc.to_be_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&buf).to_be_bytes());
+        f.write_at(SLOT_OFFSETS[index], &buf)
+    }
+
+    /// Reads both slots. Slots that were never written or were torn are None.
+    pub(crate) fn read_all(f: &mut dyn StorageBackend) -> Result<[Option<Self>; 2], io::Error> {
+        let mut slots = [None; 2];
+        for (slot, offset) in slots.iter_mut().zip(SLOT_OFFSETS) {
+            let mut buf = [0_u8; SLOT_LEN];
+            f.read_at(offset, &mut buf)?;
+            let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+            let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+            if crc32fast::hash(&buf[..36]) == u32_at(36) {
+                *slot = Some(Self {
+                    generation: u64_at(0),
+                    extent: (u64_at(8) as usize, u64_at(16) as usize),
+                    len: u64_at(24) as usize,
+                    crc: u32_at(32),
+                });
+            }
+        }
+        Ok(slots)
+    }
+}
+
+/// Where [`upgrade_data`] keeps the upgraded contents of a data file. It is only removed by
+/// [`finish_upgrade`] once the allocation table was upgraded as well, so while it exists, a data
+/// file with a header may still belong to a version 0 table.
+pub(crate) fn upgrade_copy(filename: &str) -> String {
+    filename.to_owned() + ".upgrade"
+}
+
+/// Upgrades a version 0 data file by moving its contents behind a header. This happens in place,
+/// so that the file stays locked. The upgraded contents are kept at [`upgrade_copy`] until the
+/// upgrade is finished, and an upgrade that was interrupted is finished from there. Returns the
+/// header of the data file, which may already have been upgraded.
+pub(crate) fn upgrade_data(
+    f: &mut dyn StorageBackend,
+    filename: &str,
+) -> Result<Header, io::Error> {
+    let copy = upgrade_copy(filename);
+    if let Some(header) = Header::read_data(f)? {
+        return Ok(header);
+    }
+    let bytes = match fs::read(&copy) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let mut bytes = Header::generate().data_bytes(DATA_MAGIC);
+            bytes.resize(DATA_HEADER_LEN as usize + f.len()? as usize, 0);
+            f.read_at(0, &mut bytes[DATA_HEADER_LEN as usize..])?;
+            let tmp = filename.to_owned() + ".tmp";
+            let mut new = File::create(&tmp)?;
+            new.write_all(&bytes)?;
+            new.sync_all()?;
+            fs::rename(tmp, &copy)?;
+            bytes
+        }
+        Err(e) => return Err(e),
+    };
+    let header = Header::read(DATA_MAGIC, &mut &bytes[..])?
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "damaged upgrade copy"))?;
+    // the file only counts as upgraded once it has a header, so that is written last
+    f.write_at(DATA_HEADER_LEN, &bytes[DATA_HEADER_LEN as usize..])?;
+    f.sync()?;
+    f.write_at(0, &bytes[..DATA_HEADER_LEN as usize])?;
+    f.sync()?;
+    Ok(header)
+}
+
+/// Removes the copy [`upgrade_data`] made, once the allocation table was upgraded as well.
+pub(crate) fn finish_upgrade(filename: &str) -> Result<(), io::Error> {
+    match fs::remove_file(upgrade_copy(filename)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,214 @@
+//! Free space management for the [`crate::FAlloc`] data file.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The free extents of a data file. Extents never overlap or touch; neighbours are always merged
+/// into one. Allocation is best-fit, preferring lower addresses among equally good fits.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct FreeList {
+    by_start: BTreeMap<usize, usize>,  // start -> length
+    by_size: BTreeSet<(usize, usize)>, // (length, start)
+    /// What changed since [`Self::forget_changes`], if that is tracked.
+    changes: Option<Vec<FreeChange>>,
+}
+
+/// A change of a [`FreeList`]. Applying the same changes in the same order to the list as it
+/// was before them gives the same list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FreeChange {
+    /// All extents were removed.
+    Clear,
+    /// An extent was freed.
+    Free(usize, usize),
+    /// An extent was taken from the start of a free one.
+    Take(usize, usize),
+}
+
+impl FreeList {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts keeping track of changes, so that they can be saved instead of the whole list.
+    pub(crate) fn track_changes(&mut self) {
+        self.changes = Some(Vec::new());
+    }
+
+    /// The changes since they were last forgotten. Empty if they aren't tracked.
+    pub(crate) fn changes(&self) -> &[FreeChange] {
+        self.changes.as_deref().unwrap_or_default()
+    }
+
+    /// Forgets the changes, once the list they led to was saved.
+    pub(crate) fn forget_changes(&mut self) {
+        if let Some(changes) = &mut self.changes {
+            changes.clear();
+        }
+    }
+
+    fn record(&mut self, change: FreeChange) {
+        if let Some(changes) = &mut self.changes {
+            changes.push(change);
+        }
+    }
+
+    /// Applies a change that was saved. Returns false if it doesn't fit this list.
+    pub(crate) fn apply(&mut self, change: FreeChange) -> bool {
+        match change {
+            FreeChange::Clear => self.clear(),
+            FreeChange::Free(start, len) => self.insert(start, len),
+            FreeChange::Take(start, len) => return self.alloc_at(start, len),
+        }
+        true
+    }
+
+    /// Removes all extents.
+    pub(crate) fn clear(&mut self) {
+        self.by_start.clear();
+        self.by_size.clear();
+        self.record(FreeChange::Clear);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.by_start.len()
+    }
+
+    /// All extents as (start, length), in order of their start.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.by_start.iter().map(|(&start, &len)| (start, len))
+    }
+
+    /// The extent with the highest start.
+    pub(crate) fn last(&self) -> Option<(usize, usize)> {
+        self.by_start
+            .last_key_value()
+            .map(|(&start, &len)| (start, len))
+    }
+
+    fn remove(&mut self, start: usize) -> usize {
+        let len = self.by_start.remove(&start).unwrap();
+        self.by_size.remove(&(len, start));
+        len
+    }
+
+    fn add(&mut self, start: usize, len: usize) {
+        self.by_start.insert(start, len);
+        self.by_size.insert((len, start));
+    }
+
+    /// Marks an extent as free, merging it with its neighbours. Parts that are already free
+    /// are merged as well, so freeing never duplicates space.
+    pub(crate) fn insert(&mut self, mut start: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.record(FreeChange::Free(start, len));
+        let mut end = start + len;
+        // an extent starting before this one may touch or overlap it
+        if let Some((&prev, &prev_len)) = self.by_start.range(..=start).next_back() {
+            if prev + prev_len >= start {
+                self.remove(prev);
+                start = prev;
+                end = end.max(prev + prev_len);
+            }
+        }
+        // extents starting inside or right after it
+        while let Some((&next, &next_len)) = self.by_start.range(start..=end).next() {
+            self.remove(next);
+            end = end.max(next + next_len);
+        }
+        self.add(start, end - start);
+    }
+
+    /// Takes `amount` bytes from the smallest extent that is large enough. Returns the start.
+    pub(crate) fn alloc(&mut self, amount: usize) -> Option<usize> {
+        let &(len, start) = self.by_size.range((amount, 0)..).next()?;
+        self.remove(start);
+        if len > amount {
+            self.add(start + amount, len - amount);
+        }
+        self.record(FreeChange::Take(start, amount));
+        Some(start)
+    }
+
+    /// Takes `amount` bytes starting exactly at `start`, if they are free. This is used to grow
+    /// allocations in place.
+    pub(crate) fn alloc_at(&mut self, start: usize, amount: usize) -> bool {
+        match self.by_start.get(&start) {
+            Some(&len) if len >= amount => {
+                self.remove(start);
+                if len > amount {
+                    self.add(start + amount, len - amount);
+                }
+                self.record(FreeChange::Take(start, amount));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Takes the extent that starts at `start` completely.
+    pub(crate) fn take(&mut self, start: usize) -> Option<usize> {
+        let len = self
+            .by_start
+            .contains_key(&start)
+            .then(|| self.remove(start))?;
+        self.record(FreeChange::Take(start, len));
+        Some(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FreeChange, FreeList};
+
+    #[test]
+    fn merging() {
+        let mut free = FreeList::new();
+        free.insert(0, 10);
+        free.insert(20, 10);
+        assert_eq!(free.len(), 2);
+        free.insert(10, 10);
+        assert_eq!(free.iter().collect::<Vec<_>>(), vec![(0, 30)]);
+        free.insert(5, 50);
+        assert_eq!(free.iter().collect::<Vec<_>>(), vec![(0, 55)]);
+    }
+
+    #[test]
+    fn best_fit() {
+        let mut free = FreeList::new();
+        free.insert(0, 30);
+        free.insert(40, 10);
+        free.insert(60, 20);
+        assert_eq!(free.alloc(10), Some(40));
+        assert_eq!(free.alloc(15), Some(60));
+        assert_eq!(free.alloc(31), None);
+        assert_eq!(free.iter().collect::<Vec<_>>(), vec![(0, 30), (75, 5)]);
+        assert!(free.alloc_at(0, 20));
+        assert!(!free.alloc_at(0, 5));
+        assert_eq!(free.take(75), Some(5));
+        assert_eq!(free.iter().collect::<Vec<_>>(), vec![(20, 10)]);
+    }
+
+    #[test]
+    fn changes() {
+        let mut free = FreeList::new();
+        free.insert(0, 100);
+        free.track_changes();
+        let saved = free.clone();
+        free.alloc(10);
+        free.insert(50, 10);
+        free.alloc_at(10, 5);
+        free.take(15);
+        free.insert(200, 10);
+        assert_eq!(free.changes().len(), 5);
+        let mut replayed = saved;
+        for &change in free.changes() {
+            assert!(replayed.apply(change));
+        }
+        assert_eq!(replayed.iter().collect::<Vec<_>>(), vec![(200, 10)]);
+        assert!(!replayed.apply(FreeChange::Take(0, 10)));
+        free.forget_changes();
+        assert!(free.changes().is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+//! The journal of an allocation table that is kept in its own file.
+//!
+//! Saving appends only the entries that changed since the last save to the end of the table
+//! file, together with the changes of the free list, as one batch. Batches are framed with their
+//! length and a CRC32 like records in the write-ahead log, so a batch that was torn by a crash
+//! is ignored as a whole. Once the journal
+//! has grown larger than the table in front of it, the table is rewritten without one. The
+//! batches of an encrypted table are sealed with its current key.
+
+use std::io::{self, Cursor};
+
+use crate::{
+    encryption::{self, Keys},
+    free::FreeChange,
+    storage::Allocation,
+};
+
+const CLEAR: u8 = 0;
+const FREE: u8 = 1;
+const TAKE: u8 = 2;
+
+/// The changes of one save.
+#[derive(Debug)]
+pub(crate) struct Batch {
+    pub(crate) blocks_reserved: usize,
+    /// The changes of the free list since the previous save, in order. Batches written before
+    /// version 4 don't have them.
+    pub(crate) free: Vec<FreeChange>,
+    /// None removes the path from the table.
+    pub(crate) changes: Vec<(String, Option<Allocation>)>,
+}
+
+impl Batch {
+    /// Encodes a batch including its length and checksum.
+    pub(crate) fn frame<'a>(
+        blocks_reserved: usize,
+        free: &[FreeChange],
+        changes: impl Iterator<Item = (&'a String, Option<&'a Allocation>)>,
+        keys: &Keys,
+    ) -> Result<Vec<u8>, io::Error> {
+        let mut payload = Vec::new();
+        let mut count = 0_u64;
+        payload.extend_from_slice(&(blocks_reserved as u64).to_be_bytes());
+        payload.extend_from_slice(&(free.len() as u64).to_be_bytes());
+        for change in free {
+            let (kind, start, len) = match *change {
+                FreeChange::Clear => (CLEAR, 0, 0),
+                FreeChange::Free(start, len) => (FREE, start, len),
+                FreeChange::Take(start, len) => (TAKE, start, len),
+            };
+            payload.push(kind);
+            payload.extend_from_slice(&(start as u64).to_be_bytes());
+            payload.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        let count_at = payload.len();
+        payload.extend_from_slice(&count.to_be_bytes());
+        for (path, allocation) in changes {
+            payload.extend_from_slice(&(path.len() as u64).to_be_bytes());
+            payload.extend_from_slice(path.as_bytes());
+            payload.push(allocation.is_some() as u8);
+            if let Some(allocation) = allocation {
+                allocation.serialize(&mut payload)?;
+            }
+            count += 1;
+        }
+        payload[count_at..count_at + 8].copy_from_slice(&count.to_be_bytes());
+        let mut payload = keys.seal(encryption::JOURNAL, payload);
+        let mut frame = Vec::with_capacity(payload.len() + 12);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+        frame.append(&mut payload);
+        Ok(frame)
+    }
+
+    fn decode(payload: &[u8], version: u64) -> Result<Self, io::Error> {
+        let mut f = Cursor::new(payload);
+        let mut buf64 = [0_u8; 8];
+        let mut buf8 = [0_u8; 1];
+        let mut read_u64 = |f: &mut Cursor<&[u8]>| -> Result<usize, io::Error> {
+            io::Read::read_exact(f, &mut buf64)?;
+            Ok(u64::from_be_bytes(buf64) as usize)
+        };
+        let blocks_reserved = read_u64(&mut f)?;
+        let mut free = Vec::new();
+        if version >= 4 {
+            for _ in 0..read_u64(&mut f)? {
+                io::Read::read_exact(&mut f, &mut buf8)?;
+                let kind = buf8[0];
+                let (start, len) = (read_u64(&mut f)?, read_u64(&mut f)?);
+                free.push(match kind {
+                    CLEAR => FreeChange::Clear,
+                    FREE => FreeChange::Free(start, len),
+                    TAKE => FreeChange::Take(start, len),
+                    _ => return Err(io::ErrorKind::InvalidData.into()),
+                });
+            }
+        }
+        let count = read_u64(&mut f)?;
+        let mut changes = Vec::new();
+        for _ in 0..count {
+            let len = read_u64(&mut f)?;
+            let start = f.position() as usize;
+            let path = payload
+                .get(start..start + len)
+                .and_then(|x| String::from_utf8(x.to_vec()).ok())
+                .ok_or(io::ErrorKind::InvalidData)?;
+            f.set_position((start + len) as u64);
+            io::Read::read_exact(&mut f, &mut buf8)?;
+            let allocation = match buf8[0] {
+                0 => None,
+                _ => Some(Allocation::deserialize(&mut f, version)?),
+            };
+            changes.push((path, allocation));
+        }
+        Ok(Self {
+            blocks_reserved,
+            free,
+            changes,
+        })
+    }
+
+    /// Decodes all batches that were completely written. Returns them together with the number
+    /// of bytes they take up.
+    pub(crate) fn read_all(mut bytes: &[u8], version: u64, keys: &Keys) -> (Vec<Self>, usize) {
+        let mut batches = Vec::new();
+        let mut valid = 0;
+        while bytes.len() >= 12 {
+            let len = u64::from_be_bytes(bytes[0..8].try_into().unwrap()) as usize;
+            let crc = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
+            if bytes.len() - 12 < len {
+                break;
+            }
+            let payload = &bytes[12..12 + len];
+            if crc32fast::hash(payload) != crc {
+                break;
+            }
+            let Some(payload) = keys.open(encryption::JOURNAL, payload.to_vec()) else {
+                break;
+            };
+            let Ok(batch) = Self::decode(&payload, version) else {
+                break;
+            };
+            batches.push(batch);
+            bytes = &bytes[12 + len..];
+            valid += 12 + len;
+        }
+        (batches, valid)
+    }
+}
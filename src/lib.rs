@@ -1,7 +1,38 @@
 //! See [`MicroDB`], [`FAlloc`], and [`crate::data::traits`].
 
+pub mod backend;
+pub mod check;
+#[cfg(test)]
+mod cleanup;
+pub mod client;
+mod compression;
 pub mod data;
 pub mod db;
+mod encryption;
+pub mod error;
+#[cfg(test)]
+mod faults;
+mod format;
+mod free;
+mod journal;
+mod logging;
+pub mod mirror;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod protocol;
+pub mod recovery;
+pub mod server;
 pub mod storage;
+mod transaction;
+mod wal;
+pub use backend::*;
+pub use check::*;
+pub use client::*;
 pub use db::*;
+#[cfg(feature = "encryption")]
+pub use encryption::Key;
+pub use error::*;
+pub use mirror::*;
+pub use recovery::*;
+pub use server::*;
 pub use storage::*;
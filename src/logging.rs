@@ -0,0 +1,53 @@
+//! Diagnostics of the storage, emitted through the `log` facade, or through `tracing` with the
+//! `tracing` feature. With neither feature, they are dropped.
+//!
+//! Fields are recorded with their `Display` implementation. Spans only exist with `tracing`.
+
+use std::time::{Duration, Instant};
+
+/// Emits an event at a level (`error`, `warn`, `info`, `debug` or `trace`), with fields.
+macro_rules! event {
+    ($level:ident, $message:literal) => {{
+        #[cfg(feature = "tracing")]
+        tracing::$level!($message);
+        #[cfg(all(feature = "log", not(feature = "tracing")))]
+        log::$level!($message);
+    }};
+    ($level:ident, $message:literal, $($key:ident = $value:expr),+ $(,)?) => {{
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($key = %$value,)+ $message);
+        #[cfg(all(feature = "log", not(feature = "tracing")))]
+        log::$level!($($key:% = $value),+; $message);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        {
+            $(let _ = &$value;)+
+        }
+    }};
+}
+
+/// Enters a debug span that lasts until the end of the enclosing block.
+macro_rules! span {
+    ($name:literal $(, $key:ident = $value:expr)* $(,)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($name $(, $key = %$value)*).entered();
+    };
+}
+
+pub(crate) use event;
+pub(crate) use span;
+
+/// Disk operations that take at least this long are reported.
+const SLOW: Duration = Duration::from_millis(500);
+
+/// Warns about a disk operation that started at `start` if it was slow.
+pub(crate) fn slow(operation: &str, start: Instant) {
+    let elapsed = start.elapsed();
+    if elapsed >= SLOW {
+        event!(
+            warn,
+            "slow disk operation",
+            operation = operation,
+            millis = elapsed.as_millis(),
+        );
+    }
+}
@@ -0,0 +1,626 @@
+//! Mirroring every mutation of a primary database to followers over TCP.
+//!
+//! A [`Primary`] numbers the mutations of its database and keeps the most recent ones in
+//! memory. A [`Follower`] connects to it and says which mutation it applied last, which is stored
+//! in its own database at [`MIRROR_POSITION`] together with the mutation itself. If the primary
+//! still has everything after that, the follower catches up from there. Otherwise (for example
+//! because the primary was restarted, or the follower was gone for too long) the follower is
+//! cleared and sent a full copy first.
+//!
+//! # Protocol
+//!
+//! All integers are big-endian. The primary sends the 8 bytes `MicroDBm` and the protocol
+//! version (currently 1) as a u64. The follower answers with the epoch of the primary it
+//! followed as a u128 (0 if none) and the sequence number of the last mutation it applied as a
+//! u64. Each primary picks a random epoch when it starts.
+//!
+//! From then on, the primary sends messages that start with a type byte:
+//!
+//! | Type | Message | Contents                                                            |
+//! |------|---------|---------------------------------------------------------------------|
+//! | 0    | record  | sequence number, then a mutation framed like in the write-ahead log |
+//! | 1    | reset   | epoch. The follower deletes everything, and a full copy follows     |
+//! | 2    | value   | a value of the full copy, framed like a mutation                    |
+//! | 3    | synced  | the sequence number the full copy is at                             |
+//! | 4    | alive   | nothing. Sent when there was nothing else to send for a while       |
+//!
+//! Sequence numbers of records always follow each other without gaps. A follower that hears
+//! nothing from the primary for a while, not even that it is alive, connects again.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    format::Header,
+    protocol::{read_u64, read_u8},
+    wal::Record,
+    Error, FAlloc, MicroDB,
+};
+
+/// Where a follower keeps the position of the last mutation it applied. It is below
+/// [`crate::RESERVED`], so it doesn't show up among the paths of the database.
+pub const MIRROR_POSITION: &str = "microdb-internal/mirror-position";
+
+const MAGIC: &[u8; 8] = b"MicroDBm";
+const PROTOCOL_VERSION: u64 = 1;
+
+const RECORD: u8 = 0;
+const RESET: u8 = 1;
+const VALUE: u8 = 2;
+const SYNCED: u8 = 3;
+const ALIVE: u8 = 4;
+
+/// How many bytes of recent mutations a primary keeps for followers to catch up with.
+const BACKLOG_LIMIT: usize = 64 * 1024 * 1024;
+/// How often waiting threads check whether they were stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a follower waits before connecting again.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+/// How long a primary waits for a follower to take or send anything before dropping it.
+const FOLLOWER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a follower waits for a primary to send anything before connecting again.
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a primary waits without sending anything before telling followers it is alive.
+const ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The recent mutations of a primary. [`FAlloc`] pushes to it while it is locked, so the order
+/// is the order they were applied in.
+#[derive(Debug)]
+pub(crate) struct Backlog {
+    epoch: u128,
+    state: Mutex<BacklogState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct BacklogState {
+    /// The sequence number of the latest mutation.
+    last: u64,
+    /// The latest mutations with their sequence numbers, already framed.
+    frames: VecDeque<(u64, Arc<[u8]>)>,
+    bytes: usize,
+}
+
+impl Backlog {
+    fn lock(&self) -> MutexGuard<'_, BacklogState> {
+        // the state is always consistent between operations, so poisoning can be ignored
+        self.state.lock().unwrap_or_else(|x| x.into_inner())
+    }
+
+    pub(crate) fn push(&self, record: &Record) {
+        let frame: Arc<[u8]> = record.frame().into();
+        let mut state = self.lock();
+        state.last += 1;
+        state.bytes += frame.len();
+        let last = state.last;
+        state.frames.push_back((last, frame));
+        while state.bytes > BACKLOG_LIMIT {
+            let (_, frame) = state.frames.pop_front().unwrap();
+            state.bytes -= frame.len();
+        }
+        self.changed.notify_all();
+    }
+
+    /// Whether everything after `seq` is still here.
+    fn has_after(&self, seq: u64) -> bool {
+        let state = self.lock();
+        seq <= state.last && state.frames.front().map_or(state.last + 1, |x| x.0) <= seq + 1
+    }
+
+    /// Waits a bit for mutations after `seq`, and returns them. None if some of them were
+    /// already dropped.
+    fn after(&self, seq: u64) -> Option<Vec<(u64, Arc<[u8]>)>> {
+        let mut state = self.lock();
+        if state.last == seq {
+            state = self
+                .changed
+                .wait_timeout(state, POLL_INTERVAL)
+                .unwrap_or_else(|x| x.into_inner())
+                .0;
+        }
+        let first = state.frames.front().map_or(state.last + 1, |x| x.0);
+        if first > seq + 1 {
+            return None;
+        }
+        Some(
+            state
+                .frames
+                .iter()
+                .skip((seq + 1 - first) as usize)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+struct PrimaryShared {
+    db: FAlloc,
+    backlog: Arc<Backlog>,
+    listener: TcpListener,
+    /// The connections to followers, to cut them off when stopping.
+    followers: Mutex<Vec<(SocketAddr, TcpStream)>>,
+    stopped: AtomicBool,
+}
+
+/// Sends every mutation of a database to the [`Follower`]s that connect to it. Stops when it is
+/// dropped.
+pub struct Primary {
+    shared: Arc<PrimaryShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Primary {
+    /// Starts accepting followers on the address.
+    pub fn start<A: ToSocketAddrs>(db: &MicroDB, address: A) -> Result<Self, Error> {
+        Self::start_on(db.local()?.share(), address)
+    }
+
+    fn start_on<A: ToSocketAddrs>(db: FAlloc, address: A) -> Result<Self, Error> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let backlog = Arc::new(Backlog {
+            epoch: Header::generate().uuid,
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        });
+        db.set_mirror(Some(backlog.clone()))?;
+        let shared = Arc::new(PrimaryShared {
+            db,
+            backlog,
+            listener,
+            followers: Mutex::default(),
+            stopped: AtomicBool::new(false),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.run())
+        };
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// The address followers can connect to, which is useful when binding to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.shared.listener.local_addr()?)
+    }
+
+    /// Disconnects all followers and stops mirroring. The database itself keeps working.
+    pub fn stop(self) {}
+}
+
+impl Drop for Primary {
+    fn drop(&mut self) {
+        let _ = self.shared.db.set_mirror(None);
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        // followers that don't read would keep writes from returning
+        for (_, stream) in self.shared.followers().iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl PrimaryShared {
+    fn followers(&self) -> MutexGuard<'_, Vec<(SocketAddr, TcpStream)>> {
+        // the list is always consistent between operations, so poisoning can be ignored
+        self.followers.lock().unwrap_or_else(|x| x.into_inner())
+    }
+
+    fn run(&self) {
+        thread::scope(|s| {
+            while !self.stopped.load(Ordering::Relaxed) {
+                match self.listener.accept() {
+                    Ok((stream, address)) => {
+                        let Ok(clone) = stream.try_clone() else {
+                            continue;
+                        };
+                        self.followers().push((address, clone));
+                        s.spawn(move || {
+                            // a follower that fails reconnects on its own
+                            let _ = self.serve(stream);
+                            self.followers().retain(|x| x.0 != address);
+                        });
+                    }
+                    Err(_) => thread::sleep(POLL_INTERVAL),
+                }
+            }
+        });
+    }
+
+    fn serve(&self, stream: TcpStream) -> Result<(), Error> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        // a follower that stops reading must not keep the primary from stopping
+        stream.set_read_timeout(Some(FOLLOWER_TIMEOUT))?;
+        stream.set_write_timeout(Some(FOLLOWER_TIMEOUT))?;
+        let mut reader = stream.try_clone()?;
+        let mut writer = BufWriter::new(stream);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&PROTOCOL_VERSION.to_be_bytes())?;
+        writer.flush()?;
+        let mut epoch = [0_u8; 16];
+        reader.read_exact(&mut epoch)?;
+        let mut seq = read_u64(&mut reader)?;
+
+        if u128::from_be_bytes(epoch) != self.backlog.epoch || !self.backlog.has_after(seq) {
+            seq = self.send_copy(&mut writer)?;
+        }
+        let mut sent = Instant::now();
+        while !self.stopped.load(Ordering::Relaxed) {
+            // a follower that fell too far behind has to start over
+            let frames = self
+                .backlog
+                .after(seq)
+                .ok_or(Error::Io(ErrorKind::Other.into()))?;
+            if frames.is_empty() {
+                if sent.elapsed() >= ALIVE_INTERVAL {
+                    writer.write_all(&[ALIVE])?;
+                    writer.flush()?;
+                    sent = Instant::now();
+                }
+                continue;
+            }
+            for (next, frame) in frames {
+                writer.write_all(&[RECORD])?;
+                writer.write_all(&next.to_be_bytes())?;
+                writer.write_all(&frame)?;
+                seq = next;
+            }
+            writer.flush()?;
+            sent = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Sends everything the database contains right now, and returns which mutation that
+    /// corresponds to.
+    fn send_copy(&self, writer: &mut impl Write) -> Result<u64, Error> {
+        let (snapshot, seq) = self.db.snapshot_with(|| self.backlog.lock().last)?;
+        writer.write_all(&[RESET])?;
+        writer.write_all(&self.backlog.epoch.to_be_bytes())?;
+        for path in self.db.snapshot_paths(&snapshot, None, false)? {
+            let Some(data) = self.db.snapshot_get(&snapshot, &path)? else {
+                continue;
+            };
+            writer.write_all(&[VALUE])?;
+            writer.write_all(&Record::Set { path, data }.frame())?;
+        }
+        writer.write_all(&[SYNCED])?;
+        writer.write_all(&seq.to_be_bytes())?;
+        writer.flush()?;
+        Ok(seq)
+    }
+}
+
+struct FollowerShared {
+    db: FAlloc,
+    primary: Vec<SocketAddr>,
+    stopped: AtomicBool,
+}
+
+/// Applies all mutations of a [`Primary`] to a database, reconnecting whenever the connection
+/// is lost. Stops when it is dropped.
+///
+/// The database should not be written to while it is following, as the primary doesn't know
+/// about those changes.
+pub struct Follower {
+    shared: Arc<FollowerShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Follower {
+    /// Starts following the primary at the address in the background.
+    pub fn start<A: ToSocketAddrs>(db: &MicroDB, primary: A) -> Result<Self, Error> {
+        let shared = Arc::new(FollowerShared {
+            db: db.local()?.share(),
+            primary: primary.to_socket_addrs()?.collect(),
+            stopped: AtomicBool::new(false),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                while !shared.stopped.load(Ordering::Relaxed) {
+                    if shared.follow().is_err() {
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stops following. The database keeps everything it received so far.
+    pub fn stop(self) {}
+
+    /// Stops following, and makes the database a primary that accepts followers on the
+    /// address. Other followers have to be started again to follow it, and are sent a full
+    /// copy the first time.
+    pub fn promote<A: ToSocketAddrs>(self, address: A) -> Result<Primary, Error> {
+        let db = self.shared.db.share();
+        drop(self);
+        Primary::start_on(db, address)
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Records that the follower is at a mutation.
+fn position(epoch: u128, seq: u64) -> Record {
+    let mut data = epoch.to_be_bytes().to_vec();
+    data.extend_from_slice(&seq.to_be_bytes());
+    Record::Set {
+        path: MIRROR_POSITION.to_owned(),
+        data,
+    }
+}
+
+impl FollowerShared {
+    /// Connects to the first address of the primary that accepts the connection in time.
+    fn connect(&self) -> Result<TcpStream, Error> {
+        let mut error = io::Error::from(ErrorKind::InvalidInput);
+        for address in &self.primary {
+            match TcpStream::connect_timeout(address, PRIMARY_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = e,
+            }
+        }
+        Err(error.into())
+    }
+
+    /// Follows the primary until the connection fails or the follower is stopped.
+    fn follow(&self) -> Result<(), Error> {
+        let stream = self.connect()?;
+        stream.set_nodelay(true)?;
+        // a primary that stops sending or reading must not keep the follower from stopping
+        stream.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+        stream.set_write_timeout(Some(PRIMARY_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let mut handshake = [0_u8; 16];
+        reader.read_exact(&mut handshake)?;
+        if &handshake[..8] != MAGIC || handshake[8..] != PROTOCOL_VERSION.to_be_bytes() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a MicroDB primary").into());
+        }
+        let (mut epoch, mut seq) = match self.db.get(MIRROR_POSITION)? {
+            Some(x) if x.len() == 24 => (
+                u128::from_be_bytes(x[..16].try_into().unwrap()),
+                u64::from_be_bytes(x[16..].try_into().unwrap()),
+            ),
+            _ => (0, 0),
+        };
+        writer.write_all(&epoch.to_be_bytes())?;
+        writer.write_all(&seq.to_be_bytes())?;
+
+        let mut received = Instant::now();
+        loop {
+            // waiting for the next message is interrupted to check whether the follower was
+            // stopped, reading one only times out when the primary stalls
+            reader.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+            let kind = match read_u8(&mut reader) {
+                Ok(kind) => kind,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if self.stopped.load(Ordering::Relaxed) {
+                        return Ok(());
+                    }
+                    if received.elapsed() >= PRIMARY_TIMEOUT {
+                        return Err(io::Error::from(ErrorKind::TimedOut).into());
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            received = Instant::now();
+            reader.get_ref().set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+            match kind {
+                RECORD => {
+                    let next = read_u64(&mut reader)?;
+                    let record = Record::read_frame(&mut reader)?;
+                    if next != seq + 1 {
+                        return Err(io::Error::from(ErrorKind::InvalidData).into());
+                    }
+                    seq = next;
+                    // the position is stored atomically with the mutation it belongs to
+                    self.db.commit(vec![record, position(epoch, seq)])?;
+                }
+                RESET => {
+                    let mut buf = [0_u8; 16];
+                    reader.read_exact(&mut buf)?;
+                    epoch = u128::from_be_bytes(buf);
+                    let clear = self
+                        .db
+                        .all_paths(None)?
+                        .into_iter()
+                        .chain([MIRROR_POSITION.to_owned()])
+                        .map(|path| Record::Set {
+                            path,
+                            data: Vec::new(),
+                        });
+                    self.db.commit(clear.collect())?;
+                }
+                VALUE => self.db.commit(vec![Record::read_frame(&mut reader)?])?,
+                SYNCED => {
+                    seq = read_u64(&mut reader)?;
+                    self.db.commit(vec![position(epoch, seq)])?;
+                }
+                ALIVE => (),
+                _ => return Err(io::Error::from(ErrorKind::InvalidData).into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{Backlog, BACKLOG_LIMIT, MAGIC, PRIMARY_TIMEOUT, PROTOCOL_VERSION, RECORD};
+    use crate::{cleanup::Files, wal::Record, Follower, MicroDB, Primary};
+
+    /// Waits until the follower has the value.
+    fn wait_for(db: &MicroDB, path: &str, value: u32) {
+        let start = Instant::now();
+        while db.get_raw::<u32, _>(path).unwrap() != Some(value) {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "{path} never arrived"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn oversized_record() {
+        let backlog = Backlog {
+            epoch: 0,
+            state: Default::default(),
+            changed: Default::default(),
+        };
+        backlog.push(&Record::Set {
+            path: "a".to_owned(),
+            data: vec![0; BACKLOG_LIMIT],
+        });
+        // the record didn't fit, so followers that are behind need a full copy
+        assert!(!backlog.has_after(0));
+        assert!(backlog.after(0).is_none());
+        assert!(backlog.has_after(1));
+        assert_eq!(backlog.after(1), Some(Vec::new()));
+    }
+
+    #[test]
+    fn stuck_follower() {
+        let _files = Files::new("mirror.s", &["dmdb", "mmdb", "mmdb.wal"]);
+        let db = MicroDB::create("mirror.s.dmdb", "mirror.s.mmdb", 100, 16).unwrap();
+        for i in 0..64 {
+            db.set_raw(format!("{i}"), vec![i as u8; 256 * 1024])
+                .unwrap();
+        }
+        let primary = Primary::start(&db, "127.0.0.1:0").unwrap();
+
+        // a follower that asks for a full copy and never reads it
+        let mut stream = TcpStream::connect(primary.local_addr().unwrap()).unwrap();
+        stream.write_all(&[0; 24]).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        let start = Instant::now();
+        primary.stop();
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        drop(stream);
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn stalled_primary() {
+        let _files = Files::new("mirror.t", &["dmdb", "mmdb", "mmdb.wal"]);
+        let db = MicroDB::create("mirror.t.dmdb", "mirror.t.mmdb", 100, 16).unwrap();
+
+        // a primary that starts sending a record, and then stalls
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = connections.clone();
+        thread::spawn(move || {
+            let mut streams = Vec::new();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                counted.fetch_add(1, Ordering::Relaxed);
+                stream.write_all(MAGIC).unwrap();
+                stream.write_all(&PROTOCOL_VERSION.to_be_bytes()).unwrap();
+                stream.read_exact(&mut [0; 24]).unwrap();
+                stream.write_all(&[RECORD, 0, 0]).unwrap();
+                streams.push(stream);
+            }
+        });
+
+        let follower = Follower::start(&db, address).unwrap();
+        thread::sleep(PRIMARY_TIMEOUT + Duration::from_secs(2));
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+        let start = Instant::now();
+        follower.stop();
+        assert!(start.elapsed() < PRIMARY_TIMEOUT + Duration::from_secs(1));
+
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn mirror() {
+        let _files = ["p", "f1", "f2"]
+            .map(|x| Files::new(&format!("mirror.{x}"), &["dmdb", "mmdb", "mmdb.wal"]));
+        let primary_db = MicroDB::create("mirror.p.dmdb", "mirror.p.mmdb", 100, 16).unwrap();
+        let first_db = MicroDB::create("mirror.f1.dmdb", "mirror.f1.mmdb", 100, 16).unwrap();
+        let second_db = MicroDB::create("mirror.f2.dmdb", "mirror.f2.mmdb", 100, 16).unwrap();
+        primary_db.set_raw("a", 1_u32).unwrap();
+        let primary = Primary::start(&primary_db, "127.0.0.1:0").unwrap();
+        let address = primary.local_addr().unwrap();
+
+        // a new follower gets a full copy, then every mutation
+        let follower = Follower::start(&first_db, address).unwrap();
+        primary_db.set_raw("b", 2_u32).unwrap();
+        wait_for(&first_db, "a", 1);
+        wait_for(&first_db, "b", 2);
+        primary_db.transaction(|tx| tx.remove("a")).unwrap();
+        primary_db.set_raw("c", 3_u32).unwrap();
+        wait_for(&first_db, "c", 3);
+        assert_eq!(first_db.get_raw::<u32, _>("a").unwrap(), None);
+
+        // it catches up after being disconnected
+        follower.stop();
+        primary_db.set_raw("d", 4_u32).unwrap();
+        let follower = Follower::start(&first_db, address).unwrap();
+        wait_for(&first_db, "d", 4);
+
+        // once the primary is gone, the follower takes over
+        primary.stop();
+        primary_db.shutdown().unwrap();
+        let primary = follower.promote("127.0.0.1:0").unwrap();
+        first_db.set_raw("e", 5_u32).unwrap();
+        let follower = Follower::start(&second_db, primary.local_addr().unwrap()).unwrap();
+        wait_for(&second_db, "e", 5);
+        assert_eq!(
+            second_db.get_all_paths(None::<&str>).unwrap(),
+            vec!["b", "c", "d", "e"]
+        );
+        assert!(second_db
+            .local()
+            .unwrap()
+            .get(crate::MIRROR_POSITION)
+            .unwrap()
+            .is_some());
+
+        follower.stop();
+        primary.stop();
+        first_db.shutdown().unwrap();
+        second_db.shutdown().unwrap();
+    }
+}
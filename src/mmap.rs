@@ -0,0 +1,82 @@
+//! Reading uncached values from a memory map of the data file (the `mmap` feature).
+//!
+//! Writes still go through the file, which the OS keeps coherent with the shared mapping. The
+//! mapping is only replaced when a value lies beyond its end, and must be dropped before the file
+//! is shrunk, as touching unmapped pages would crash the process.
+
+use std::{fs::File, io};
+
+use memmap2::Mmap;
+
+use crate::{encryption::Keys, format::DATA_HEADER_LEN, storage::Allocation, Error};
+
+#[derive(Debug, Default)]
+pub(crate) struct Mapping {
+    map: Option<Mmap>,
+}
+
+impl Mapping {
+    /// Reads a value from the mapping, mapping the file again if it has grown past it.
+    pub(crate) fn read(
+        &mut self,
+        allocation: &Allocation,
+        path: &str,
+        file: &File,
+        keys: &Keys,
+    ) -> Result<Vec<u8>, Error> {
+        let mut parts = Vec::with_capacity(allocation.locations.len());
+        let mut i = 0;
+        for location in &allocation.locations {
+            let end = (i + location.1).min(allocation.full_size);
+            if i >= end {
+                break;
+            }
+            let start = DATA_HEADER_LEN as usize + location.0;
+            parts.push(start..start + end - i);
+            i = end;
+        }
+        let needed = parts.iter().map(|x| x.end).max().unwrap_or(0);
+        if self.map.as_ref().is_none_or(|x| x.len() < needed) {
+            self.map = None;
+            // SAFETY: the data file is only shrunk after calling unmap
+            self.map = Some(unsafe { Mmap::map(file)? });
+        }
+        let map = self.map.as_ref().unwrap();
+        if map.len() < needed {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut bytes = Vec::with_capacity(allocation.full_size);
+        for part in parts {
+            bytes.extend_from_slice(&map[part]);
+        }
+        allocation.decode(path, allocation.verify(path, bytes)?, keys)
+    }
+
+    /// Drops the mapping. Must be called before the file is shrunk.
+    pub(crate) fn unmap(&mut self) {
+        self.map = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{cleanup::Files, FAlloc};
+
+    #[test]
+    fn mapped_reads() {
+        let _files = Files::db("mmap");
+        let db = FAlloc::create("mmap.dat", "mmap.alloc", 0, 16).unwrap();
+        db.set("a", vec![1; 100]).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some(vec![1; 100]));
+        // growing the file needs a new mapping, shrinking it must drop the old one
+        db.set("b", vec![2; 100_000]).unwrap();
+        assert_eq!(db.get("b").unwrap(), Some(vec![2; 100_000]));
+        db.set("a", vec![3; 50]).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some(vec![3; 50]));
+        db.set("b", Vec::new()).unwrap();
+        db.save().unwrap();
+        assert_eq!(db.get("a").unwrap(), Some(vec![3; 50]));
+        assert_eq!(db.get("b").unwrap(), None);
+        db.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+//! Encoding of the protocol described in [`crate::server`].
+
+use std::io::{self, ErrorKind, Read};
+
+use crate::{Corruption, Error, FormatError};
+
+/// Sent by the server when a connection is opened.
+pub(crate) const MAGIC: &[u8; 8] = b"MicroDBn";
+pub(crate) const PROTOCOL_VERSION: u64 = 2;
+/// The longest string or byte string that is read, see [`crate::server`].
+pub(crate) const MAX_LEN: u64 = 1 << 30;
+
+const GET: u8 = 0;
+const SET: u8 = 1;
+const REMOVE: u8 = 2;
+const PATHS: u8 = 3;
+const DELETE_SUBSTRUCTURE: u8 = 4;
+
+/// The I/O error kinds that are sent as themselves, by their index. Others are sent as
+/// [`ErrorKind::Other`].
+const IO_KINDS: [ErrorKind; 22] = [
+    ErrorKind::Other,
+    ErrorKind::NotFound,
+    ErrorKind::PermissionDenied,
+    ErrorKind::ConnectionRefused,
+    ErrorKind::ConnectionReset,
+    ErrorKind::ConnectionAborted,
+    ErrorKind::NotConnected,
+    ErrorKind::AddrInUse,
+    ErrorKind::AddrNotAvailable,
+    ErrorKind::BrokenPipe,
+    ErrorKind::AlreadyExists,
+    ErrorKind::WouldBlock,
+    ErrorKind::InvalidInput,
+    ErrorKind::InvalidData,
+    ErrorKind::TimedOut,
+    ErrorKind::WriteZero,
+    ErrorKind::Interrupted,
+    ErrorKind::Unsupported,
+    ErrorKind::UnexpectedEof,
+    ErrorKind::OutOfMemory,
+    ErrorKind::StorageFull,
+    ErrorKind::ResourceBusy,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Request {
+    Get { path: String },
+    Set { path: String, data: Vec<u8> },
+    Remove { path: String },
+    Paths { path: Option<String>, all: bool },
+    DeleteSubstructure { path: String },
+}
+
+/// The answer to a request that succeeded. Failures are sent as an [`Error`] instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Response {
+    /// The answer to a get.
+    Value(Option<Vec<u8>>),
+    /// The answer to a paths request.
+    Paths(Vec<String>),
+    /// The request succeeded and has no answer.
+    Done,
+}
+
+fn put(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+pub(crate) fn read_u8(r: &mut impl Read) -> Result<u8, io::Error> {
+    let mut buf = [0_u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub(crate) fn read_u64(r: &mut impl Read) -> Result<u64, io::Error> {
+    let mut buf = [0_u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_bytes(r: &mut impl Read) -> Result<Vec<u8>, io::Error> {
+    let len = read_u64(r)?;
+    if len > MAX_LEN {
+        return Err(ErrorKind::InvalidData.into());
+    }
+    // the length isn't trusted with an allocation of its size up front
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+fn read_string(r: &mut impl Read) -> Result<String, io::Error> {
+    String::from_utf8(read_bytes(r)?).map_err(|_| ErrorKind::InvalidData.into())
+}
+
+impl Request {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Request::Get { path } => {
+                buf.push(GET);
+                put(&mut buf, path.as_bytes());
+            }
+            Request::Set { path, data } => {
+                buf.push(SET);
+                put(&mut buf, path.as_bytes());
+                put(&mut buf, data);
+            }
+            Request::Remove { path } => {
+                buf.push(REMOVE);
+                put(&mut buf, path.as_bytes());
+            }
+            Request::Paths { path, all } => {
+                buf.push(PATHS);
+                buf.push(*all as u8);
+                buf.push(path.is_some() as u8);
+                if let Some(path) = path {
+                    put(&mut buf, path.as_bytes());
+                }
+            }
+            Request::DeleteSubstructure { path } => {
+                buf.push(DELETE_SUBSTRUCTURE);
+                put(&mut buf, path.as_bytes());
+            }
+        }
+        buf
+    }
+
+    /// Reads the arguments of a request whose opcode was already read.
+    pub(crate) fn read(opcode: u8, r: &mut impl Read) -> Result<Self, io::Error> {
+        Ok(match opcode {
+            GET => Request::Get {
+                path: read_string(r)?,
+            },
+            SET => Request::Set {
+                path: read_string(r)?,
+                data: read_bytes(r)?,
+            },
+            REMOVE => Request::Remove {
+                path: read_string(r)?,
+            },
+            PATHS => {
+                let all = read_u8(r)? != 0;
+                let path = match read_u8(r)? {
+                    0 => None,
+                    _ => Some(read_string(r)?),
+                };
+                Request::Paths { path, all }
+            }
+            DELETE_SUBSTRUCTURE => Request::DeleteSubstructure {
+                path: read_string(r)?,
+            },
+            _ => return Err(ErrorKind::InvalidData.into()),
+        })
+    }
+}
+
+impl Response {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Response::Value(value) => {
+                buf.push(0);
+                buf.push(value.is_some() as u8);
+                if let Some(value) = value {
+                    put(&mut buf, value);
+                }
+            }
+            Response::Paths(paths) => {
+                buf.push(0);
+                buf.extend_from_slice(&(paths.len() as u64).to_be_bytes());
+                for path in paths {
+                    put(&mut buf, path.as_bytes());
+                }
+            }
+            Response::Done => buf.push(0),
+        }
+        buf
+    }
+
+    /// Encodes a failed request, so that the client gets the same error back.
+    pub(crate) fn encode_error(error: &Error) -> Vec<u8> {
+        let mut buf = vec![1];
+        put(&mut buf, error.to_string().as_bytes());
+        match error {
+            Error::Io(e) => {
+                buf.push(0);
+                let kind = IO_KINDS.iter().position(|x| *x == e.kind()).unwrap_or(0);
+                buf.push(kind as u8);
+            }
+            Error::ShutDown => buf.push(1),
+            Error::Corrupt(Corruption::Checksum {
+                path,
+                expected,
+                found,
+            }) => {
+                buf.extend_from_slice(&[2, 0]);
+                put(&mut buf, path.as_bytes());
+                buf.extend_from_slice(&expected.to_be_bytes());
+                buf.extend_from_slice(&found.to_be_bytes());
+            }
+            Error::Corrupt(Corruption::Compression { path }) => {
+                buf.extend_from_slice(&[2, 1]);
+                put(&mut buf, path.as_bytes());
+            }
+            Error::Corrupt(Corruption::Authentication { path }) => {
+                buf.extend_from_slice(&[2, 2]);
+                put(&mut buf, path.as_bytes());
+            }
+            Error::Corrupt(Corruption::InvalidPath) => buf.extend_from_slice(&[2, 3]),
+            Error::Format(FormatError::NotADatabase) => buf.extend_from_slice(&[3, 0]),
+            Error::Format(FormatError::UnsupportedVersion(version)) => {
+                buf.extend_from_slice(&[3, 1]);
+                buf.extend_from_slice(&version.to_be_bytes());
+            }
+            Error::Format(FormatError::Mismatch { data, meta }) => {
+                buf.extend_from_slice(&[3, 2]);
+                buf.extend_from_slice(&data.to_be_bytes());
+                buf.extend_from_slice(&meta.to_be_bytes());
+            }
+            Error::Format(FormatError::UnsupportedCodec(codec)) => {
+                buf.extend_from_slice(&[3, 3, *codec]);
+            }
+            Error::Format(FormatError::Encrypted) => buf.extend_from_slice(&[3, 4]),
+            Error::Format(FormatError::NotEncrypted) => buf.extend_from_slice(&[3, 5]),
+            Error::Decode { path } => {
+                buf.push(4);
+                put(&mut buf, path.as_bytes());
+            }
+            Error::NotFound { path } => {
+                buf.push(5);
+                put(&mut buf, path.as_bytes());
+            }
+            Error::Poisoned => buf.push(6),
+            Error::ReadOnly => buf.push(7),
+            Error::Unsupported => buf.push(8),
+            Error::InUse { path } => {
+                buf.push(10);
+                put(&mut buf, path.as_bytes());
+            }
+            Error::WrongKey => buf.push(11),
+            // everything else only has its message
+            _ => buf.push(9),
+        }
+        buf
+    }
+
+    /// Reads the response to a request, which determines what it contains. A request that
+    /// failed on the server returns the error it failed with.
+    pub(crate) fn read(
+        request: &Request,
+        r: &mut impl Read,
+    ) -> Result<Result<Self, Error>, io::Error> {
+        if read_u8(r)? != 0 {
+            return Ok(Err(read_error(r)?));
+        }
+        Ok(Ok(match request {
+            Request::Get { .. } => match read_u8(r)? {
+                0 => Response::Value(None),
+                _ => Response::Value(Some(read_bytes(r)?)),
+            },
+            Request::Paths { .. } => {
+                let count = read_u64(r)?;
+                let mut paths = Vec::new();
+                for _ in 0..count {
+                    paths.push(read_string(r)?);
+                }
+                Response::Paths(paths)
+            }
+            _ => Response::Done,
+        }))
+    }
+}
+
+/// Reads an error that was encoded with [`Response::encode_error`].
+fn read_error(r: &mut impl Read) -> Result<Error, io::Error> {
+    let message = read_string(r)?;
+    let mut buf32 = [0_u8; 4];
+    let mut buf128 = [0_u8; 16];
+    Ok(match read_u8(r)? {
+        0 => {
+            let kind = IO_KINDS.get(read_u8(r)? as usize);
+            let message = message.strip_prefix("I/O error: ").unwrap_or(&message);
+            Error::Io(io::Error::new(
+                *kind.unwrap_or(&ErrorKind::Other),
+                message.to_owned(),
+            ))
+        }
+        1 => Error::ShutDown,
+        2 => Error::Corrupt(match read_u8(r)? {
+            0 => Corruption::Checksum {
+                path: read_string(r)?,
+                expected: (r.read_exact(&mut buf32)?, u32::from_be_bytes(buf32)).1,
+                found: (r.read_exact(&mut buf32)?, u32::from_be_bytes(buf32)).1,
+            },
+            1 => Corruption::Compression {
+                path: read_string(r)?,
+            },
+            2 => Corruption::Authentication {
+                path: read_string(r)?,
+            },
+            3 => Corruption::InvalidPath,
+            _ => return Ok(Error::Remote(message)),
+        }),
+        3 => Error::Format(match read_u8(r)? {
+            0 => FormatError::NotADatabase,
+            1 => FormatError::UnsupportedVersion(read_u64(r)?),
+            2 => FormatError::Mismatch {
+                data: (r.read_exact(&mut buf128)?, u128::from_be_bytes(buf128)).1,
+                meta: (r.read_exact(&mut buf128)?, u128::from_be_bytes(buf128)).1,
+            },
+            3 => FormatError::UnsupportedCodec(read_u8(r)?),
+            4 => FormatError::Encrypted,
+            5 => FormatError::NotEncrypted,
+            _ => return Ok(Error::Remote(message)),
+        }),
+        4 => Error::Decode {
+            path: read_string(r)?,
+        },
+        5 => Error::NotFound {
+            path: read_string(r)?,
+        },
+        6 => Error::Poisoned,
+        7 => Error::ReadOnly,
+        8 => Error::Unsupported,
+        10 => Error::InUse {
+            path: read_string(r)?,
+        },
+        11 => Error::WrongKey,
+        _ => Error::Remote(message),
+    })
+}
@@ -0,0 +1,123 @@
+//! What happens when the background thread of a [`crate::FAlloc`] fails to save.
+//!
+//! Mutations are logged before they are acknowledged, so unless the database has no log, a
+//! failed save loses nothing that the next successful one, or the next load, can't restore. What
+//! the database does meanwhile is chosen with [`crate::FAlloc::set_recovery_policy`], and a
+//! supervisor can be told about every failure with [`crate::FAlloc::on_error`].
+
+use std::{
+    fmt::{self, Debug},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::Error;
+
+/// What the background thread does after it failed to save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryPolicy {
+    /// Keeps trying, waiting `initial` after the first failure and twice as long after every
+    /// further one, but never longer than `max`. The database stays usable meanwhile.
+    Retry { initial: Duration, max: Duration },
+    /// Stops saving and makes the database read-only. Mutations that weren't saved yet can
+    /// still be read, and are replayed from the log when the database is loaded again.
+    ReadOnly,
+    /// Shuts the database down, so that using it fails with [`Error::ShutDown`]. Mutations
+    /// that weren't saved yet are replayed from the log when it is loaded again.
+    Stop,
+}
+
+impl Default for RecoveryPolicy {
+    /// Retries after one second at first, and at least once a minute.
+    fn default() -> Self {
+        Self::Retry {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RecoveryPolicy {
+    /// How long to wait before retrying after `failures` failures in a row, if at all.
+    pub(crate) fn delay(&self, failures: u32) -> Option<Duration> {
+        match *self {
+            Self::Retry { initial, max } => {
+                let factor = 1_u32 << failures.saturating_sub(1).min(31);
+                Some(initial.saturating_mul(factor).min(max))
+            }
+            Self::ReadOnly | Self::Stop => None,
+        }
+    }
+}
+
+/// Is told about errors of the background thread, see [`crate::FAlloc::on_error`].
+#[derive(Clone)]
+pub(crate) struct Callback(pub(crate) Arc<dyn Fn(&Error) + Send + Sync>);
+
+impl Debug for Callback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use crate::{faults::Faulty, Error, FAlloc, MemoryBackend, RecoveryPolicy};
+
+    /// A database whose saves fail, and the number of errors it reported.
+    fn failing(policy: RecoveryPolicy) -> (FAlloc, Faulty, Arc<Mutex<u32>>) {
+        let backend = Faulty::default();
+        let db =
+            FAlloc::create_with_backend(backend.clone(), MemoryBackend::default(), 0, 16).unwrap();
+        db.set("a", vec![1]).unwrap();
+        db.set_recovery_policy(policy).unwrap();
+        let errors = Arc::new(Mutex::new(0));
+        let errors_clone = errors.clone();
+        db.on_error(move |e| {
+            assert!(matches!(e, Error::Io(_)));
+            *errors_clone.lock().unwrap() += 1;
+        })
+        .unwrap();
+        backend.fail_syncs(true);
+        (db, backend, errors)
+    }
+
+    fn wait_for(errors: &Mutex<u32>, count: u32) {
+        while *errors.lock().unwrap() < count {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn recovery_policies() {
+        let (db, backend, errors) = failing(RecoveryPolicy::Retry {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(20),
+        });
+        wait_for(&errors, 3);
+        db.set("b", vec![2]).unwrap();
+        assert!(matches!(db.save(), Err(Error::Io(_))));
+        backend.fail_syncs(false);
+        db.save().unwrap();
+        db.shutdown().unwrap();
+
+        let (db, _, errors) = failing(RecoveryPolicy::ReadOnly);
+        wait_for(&errors, 1);
+        assert!(matches!(db.set("b", vec![2]), Err(Error::ReadOnly)));
+        assert_eq!(db.get("a").unwrap(), Some(vec![1]));
+        db.shutdown().unwrap();
+
+        let (db, _, errors) = failing(RecoveryPolicy::Stop);
+        wait_for(&errors, 1);
+        assert!(matches!(db.get("a"), Err(Error::ShutDown)));
+        assert!(matches!(db.shutdown(), Err(Error::ShutDown)));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(*errors.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,349 @@
+//! Serving a database to other processes over TCP.
+//!
+//! # Protocol
+//!
+//! All integers are big-endian. Strings and byte strings are sent as a u64 length followed by
+//! the bytes, and paths must be valid UTF-8. Neither may be longer than 1 GiB.
+//!
+//! When a connection is accepted, the server sends the 8 bytes `MicroDBn` followed by the
+//! protocol version (currently 2) as a u64. After that, the client sends requests and the server
+//! answers each of them in order. A request is an opcode byte followed by its arguments:
+//!
+//! | Opcode | Request                | Arguments                                        |
+//! |--------|------------------------|--------------------------------------------------|
+//! | 0      | get                    | path                                             |
+//! | 1      | set                    | path, data (empty data removes the value)        |
+//! | 2      | remove                 | path                                             |
+//! | 3      | paths                  | all (1 byte), has path (1 byte), path if present |
+//! | 4      | delete substructure    | path                                             |
+//!
+//! `paths` returns only direct sub-paths unless `all` is 1, and the root paths if there is no
+//! path.
+//!
+//! A response starts with a status byte. 0 means the request succeeded, and is followed by:
+//! - get: 1 byte that is 1 if the value exists, followed by the value if so
+//! - paths: the number of paths as a u64, followed by the paths
+//! - everything else: nothing
+//!
+//! 1 means the request failed, and is followed by the error message, a byte for the [`Error`]
+//! variant, and what the variant contains:
+//!
+//! | Code | Error                | Contents                                                 |
+//! |------|----------------------|----------------------------------------------------------|
+//! | 0    | `Io`                 | the `ErrorKind`, as a byte (0 is `Other`)                |
+//! | 1    | `ShutDown`           |                                                          |
+//! | 2    | `Corrupt`            | a byte for the `Corruption` variant, then its fields     |
+//! | 3    | `Format`             | a byte for the `FormatError` variant, then its fields    |
+//! | 4    | `Decode`             | path                                                     |
+//! | 5    | `NotFound`           | path                                                     |
+//! | 6    | `Poisoned`           |                                                          |
+//! | 7    | `ReadOnly`           |                                                          |
+//! | 8    | `Unsupported`        |                                                          |
+//! | 9    | anything else        |                                                          |
+//! | 10   | `InUse`              | path                                                     |
+//! | 11   | `WrongKey`           |                                                          |
+//!
+//! Variants are numbered in the order they are declared in, and integer fields have their own
+//! size. Clients turn errors they can't decode into [`Error::Remote`] with the message.
+//!
+//! A server closes the connection when it receives an unknown opcode or a string that is too
+//! long, or when the client stops sending a request or reading a response halfway for 5 seconds.
+//! Connections beyond the first 256 are closed right away. Run the `microdb-server` binary to
+//! serve a database from the command line.
+
+use std::{
+    io::{self, BufReader, ErrorKind, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    protocol::{read_u8, Request, Response, MAGIC, PROTOCOL_VERSION},
+    Error, FAlloc,
+};
+
+/// How often idle connections check whether the server was stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a client may take to send the rest of a request, or to take a response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many connections are served at the same time.
+const MAX_CONNECTIONS: usize = 256;
+
+/// Serves a [`FAlloc`] over TCP. Every connection is handled by its own thread, and all of them
+/// share the same database.
+pub struct Server {
+    db: FAlloc,
+    listener: TcpListener,
+    connections: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+impl Server {
+    /// Starts listening for connections. They are only accepted once [`Self::run`] is called.
+    pub fn bind<A: ToSocketAddrs>(db: FAlloc, address: A) -> Result<Self, Error> {
+        let listener = TcpListener::bind(address)?;
+        Ok(Self {
+            db,
+            listener,
+            connections: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    /// The address the server listens on, which is useful when binding to port 0.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accepts and serves connections until [`Self::stop`] is called. Returns once all
+    /// connections are closed.
+    pub fn run(&self) -> Result<(), Error> {
+        thread::scope(|s| loop {
+            let accepted = self.listener.accept();
+            // the connection that woke this up after stopping isn't served
+            if self.stopped.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            match accepted {
+                // there is one thread per connection, so their number is limited
+                Ok(_) if self.connections.load(Ordering::Relaxed) >= MAX_CONNECTIONS => (),
+                Ok((stream, _)) => {
+                    self.connections.fetch_add(1, Ordering::Relaxed);
+                    s.spawn(move || {
+                        // a connection that fails only affects its own client
+                        let _ = self.serve(stream);
+                        self.connections.fetch_sub(1, Ordering::Relaxed);
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => (),
+                Err(e) => return Err(e.into()),
+            }
+        })
+    }
+
+    /// Makes [`Self::run`] close all connections and return. Requests that are being handled
+    /// are finished first.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // run is waiting for a connection, so it is woken up with one
+        if let Ok(mut address) = self.listener.local_addr() {
+            if address.ip().is_unspecified() {
+                address.set_ip(match address {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect_timeout(&address, POLL_INTERVAL);
+        }
+    }
+
+    /// Gracefully shuts down the database, saving in the process.
+    pub fn shutdown(self) -> Result<(), Error> {
+        self.db.shutdown()
+    }
+
+    /// Gives back the database, for example to serve it again.
+    pub fn into_inner(self) -> FAlloc {
+        self.db
+    }
+
+    fn serve(&self, stream: TcpStream) -> Result<(), io::Error> {
+        stream.set_nodelay(true)?;
+        // a client that stops reading must not keep the server from stopping
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let mut handshake = MAGIC.to_vec();
+        handshake.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        writer.write_all(&handshake)?;
+        let mut reader = BufReader::new(stream);
+        while !self.stopped.load(Ordering::Relaxed) {
+            // waiting for the next request is interrupted to check whether the server was
+            // stopped, reading one only times out when the client stalls
+            reader.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+            let opcode = match read_u8(&mut reader) {
+                Ok(opcode) => opcode,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if self.stopped.load(Ordering::Relaxed) {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            reader.get_ref().set_read_timeout(Some(CLIENT_TIMEOUT))?;
+            let request = Request::read(opcode, &mut reader)?;
+            let response = match self.handle(request) {
+                Ok(response) => response.encode(),
+                Err(e) => Response::encode_error(&e),
+            };
+            writer.write_all(&response)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&self, request: Request) -> Result<Response, Error> {
+        match request {
+            Request::Get { path } => self.db.get(&path).map(Response::Value),
+            Request::Set { path, data } => self.db.set(&path, data).map(|_| Response::Done),
+            Request::Remove { path } => self.db.set(&path, Vec::new()).map(|_| Response::Done),
+            Request::Paths { path, all } => if all {
+                self.db.all_paths(path.as_deref())
+            } else {
+                self.db.paths(path.as_deref())
+            }
+            .map(Response::Paths),
+            Request::DeleteSubstructure { path } => {
+                self.db.delete_substructure(&path).map(|_| Response::Done)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufReader, ErrorKind, Read, Write},
+        net::{SocketAddr, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::CLIENT_TIMEOUT;
+    use crate::{
+        cleanup::Files,
+        protocol::{Request, Response, MAGIC, MAX_LEN},
+        Corruption, Error, FAlloc, Server,
+    };
+
+    fn connect(address: SocketAddr) -> BufReader<TcpStream> {
+        let mut stream = BufReader::new(TcpStream::connect(address).unwrap());
+        let mut handshake = [0_u8; 16];
+        stream.read_exact(&mut handshake).unwrap();
+        assert_eq!(&handshake[..8], MAGIC);
+        stream
+    }
+
+    fn call(stream: &mut BufReader<TcpStream>, request: Request) -> Response {
+        stream.get_mut().write_all(&request.encode()).unwrap();
+        Response::read(&request, stream).unwrap().unwrap()
+    }
+
+    fn use_server(address: SocketAddr) {
+        thread::scope(|s| {
+            for i in 0..8_u8 {
+                s.spawn(move || {
+                    let mut stream = connect(address);
+                    let path = format!("clients/{i}");
+                    let set = Request::Set {
+                        path: path.clone(),
+                        data: vec![i; 10],
+                    };
+                    assert_eq!(call(&mut stream, set), Response::Done);
+                    let get = Request::Get { path };
+                    assert_eq!(call(&mut stream, get), Response::Value(Some(vec![i; 10])));
+                });
+            }
+        });
+
+        let mut stream = connect(address);
+        let paths = Request::Paths {
+            path: Some("clients".to_owned()),
+            all: false,
+        };
+        let Response::Paths(paths) = call(&mut stream, paths) else {
+            panic!("expected paths");
+        };
+        assert_eq!(paths.len(), 8);
+        let remove = Request::Remove {
+            path: "clients/0".to_owned(),
+        };
+        assert_eq!(call(&mut stream, remove), Response::Done);
+        let get = Request::Get {
+            path: "clients/0".to_owned(),
+        };
+        assert_eq!(call(&mut stream, get), Response::Value(None));
+        let delete = Request::DeleteSubstructure {
+            path: "clients".to_owned(),
+        };
+        assert_eq!(call(&mut stream, delete), Response::Done);
+        let paths = Request::Paths {
+            path: None,
+            all: true,
+        };
+        assert_eq!(call(&mut stream, paths), Response::Paths(Vec::new()));
+
+        // a path that is too long closes the connection instead of being read
+        let mut stream = connect(address);
+        let mut get = vec![0];
+        get.extend_from_slice(&(MAX_LEN + 1).to_be_bytes());
+        stream.get_mut().write_all(&get).unwrap();
+        assert_eq!(stream.read(&mut [0]).unwrap(), 0);
+    }
+
+    #[test]
+    fn errors() {
+        let request = Request::Get {
+            path: "a".to_owned(),
+        };
+        let checksum = Corruption::Checksum {
+            path: "a".to_owned(),
+            expected: 1,
+            found: 2,
+        };
+        let encoded = Response::encode_error(&Error::Corrupt(checksum.clone()));
+        assert!(matches!(
+            Response::read(&request, &mut &encoded[..]).unwrap(),
+            Err(Error::Corrupt(c)) if c == checksum
+        ));
+        let encoded = Response::encode_error(&Error::Io(ErrorKind::StorageFull.into()));
+        assert!(matches!(
+            Response::read(&request, &mut &encoded[..]).unwrap(),
+            Err(Error::Io(e)) if e.kind() == ErrorKind::StorageFull
+        ));
+        let encoded = Response::encode_error(&Corruption::Table("broken").into());
+        assert!(matches!(
+            Response::read(&request, &mut &encoded[..]).unwrap(),
+            Err(Error::Remote(message)) if message.ends_with("broken")
+        ));
+    }
+
+    #[test]
+    fn serve() {
+        let _files = Files::new("server.test", &["dmdb", "mmdb", "mmdb.wal"]);
+        let db = FAlloc::create("server.test.dmdb", "server.test.mmdb", 100, 16).unwrap();
+        let server = Server::bind(db, "127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| server.run().unwrap());
+            // the server has to be stopped even if this fails
+            let result = s.spawn(|| use_server(address)).join();
+            server.stop();
+            result.unwrap();
+        });
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn stalled_client() {
+        let _files = Files::new("server.stall", &["dmdb", "mmdb", "mmdb.wal"]);
+        let db = FAlloc::create("server.stall.dmdb", "server.stall.mmdb", 100, 16).unwrap();
+        let server = Server::bind(db, "127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::scope(|s| {
+            let running = s.spawn(|| server.run());
+            // a client that starts a request and never finishes it
+            let mut stream = connect(address);
+            stream.get_mut().write_all(&[0]).unwrap();
+            thread::sleep(Duration::from_millis(300));
+            let start = Instant::now();
+            server.stop();
+            running.join().unwrap().unwrap();
+            assert!(start.elapsed() < CLIENT_TIMEOUT + Duration::from_secs(1));
+            drop(stream);
+        });
+        server.shutdown().unwrap();
+    }
+}
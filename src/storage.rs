@@ -1,16 +1,38 @@
 use std::{
-    collections::BTreeMap,
-    fs::{self, File},
-    hint::black_box,
-    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
-    mem, process,
-    sync::{Arc, Mutex},
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File, TryLockError},
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::Bound,
+    sync::{Arc, Mutex, MutexGuard, Weak},
     thread,
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use deborrow::deborrow;
 
+#[cfg(feature = "mmap")]
+use crate::mmap::Mapping;
+
+#[cfg(feature = "encryption")]
+use crate::Key;
+
+use crate::{
+    backend::{FileBackend, MemoryBackend, StorageBackend},
+    compression,
+    encryption::Keys,
+    format::{self, Header, Slot, DATA_HEADER_LEN, FORMAT_VERSION},
+    free::FreeList,
+    journal::Batch,
+    logging::{self, event, span},
+    mirror::Backlog,
+    recovery::Callback,
+    transaction::Overlay,
+    wal::{Record, Wal},
+    Corruption, Error, FormatError, Problem, RecoveryPolicy, Report,
+};
+
 macro_rules! serialize_u64 {
     ($f:ident, $thing:expr) => {
         $f.write_all(&u64::to_be_bytes($thing as u64))
@@ -26,28 +48,98 @@ macro_rules! deserialize_u64 {
     };
 }
 
+/// The path below which the database keeps values of its own. They can be read like any other,
+/// but are left out of path listings.
+pub const RESERVED: &str = "microdb-internal";
+
+/// How many bytes [`FAlloc::compact`] moves before saving and letting other threads continue.
+const COMPACTION_BATCH: usize = 64 * 1024 * 1024;
+/// The journal of a table is never consolidated while it is smaller than this.
+const JOURNAL_MIN: usize = 1024 * 1024;
+/// How many bytes [`FAlloc::backup_to`] copies before saving the backup.
+const BACKUP_BATCH: usize = 8 * 1024 * 1024;
+/// How many bytes the background thread re-encrypts at a time after the key was rotated.
+const REKEY_BATCH: usize = 8 * 1024 * 1024;
+/// The block size of databases created with [`FAlloc::in_memory`].
+const MEMORY_BLOCK_SIZE: usize = 64;
+
 #[derive(Debug)]
-struct Allocation {
-    full_size: usize,
-    locations: Vec<(usize, usize)>, // start, length
+pub(crate) struct Allocation {
+    pub(crate) full_size: usize,
+    pub(crate) locations: Vec<(usize, usize)>, // start, length
+    pub(crate) checksum: Option<u32>, // CRC32 of the data, None for tables written before checksums
+    pub(crate) codec: u8,             // how the data is compressed, see crate::compression
+}
+
+/// Allocations whose path is not valid UTF-8, with the raw path.
+pub(crate) type InvalidAllocations = Vec<(Vec<u8>, Allocation)>;
+
+/// Where an [`AllocationTable`] is saved.
+#[derive(Debug, Clone)]
+pub(crate) enum Placement {
+    /// In its own file, which is replaced on every save.
+    File(String),
+    /// Inside of a single-file database. Every save writes a new copy into free space and then
+    /// points the older of the two header slots at it.
+    Embedded {
+        slot: usize,
+        generation: u64,
+        extent: Option<(usize, usize)>,
+    },
+    /// Nowhere, as the database only exists in memory.
+    Memory,
 }
 
 #[derive(Debug)]
-struct AllocationTable {
-    filename: String,
-    block_size: usize,
+pub(crate) struct AllocationTable {
+    pub(crate) placement: Placement,
+    pub(crate) header: Header,
+    pub(crate) block_size: usize,
+    pub(crate) blocks_reserved: usize,
+    pub(crate) free: FreeList,
+    pub(crate) map: BTreeMap<String, Allocation>,
+    /// Paths whose allocation changed since the table was last saved.
+    dirty: BTreeSet<String>,
+    /// What values and the table are encrypted with.
+    pub(crate) keys: Keys,
+    saved: SaveState,
+}
+
+/// What is on disk since the table was last saved.
+#[derive(Debug, Default)]
+struct SaveState {
     blocks_reserved: usize,
-    free: Vec<(usize, usize)>,
-    map: BTreeMap<String, Allocation>,
+    /// Length of the full table and of the journal after it, if the table has its own file.
+    table_len: usize,
+    journal_len: usize,
+    /// The next save must rewrite the whole table.
+    consolidate: bool,
 }
 
 #[derive(Debug)]
 struct InnerFAlloc {
     cache_period: u128,
-    data: File,
+    data: Box<dyn StorageBackend>,
+    /// The data file, if there is one. Its lock is released when the database shuts down.
+    file: Option<File>,
     alloc: AllocationTable,
+    /// None if mutations aren't logged, because the database is read-only, only in memory, or
+    /// its storage backend was given no log.
+    wal: Option<Wal>,
+    read_only: bool,
+    /// None if there is no locked data file that could be mapped safely.
+    #[cfg(feature = "mmap")]
+    mapping: Option<Mapping>,
     cache: BTreeMap<String, (u128, bool, Vec<u8>)>,
+    /// Snapshots that still exist get old values before they change.
+    snapshots: Vec<Weak<Mutex<Overlay>>>,
+    /// Where mutations are sent to followers from, if this is a primary.
+    mirror: Option<Arc<Backlog>>,
     last_cache_check: u128,
+    /// The path values were re-encrypted up to since the key was last rotated.
+    rekeyed: Option<String>,
+    recovery: RecoveryPolicy,
+    on_error: Option<Callback>,
     shutdown: bool,
 }
 
@@ -62,127 +154,435 @@ pub struct FAlloc {
 }
 
 impl Allocation {
-    fn get_data(&self, file: &mut File) -> Result<Vec<u8>, io::Error> {
+    /// Writes the table entry of the allocation, without its path.
+    pub(crate) fn serialize(&self, buf: &mut Vec<u8>) -> Result<(), io::Error> {
+        serialize_u64!(buf, self.full_size)?;
+        buf.write_all(&[self.checksum.is_some() as u8])?;
+        buf.write_all(&self.checksum.unwrap_or(0).to_be_bytes())?;
+        buf.write_all(&[self.codec])?;
+        serialize_u64!(buf, self.locations.len())?;
+        for location in &self.locations {
+            serialize_u64!(buf, location.0)?;
+            serialize_u64!(buf, location.1)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a table entry written by a table of the given version.
+    pub(crate) fn deserialize(f: &mut impl Read, version: u64) -> Result<Self, io::Error> {
+        let mut buf64 = [0_u8; 8];
+        let mut buf32 = [0_u8; 4];
+        let mut buf8 = [0_u8; 1];
+        let full_size = deserialize_u64!(f, buf64);
+        let mut checksum = None;
+        if version >= 1 {
+            f.read_exact(&mut buf8)?;
+            f.read_exact(&mut buf32)?;
+            if buf8[0] != 0 {
+                checksum = Some(u32::from_be_bytes(buf32));
+            }
+        }
+        let mut codec = compression::NONE;
+        if version >= 3 {
+            f.read_exact(&mut buf8)?;
+            codec = buf8[0];
+        }
+        let locs_len = deserialize_u64!(f, buf64);
+        let mut locations = Vec::new();
+        for _ in 0..locs_len {
+            locations.push((deserialize_u64!(f, buf64), deserialize_u64!(f, buf64)));
+        }
+        Ok(Self {
+            full_size,
+            locations,
+            checksum,
+            codec,
+        })
+    }
+
+    /// Reads the value, decrypting and decompressing it if needed.
+    pub(crate) fn get_data(
+        &self,
+        path: &str,
+        data: &mut dyn StorageBackend,
+        keys: &Keys,
+    ) -> Result<Vec<u8>, Error> {
+        self.decode(path, self.get_stored(path, data)?, keys)
+    }
+
+    /// Reads the bytes the value is stored as, without decrypting or decompressing them.
+    pub(crate) fn get_stored(
+        &self,
+        path: &str,
+        data: &mut dyn StorageBackend,
+    ) -> Result<Vec<u8>, Error> {
+        self.verify(path, self.read_stored(data)?)
+    }
+
+    /// Reads the bytes the value is stored as, without checking them against the checksum.
+    fn read_stored(&self, data: &mut dyn StorageBackend) -> Result<Vec<u8>, io::Error> {
         let mut bytes = vec![0_u8; self.full_size];
         let mut i = 0;
         for location in &self.locations {
-            file.seek(SeekFrom::Start(location.0 as u64))?;
-            file.read_exact(&mut bytes[i..(i + location.1).min(self.full_size)])?;
-            i += location.1;
+            let end = (i + location.1).min(self.full_size);
+            if i >= end {
+                break;
+            }
+            data.read_at(DATA_HEADER_LEN + location.0 as u64, &mut bytes[i..end])?;
+            i = end;
+        }
+        Ok(bytes)
+    }
+
+    /// Checks data that was read against the checksum.
+    pub(crate) fn verify(&self, path: &str, bytes: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if let Some(expected) = self.checksum {
+            let found = crc32fast::hash(&bytes);
+            if found != expected {
+                return Err(Corruption::Checksum {
+                    path: path.to_owned(),
+                    expected,
+                    found,
+                }
+                .into());
+            }
         }
         Ok(bytes)
     }
-    fn set_data(&self, file: &mut File, mut data: Vec<u8>) -> Result<(), io::Error> {
+
+    /// Decrypts and decompresses stored bytes that were verified already.
+    pub(crate) fn decode(
+        &self,
+        path: &str,
+        stored: Vec<u8>,
+        keys: &Keys,
+    ) -> Result<Vec<u8>, Error> {
+        let Some(stored) = keys.open(path.as_bytes(), stored) else {
+            return Err(Corruption::Authentication {
+                path: path.to_owned(),
+            }
+            .into());
+        };
+        compression::decompress(self.codec, stored, path)
+    }
+
+    /// Writes the bytes the value is stored as. The caller sets [`Self::codec`] to match them.
+    pub(crate) fn set_data(
+        &mut self,
+        file: &mut dyn StorageBackend,
+        mut data: Vec<u8>,
+    ) -> Result<(), Error> {
         data.resize(self.full_size, 0);
+        self.checksum = Some(crc32fast::hash(&data));
         let mut i = 0;
         for location in &self.locations {
-            file.seek(SeekFrom::Start(location.0 as u64))?;
-            file.write_all(&data[i..(i + location.1).min(self.full_size)])?;
-            i += location.1;
+            let end = (i + location.1).min(self.full_size);
+            if i >= end {
+                break;
+            }
+            file.write_at(DATA_HEADER_LEN + location.0 as u64, &data[i..end])?;
+            i = end;
         }
         Ok(())
     }
 }
 
 impl AllocationTable {
-    fn new(file: String) -> Result<Self, io::Error> {
+    /// Fails if any path in a loaded table is not valid UTF-8.
+    fn valid((table, invalid): (Self, InvalidAllocations)) -> Result<Self, Error> {
+        if !invalid.is_empty() {
+            return Err(Corruption::InvalidPath.into());
+        }
+        Ok(table)
+    }
+
+    /// Loads a table. Allocations whose path is not valid UTF-8 are returned separately instead
+    /// of failing. Encrypted tables need their current key in `keys`.
+    pub(crate) fn load(file: String, keys: Keys) -> Result<(Self, InvalidAllocations), Error> {
+        let mut f = File::open(&file).map_err(|e| Error::opening(e, &file))?;
+        Self::read(&mut f, Placement::File(file), keys)
+    }
+
+    /// Loads the current table of a single-file database. Encrypted tables need their current
+    /// key in `keys`.
+    pub(crate) fn load_embedded(
+        data: &mut dyn StorageBackend,
+        keys: Keys,
+    ) -> Result<(Self, InvalidAllocations), Error> {
+        let header = Header::read_single(data)?.ok_or(FormatError::NotADatabase)?;
+        if header.version > FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion(header.version).into());
+        }
+        let mut slots: Vec<_> = Slot::read_all(data)?
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, slot)| Some((slot?, i)))
+            .collect();
+        slots.sort_by_key(|x| Reverse(x.0.generation));
+        for (i, &(slot, index)) in slots.iter().enumerate() {
+            // the newest copy may not have been written completely
+            let mut bytes = vec![0_u8; slot.len];
+            let offset = DATA_HEADER_LEN + slot.extent.0 as u64;
+            if data.read_at(offset, &mut bytes).is_err() || crc32fast::hash(&bytes) != slot.crc {
+                continue;
+            }
+            let placement = Placement::Embedded {
+                slot: index,
+                generation: slot.generation,
+                extent: Some(slot.extent),
+            };
+            let (mut table, invalid) =
+                Self::read(&mut Cursor::new(bytes), placement, keys.clone())?;
+            Header::check_pair(Some(header), table.header)?;
+            match slots.get(1) {
+                // the copy the newest one replaced is only freed after it was written
+                Some((older, _)) if i == 0 => table.dealloc(older.extent),
+                // a save was interrupted, so space it took may be neither used nor free
+                _ => table.rebuild_free(),
+            }
+            return Ok((table, invalid));
+        }
+        Err(Corruption::Table("The data file contains no intact allocation table.").into())
+    }
+
+    fn read(
+        file: &mut (impl Read + Seek),
+        placement: Placement,
+        mut keys: Keys,
+    ) -> Result<(Self, InvalidAllocations), Error> {
         let mut buf64 = [0_u8; 8];
-        let mut f = File::open(&file)?;
+        let (header, encrypted) = Header::read_meta(file)?.unwrap_or((
+            Header {
+                version: 0,
+                uuid: 0,
+            },
+            false,
+        ));
+        if header.version > FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion(header.version).into());
+        }
+        if keys.is_encrypted() && !encrypted {
+            return Err(FormatError::NotEncrypted.into());
+        }
+        // the body of an encrypted table is decrypted as a whole before it is parsed
+        let mut body = match encrypted {
+            true => Some(Cursor::new(keys.read_table(file)?)),
+            false => None,
+        };
+        // lengths are checked against the size of the table, so that garbage (like a file that
+        // isn't a table at all, which is read as version 0) can't allocate more than that
+        let len = match &mut body {
+            Some(body) => body.get_ref().len() as u64 - body.position(),
+            None => {
+                let position = file.stream_position()?;
+                let len = file.seek(SeekFrom::End(0))? - position;
+                file.seek(SeekFrom::Start(position))?;
+                len
+            }
+        } as usize;
+        let too_long = || -> Error {
+            match header.version {
+                0 => FormatError::NotADatabase.into(),
+                _ => Corruption::Table("A length in the allocation table exceeds the file.").into(),
+            }
+        };
+        let mut f: &mut dyn Read = match &mut body {
+            Some(body) => body,
+            None => &mut *file,
+        };
         let block_size = deserialize_u64!(f, buf64);
         let blocks_reserved = deserialize_u64!(f, buf64);
         let free_len = deserialize_u64!(f, buf64);
         let map_len = deserialize_u64!(f, buf64);
-        let mut free = Vec::new();
+        // free space takes up 16 bytes, and an allocation at least 24
+        if free_len > len / 16 || map_len > len / 24 {
+            return Err(too_long());
+        }
+        let mut free = FreeList::new();
         for _ in 0..free_len {
-            free.push((deserialize_u64!(f, buf64), deserialize_u64!(f, buf64)));
+            free.insert(deserialize_u64!(f, buf64), deserialize_u64!(f, buf64));
         }
         let mut map = BTreeMap::new();
+        let mut invalid = Vec::new();
         for _ in 0..map_len {
             let str_len = deserialize_u64!(f, buf64);
+            if str_len > len {
+                return Err(too_long());
+            }
             let mut buf = vec![0_u8; str_len];
             f.read_exact(&mut buf)?;
-            let str = String::from_utf8(buf).expect("bitflip on drive??");
-            let full_size = deserialize_u64!(f, buf64);
-            let locs_len = deserialize_u64!(f, buf64);
-            let mut locations = Vec::new();
-            for _ in 0..locs_len {
-                locations.push((deserialize_u64!(f, buf64), deserialize_u64!(f, buf64)));
-            }
-            map.insert(
-                str,
-                Allocation {
-                    full_size,
-                    locations,
-                },
-            );
+            let allocation = Allocation::deserialize(&mut f, header.version)?;
+            match String::from_utf8(buf) {
+                Ok(str) => {
+                    map.insert(str, allocation);
+                }
+                Err(e) => invalid.push((e.into_bytes(), allocation)),
+            }
         }
-        Ok(Self {
-            filename: file,
+        let table_len = file.stream_position()? as usize;
+        let mut table = Self {
+            placement,
+            header,
             block_size,
             blocks_reserved,
             free,
             map,
-        })
+            dirty: BTreeSet::new(),
+            keys,
+            saved: SaveState {
+                blocks_reserved,
+                table_len,
+                journal_len: 0,
+                // the journal can only be appended to tables in the current format
+                consolidate: header.version < FORMAT_VERSION,
+            },
+        };
+        if header.version >= 2 {
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes)?;
+            let (batches, valid) = Batch::read_all(&bytes, header.version, &table.keys);
+            // the journal of older versions doesn't contain the free list
+            let mut stale_free = header.version < 4 && !batches.is_empty();
+            for batch in batches {
+                table.blocks_reserved = batch.blocks_reserved;
+                for change in batch.free {
+                    stale_free |= !table.free.apply(change);
+                }
+                for (path, allocation) in batch.changes {
+                    match allocation {
+                        Some(allocation) => table.map.insert(path, allocation),
+                        None => table.map.remove(&path),
+                    };
+                }
+            }
+            table.saved.blocks_reserved = table.blocks_reserved;
+            table.saved.journal_len = valid;
+            // more batches can't be appended after a torn one
+            table.saved.consolidate |= valid != bytes.len();
+            if stale_free {
+                table.rebuild_free();
+            }
+        }
+        if header.version == 0 {
+            // older versions could lose track of free space, find it again
+            table.rebuild_free();
+        }
+        table.track_free();
+        Ok((table, invalid))
+    }
+
+    /// Makes the free list keep track of its changes if they are journaled.
+    fn track_free(&mut self) {
+        if let Placement::File(_) = self.placement {
+            self.free.track_changes();
+        }
+    }
+
+    /// An empty table for a new database.
+    fn empty(placement: Placement, header: Header, block_size: usize) -> Self {
+        let mut table = Self {
+            placement,
+            header,
+            block_size,
+            blocks_reserved: 0,
+            free: FreeList::new(),
+            map: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+            keys: Keys::default(),
+            saved: SaveState {
+                consolidate: true,
+                ..Default::default()
+            },
+        };
+        table.track_free();
+        table
+    }
+
+    /// Gets an allocation to change it, marking it to be saved.
+    pub(crate) fn get_mut(&mut self, path: &str) -> Option<&mut Allocation> {
+        let allocation = self.map.get_mut(path)?;
+        self.dirty.insert(path.to_owned());
+        Some(allocation)
+    }
+
+    pub(crate) fn insert(&mut self, path: String, allocation: Allocation) {
+        self.dirty.insert(path.to_owned());
+        self.map.insert(path, allocation);
+    }
+
+    pub(crate) fn remove(&mut self, path: &str) -> Option<Allocation> {
+        self.dirty.insert(path.to_owned());
+        self.map.remove(path)
     }
 
-    fn alloc(&mut self, amount: usize, file: &mut File) -> Result<(usize, usize), io::Error> {
+    fn alloc(
+        &mut self,
+        amount: usize,
+        file: &mut dyn StorageBackend,
+    ) -> Result<(usize, usize), Error> {
         let amount = ((amount - 1) / self.block_size + 1) * self.block_size;
         // try to reclaim old space
-        if let Some((loc, &x)) = self.free.iter().enumerate().find(|x| x.1 .1 >= amount) {
-            self.free.remove(loc);
-            if (x.1 - amount) / self.block_size > 0 {
-                self.free.push((
-                    // location + amount.round_up_to(block_size)
-                    x.0 + ((amount - 1) / self.block_size + 1) * self.block_size,
-                    // size - amount.round_down_to(block_size)
-                    x.1 - amount / self.block_size * self.block_size,
-                ))
-            }
-            return Ok((x.0, amount));
-        }
-        // otherwise find new place
-        let start = self.blocks_reserved * self.block_size;
+        if let Some(start) = self.free.alloc(amount) {
+            return Ok((start, amount));
+        }
+        // otherwise grow the file, reusing free space at its end
+        let end = self.blocks_reserved * self.block_size;
+        let start = match self.free.last() {
+            Some((start, len)) if start + len == end => {
+                self.free.take(start);
+                start
+            }
+            _ => end,
+        };
+        self.grow(start + amount - end, file)?;
+        Ok((start, amount))
+    }
+
+    /// Adds `amount` bytes to the end of the file without making them free.
+    fn grow(&mut self, amount: usize, file: &mut dyn StorageBackend) -> Result<(), Error> {
         let amount_blocks = amount / self.block_size;
-        file.seek(SeekFrom::Start(start as u64))?;
-        file.write_all(&vec![0_u8; amount_blocks * self.block_size])?;
+        file.write_at(
+            DATA_HEADER_LEN + (self.blocks_reserved * self.block_size) as u64,
+            &vec![0_u8; amount_blocks * self.block_size],
+        )?;
         self.blocks_reserved += amount_blocks;
-        Ok((start, amount_blocks * self.block_size))
+        Ok(())
     }
 
     fn dealloc(&mut self, alloc: (usize, usize)) {
-        let amount = ((alloc.1 - 1) / self.block_size + 1) * self.block_size;
-        let index = self.free.binary_search_by(|x| (x.0 + x.1).cmp(&alloc.0));
-        match index {
-            Ok(mut index) => {
-                self.free[index].1 += alloc.1;
-                // unify previous ones
-                let mut i = 0;
-                while i < (index + 1).min(self.free.len() - 1) {
-                    if self.free[i].0 + self.free[i].1 == self.free[i + 1].0 {
-                        self.free[i].1 += self.free.remove(i + 1).1;
-                        index = index.saturating_sub(1);
-                    }
-                    i += 1;
-                }
+        self.free.insert(alloc.0, alloc.1);
+    }
+
+    /// Recomputes the free list from the allocations, so that every byte that is not allocated
+    /// is free.
+    pub(crate) fn rebuild_free(&mut self) {
+        let mut used: Vec<_> = self
+            .map
+            .values()
+            .flat_map(|x| x.locations.iter().copied())
+            .chain(self.extent())
+            .collect();
+        used.sort();
+        self.free.clear();
+        let mut pos = 0;
+        for (start, len) in used {
+            if start > pos {
+                self.free.insert(pos, start - pos);
             }
-            Err(index) => self.free.insert(index, (alloc.0, amount)),
+            pos = pos.max(start + len);
+        }
+        let end = self.blocks_reserved * self.block_size;
+        if end > pos {
+            self.free.insert(pos, end - pos);
         }
     }
 
-    fn set_allocation_length(
+    pub(crate) fn set_allocation_length(
         &mut self,
         allocation: &mut Allocation,
-        file: &mut File,
+        file: &mut dyn StorageBackend,
         needed: usize,
-    ) -> Result<(), io::Error> {
-        if needed == allocation.full_size {
-            return Ok(());
-        }
-        if allocation.full_size == 0 && needed > 0 {
-            allocation.locations.push(self.alloc(needed, file)?);
-            allocation.full_size = needed;
-            return Ok(());
-        }
+    ) -> Result<(), Error> {
         if needed == 0 {
             for loc in &allocation.locations {
                 self.dealloc(*loc);
@@ -191,225 +591,1426 @@ impl AllocationTable {
             allocation.locations.clear();
             return Ok(());
         }
-        let current_blocked_size = (allocation.full_size - 1) / self.block_size + 1;
-        // can we change without (de)allocation?
-        if (allocation.full_size + needed - 1) / self.block_size + 1 == current_blocked_size {
-            allocation.full_size = needed;
-            return Ok(());
-        }
+        let capacity: usize = allocation.locations.iter().map(|x| x.1).sum();
+        let needed_capacity = ((needed - 1) / self.block_size + 1) * self.block_size;
 
-        if needed > allocation.full_size {
-            let change = needed - allocation.full_size;
-            let place = self.alloc(change, file)?;
-            allocation.locations.push(place);
-            allocation.full_size = needed;
+        if needed_capacity > capacity {
+            let change = needed_capacity - capacity;
+            let end = self.blocks_reserved * self.block_size;
+            match allocation.locations.last_mut() {
+                // grow in place if the space after the last location is free
+                Some(last) if self.free.alloc_at(last.0 + last.1, change) => last.1 += change,
+                Some(last) if last.0 + last.1 == end => {
+                    self.grow(change, file)?;
+                    last.1 += change;
+                }
+                _ => allocation.locations.push(self.alloc(change, file)?),
+            }
         } else {
-            while needed < allocation.full_size {
-                let change = allocation.full_size - needed;
-                if allocation.locations.last().unwrap().1 <= change {
+            // give back the blocks that aren't needed anymore, starting at the end
+            let mut change = capacity - needed_capacity;
+            while change > 0 {
+                let last = allocation.locations.last_mut().unwrap();
+                if last.1 <= change {
                     // the entire thing can be removed
+                    change -= last.1;
                     let loc = allocation.locations.pop().unwrap();
-                    allocation.full_size -= loc.1;
                     self.dealloc(loc);
                 } else {
-                    // start .. end - change is the range where data is still needed,
-                    // so it follows end - change .. end is the range where it isnt.
-                    let last = allocation.locations.last_mut().unwrap();
-                    let end = last.0 + last.1;
-                    let begin_dealloc = end - change;
-                    let begin_dealloc =
-                        ((begin_dealloc - 1) / self.block_size + 1) * self.block_size;
-                    last.1 = begin_dealloc;
-                    allocation.full_size = needed;
-                    self.dealloc((begin_dealloc, end - begin_dealloc));
+                    last.1 -= change;
+                    let freed = (last.0 + last.1, change);
+                    self.dealloc(freed);
+                    change = 0;
                 }
             }
         }
+        allocation.full_size = needed;
         Ok(())
     }
 
-    fn save(&mut self) -> Result<(), io::Error> {
-        let mut file = File::create(self.filename.to_owned() + ".tmp")?;
-        serialize_u64!(file, self.block_size)?;
-        serialize_u64!(file, self.blocks_reserved)?;
-        serialize_u64!(file, self.free.len())?;
-        serialize_u64!(file, self.map.len())?;
-        for item in &self.free {
-            serialize_u64!(file, item.0)?;
-            serialize_u64!(file, item.1)?;
+    /// Compresses, encrypts and writes a value, resizing its allocation to fit it. An empty
+    /// value frees the allocation.
+    pub(crate) fn store(
+        &mut self,
+        allocation: &mut Allocation,
+        path: &str,
+        file: &mut dyn StorageBackend,
+        value: Vec<u8>,
+    ) -> Result<(), Error> {
+        if value.is_empty() {
+            return self.set_allocation_length(allocation, file, 0);
+        }
+        let (codec, stored) = compression::compress(value);
+        let stored = self.keys.seal(path.as_bytes(), stored);
+        self.set_allocation_length(allocation, file, stored.len())?;
+        allocation.codec = codec;
+        allocation.set_data(file, stored)
+    }
+
+    /// Gives up the free space at the end of the data file, so the file can be shrunk after
+    /// the table is saved.
+    fn release_tail(&mut self) {
+        let end = self.blocks_reserved * self.block_size;
+        if let Some((start, len)) = self.free.last() {
+            if start + len == end {
+                self.free.take(start);
+                let kept = start.div_ceil(self.block_size) * self.block_size;
+                self.free.insert(start, kept - start);
+                self.blocks_reserved = kept / self.block_size;
+            }
+        }
+    }
+
+    /// The space taken by the table itself inside of a single-file database.
+    pub(crate) fn extent(&self) -> Option<(usize, usize)> {
+        match self.placement {
+            Placement::File(_) | Placement::Memory => None,
+            Placement::Embedded { extent, .. } => extent,
+        }
+    }
+
+    fn serialize(&mut self) -> Result<Vec<u8>, Error> {
+        let mut table = Vec::new();
+        self.header.version = FORMAT_VERSION;
+        self.header
+            .write_meta(&mut table, self.keys.is_encrypted())?;
+        let mut buf = Vec::new();
+        serialize_u64!(buf, self.block_size)?;
+        serialize_u64!(buf, self.blocks_reserved)?;
+        serialize_u64!(buf, self.free.len())?;
+        serialize_u64!(buf, self.map.len())?;
+        for item in self.free.iter() {
+            serialize_u64!(buf, item.0)?;
+            serialize_u64!(buf, item.1)?;
         }
         for item in &self.map {
-            serialize_u64!(file, item.0.as_bytes().len())?;
-            file.write_all(item.0.as_bytes())?;
-            serialize_u64!(file, item.1.full_size)?;
-            serialize_u64!(file, item.1.locations.len())?;
-            for location in &item.1.locations {
-                serialize_u64!(file, location.0)?;
-                serialize_u64!(file, location.1)?;
+            serialize_u64!(buf, item.0.len())?;
+            buf.write_all(item.0.as_bytes())?;
+            item.1.serialize(&mut buf)?;
+        }
+        self.keys.write_table(&mut table, buf);
+        Ok(table)
+    }
+
+    /// Saves the table if it changed. `data` is only written to by single-file databases.
+    pub(crate) fn save(&mut self, data: &mut dyn StorageBackend) -> Result<(), Error> {
+        span!("save_table", changed = self.dirty.len());
+        self.release_tail();
+        if self.dirty.is_empty()
+            && self.free.changes().is_empty()
+            && self.blocks_reserved == self.saved.blocks_reserved
+            && !self.saved.consolidate
+        {
+            return Ok(());
+        }
+        let start = Instant::now();
+        match self.placement.clone() {
+            Placement::File(filename) => {
+                if self.saved.consolidate
+                    || self.saved.journal_len > self.saved.table_len.max(JOURNAL_MIN)
+                {
+                    let bytes = self.serialize()?;
+                    let mut file = File::create(filename.to_owned() + ".tmp")?;
+                    file.write_all(&bytes)?;
+                    file.sync_all()?;
+                    fs::rename(filename.to_owned() + ".tmp", &filename)?;
+                    event!(debug, "rewrote the allocation table", bytes = bytes.len());
+                    self.saved.table_len = bytes.len();
+                    self.saved.journal_len = 0;
+                    self.saved.consolidate = false;
+                } else {
+                    let frame = Batch::frame(
+                        self.blocks_reserved,
+                        self.free.changes(),
+                        self.dirty.iter().map(|path| (path, self.map.get(path))),
+                        &self.keys,
+                    )?;
+                    let mut file = File::options().append(true).open(&filename)?;
+                    file.write_all(&frame)?;
+                    file.sync_data()?;
+                    event!(
+                        debug,
+                        "appended to the allocation table's journal",
+                        bytes = frame.len(),
+                        changed = self.dirty.len(),
+                    );
+                    self.saved.journal_len += frame.len();
+                }
+            }
+            Placement::Embedded {
+                slot,
+                generation,
+                extent,
+            } => {
+                // taking the space can only make the table shorter, so it will fit
+                let estimate = self.serialize()?.len();
+                let new = self.alloc(estimate, data)?;
+                let bytes = self.serialize()?;
+                data.write_at(DATA_HEADER_LEN + new.0 as u64, &bytes)?;
+                data.sync()?;
+                Slot {
+                    generation: generation + 1,
+                    extent: new,
+                    len: bytes.len(),
+                    crc: crc32fast::hash(&bytes),
+                }
+                .write(data, 1 - slot)?;
+                data.sync()?;
+                // the old copy is still in the other slot's table, but that one is outdated now
+                if let Some(extent) = extent {
+                    self.dealloc(extent);
+                }
+                event!(
+                    debug,
+                    "wrote the allocation table",
+                    bytes = bytes.len(),
+                    generation = generation + 1,
+                );
+                self.placement = Placement::Embedded {
+                    slot: 1 - slot,
+                    generation: generation + 1,
+                    extent: Some(new),
+                };
+                self.saved.consolidate = false;
+            }
+            Placement::Memory => (),
+        }
+        logging::slow("saving the allocation table", start);
+        self.dirty.clear();
+        self.free.forget_changes();
+        self.saved.blocks_reserved = self.blocks_reserved;
+        Ok(())
+    }
+}
+
+/// The files [`FAlloc::backup`] writes a copy to.
+#[derive(Debug)]
+pub(crate) enum Backup {
+    /// A data file and an allocation table, like [`FAlloc::create`] creates.
+    Pair { data: String, alloc: String },
+    /// A single-file database, like [`FAlloc::create_single_file`] creates.
+    Single(String),
+}
+
+/// How the files of a database are opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    /// For reading and writing, with an exclusive lock.
+    Exclusive,
+    /// Only for reading, with a shared lock.
+    Shared,
+    /// Only for reading, without a lock, even if another handle owns the database.
+    ReadOnly,
+}
+
+/// Locks and opens a data file, then loads the allocation table belonging to it, upgrading both
+/// if they are from version 0. The table is only read once the lock is held, so that it can't
+/// change before the database is owned.
+pub(crate) fn open_pair(
+    data: &str,
+    alloc: String,
+    keys: Keys,
+    access: Access,
+) -> Result<(AllocationTable, InvalidAllocations, FileBackend), Error> {
+    let mut file = open_data(data, access)?;
+    let (mut table, invalid) = AllocationTable::load(alloc, keys)?;
+    if table.header.version == 0 {
+        if access != Access::Exclusive {
+            return Err(Error::ReadOnly);
+        }
+        if !invalid.is_empty() {
+            // saving the upgraded table would lose these
+            return Err(Corruption::InvalidPath.into());
+        }
+        // a data file that already has a header only belongs to the table if it was upgraded
+        // for it
+        if let Some(header) = Header::read_data(&mut file)? {
+            if fs::metadata(format::upgrade_copy(data)).is_err() {
+                return Err(FormatError::Mismatch {
+                    data: header.uuid,
+                    meta: 0,
+                }
+                .into());
             }
         }
-        fs::rename(self.filename.to_owned() + ".tmp", &self.filename)
+        // from before files had headers, upgrade them
+        table.header.uuid = format::upgrade_data(&mut file, data)
+            .map_err(|e| Error::opening(e, data))?
+            .uuid;
+        table.save(&mut file)?;
+        format::finish_upgrade(data).map_err(|e| Error::opening(e, data))?;
+    }
+    Header::check_pair(Header::read_data(&mut file)?, table.header)?;
+    Ok((table, invalid, file))
+}
+
+/// Opens an existing data file and locks it.
+pub(crate) fn open_data(data: &str, access: Access) -> Result<FileBackend, Error> {
+    let file = File::options()
+        .read(true)
+        .write(access == Access::Exclusive)
+        .open(data)
+        .map_err(|e| Error::opening(e, data))?;
+    lock_data(&file, data, access)?;
+    Ok(FileBackend::new(file))
+}
+
+/// Takes an advisory lock on a data file, which keeps other handles from opening the database
+/// at the same time. It is released when the database shuts down or the process ends.
+fn lock_data(file: &File, data: &str, access: Access) -> Result<(), Error> {
+    let result = match access {
+        Access::Exclusive => file.try_lock(),
+        Access::Shared => file.try_lock_shared(),
+        Access::ReadOnly => return Ok(()),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(Error::InUse {
+            path: data.to_owned(),
+        }),
+        Err(TryLockError::Error(e)) => Err(e.into()),
     }
 }
 
 impl InnerFAlloc {
-    fn flush_cache(&mut self, force: bool) -> Result<u128, io::Error> {
+    /// Whether there is a thread that saves the database regularly. Databases that are
+    /// read-only or only in memory have nothing to save. A recovery policy that gives up on
+    /// saving makes the database read-only.
+    fn background(&self) -> bool {
+        !self.read_only && !matches!(self.alloc.placement, Placement::Memory)
+    }
+
+    fn flush_cache(&mut self, force: bool) -> Result<u128, Error> {
+        span!("flush_cache", force = force);
         let time = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_millis();
+        if self.read_only {
+            // values replayed from the log of a read-only database can't be written anywhere
+            self.cache
+                .retain(|_, x| x.1 || time - x.0 < self.cache_period);
+            return Ok(time);
+        }
         if time - self.last_cache_check >= 100 || force || self.cache_period == 0 {
             self.last_cache_check = time;
+            // the log must be durable before any data is overwritten in place
+            if let Some(wal) = &mut self.wal {
+                let start = Instant::now();
+                wal.sync()?;
+                logging::slow("syncing the log", start);
+            }
+            let start = Instant::now();
+            let (mut values, mut bytes) = (0, 0);
             for item in self.cache.iter_mut() {
                 if item.1 .1 && time - item.1 .0 >= self.cache_period {
-                    let allocation = unsafe { deborrow(self.alloc.map.get_mut(item.0).unwrap()) };
-                    self.alloc.set_allocation_length(
-                        allocation,
-                        &mut self.data,
-                        item.1 .2.len(),
-                    )?;
-                    allocation.set_data(&mut self.data, item.1 .2.clone())?;
+                    values += 1;
+                    bytes += item.1 .2.len();
+                    let allocation = unsafe { deborrow(self.alloc.get_mut(item.0).unwrap()) };
+                    self.alloc
+                        .store(allocation, item.0, &mut *self.data, item.1 .2.clone())?;
                     item.1 .1 = false;
                     if allocation.full_size == 0 {
-                        self.alloc.map.remove(item.0);
+                        self.alloc.remove(item.0);
                         item.1 .0 = 0;
                         continue;
                     }
                 }
             }
+            if values > 0 {
+                event!(debug, "flushed the cache", values = values, bytes = bytes);
+                logging::slow("flushing the cache", start);
+            }
             self.cache.retain(|_, x| time - x.0 < self.cache_period);
         }
         Ok(time)
     }
-}
 
-impl FAlloc {
-    fn internal_new(
-        data: File,
-        alloc: AllocationTable,
-        cache_period: u128,
-    ) -> Result<Self, io::Error> {
-        let inner = Arc::new(Mutex::new(InnerFAlloc {
-            cache_period,
-            data,
-            alloc,
-            cache: BTreeMap::new(),
-            last_cache_check: 0,
-            shutdown: false,
-        }));
-        let inner_clone = inner.clone();
-        thread::spawn(move || {
-            thread::sleep(Duration::from_secs(1));
-            let mut recovery = false;
-            loop {
-                if inner_clone.is_poisoned() {
-                    println!(
-                        "SEVERE: The database mutex was poisoned. Data may be corrupt. Circumventing poison and attempting to write to disk one last time before shutting down."
-                    );
-                    println!(
-                        "First, waiting 60 seconds for rest of program to crash if possible..."
-                    );
-                    thread::sleep(Duration::from_secs(60));
-                    println!("Circumventing poison and attempting recovery.");
-                    recovery = true;
+    /// Flushes, saves the allocation table, and then drops everything from the write-ahead log
+    /// that is now safely on disk.
+    fn save(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.flush_cache(true)?;
+        self.alloc.save(&mut *self.data)?;
+        // only shrink the file once the table no longer refers to the space
+        let len = DATA_HEADER_LEN + (self.alloc.blocks_reserved * self.alloc.block_size) as u64;
+        if self.data.len()? > len {
+            #[cfg(feature = "mmap")]
+            if let Some(mapping) = &mut self.mapping {
+                mapping.unmap();
+            }
+            self.data.set_len(len)?;
+        }
+        let start = Instant::now();
+        self.data.sync()?;
+        logging::slow("syncing the data", start);
+        if let Some(wal) = &mut self.wal {
+            wal.checkpoint(self.cache.iter().filter(|x| x.1 .1).map(|x| (x.0, &x.1 .2)))?;
+        }
+        event!(trace, "saved");
+        Ok(())
+    }
+
+    /// Moves allocations that are not yet compacted to `cursor` and onwards, until roughly
+    /// [`COMPACTION_BATCH`] bytes were moved. Returns the new cursor, or None if everything is
+    /// compacted. Must be called right after [`Self::save`] and followed by another one.
+    ///
+    /// Damaged values are moved as they are, and added to `damaged` instead of failing.
+    fn compact_batch(
+        &mut self,
+        mut cursor: usize,
+        damaged: &mut Vec<Error>,
+    ) -> Result<Option<usize>, Error> {
+        let block_size = self.alloc.block_size;
+        let mut candidates: Vec<_> = self
+            .alloc
+            .map
+            .iter()
+            .filter(|x| x.1.locations.iter().any(|l| l.0 + l.1 > cursor))
+            .map(|x| (x.1.locations.iter().map(|l| l.0).min().unwrap(), x.0))
+            .collect();
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        candidates.sort();
+
+        let mut moves = Vec::new();
+        let mut moved = 0;
+        for (start, path) in candidates {
+            // everything overlapping the target area must be read before anything is written
+            if moved >= COMPACTION_BATCH && start >= cursor {
+                break;
+            }
+            let allocation = &self.alloc.map[path];
+            let size = ((allocation.full_size - 1) / block_size + 1) * block_size;
+            // the table of a single-file database stays where it is until the next save
+            if let Some((table, len)) = self.alloc.extent() {
+                if cursor < table + len && cursor + size > table {
+                    cursor = table + len;
                 }
-                let mut inner = inner_clone.lock().unwrap_or_else(|x| x.into_inner());
-                if recovery {
-                    inner.shutdown = true;
-                    if let Err(e) = inner.alloc.save() {
-                        println!("The database was unable to write *critical* data to disk. DO NOT END THE PROGRAM. Error: {e:?}. Recovery attempts happen every 10 seconds.");
-                        thread::sleep(Duration::from_secs(10));
-                        continue;
+            }
+            if matches!(allocation.locations[..], [(start, len)] if start == cursor && len >= size)
+            {
+                moves.push((path.to_owned(), cursor, size, None));
+            } else {
+                // a damaged value keeps its damage, so it is still reported when it is read
+                let stored = match allocation.get_stored(path, &mut *self.data) {
+                    Ok(stored) => (stored, true),
+                    Err(e @ Error::Corrupt(_)) => {
+                        event!(warn, "a damaged value was moved as it is", path = path);
+                        damaged.push(e);
+                        (allocation.read_stored(&mut *self.data)?, false)
+                    }
+                    Err(e) => return Err(e),
+                };
+                moves.push((path.to_owned(), cursor, size, Some(stored)));
+                moved += size;
+            }
+            cursor += size;
+        }
+
+        // the values are logged so that a crash in the middle of moving can be repaired. ones
+        // that are still dirty in the cache are already in the log with newer data. damaged ones
+        // can't be decoded, and are only moved.
+        if let Some(wal) = &mut self.wal {
+            for (path, _, _, stored) in &moves {
+                if let Some((stored, true)) = stored {
+                    if !self.cache.get(path).is_some_and(|x| x.1) {
+                        let decoded =
+                            self.alloc.map[path].decode(path, stored.to_owned(), &self.alloc.keys);
+                        match decoded {
+                            Ok(data) => wal.append(&Record::Set {
+                                path: path.to_owned(),
+                                data,
+                            })?,
+                            Err(e @ (Error::Corrupt(_) | Error::Format(_))) => {
+                                event!(warn, "a damaged value was moved as it is", path = path);
+                                damaged.push(e);
+                            }
+                            Err(e) => return Err(e),
+                        }
                     }
                 }
-                if let Err(e) = inner
-                    .flush_cache(true)
-                    .and(inner.alloc.save())
-                    .and(inner.data.sync_all())
-                {
-                    inner.shutdown = true;
-                    recovery = true;
-                    println!("The database was unable to write to disk. Depending on where this error happened, your data might be mostly fine. Error: {e:?}. Recovery will be attempted every 30 seconds.");
-                    thread::sleep(Duration::from_secs(30));
-                    continue;
+            }
+            wal.sync()?;
+        }
+        for (path, target, size, stored) in moves {
+            let allocation = self.alloc.get_mut(&path).unwrap();
+            allocation.locations = vec![(target, size)];
+            if let Some((stored, intact)) = stored {
+                let checksum = allocation.checksum;
+                allocation.set_data(&mut *self.data, stored)?;
+                if !intact {
+                    allocation.checksum = checksum;
                 }
-                if inner.shutdown {
-                    inner.shutdown = false;
-                    if recovery {
-                        println!("Recovery seems to have been successful. HALTING THE PROGRAM IN ORDER TO PREVENT FURTHER DAMAGE.");
-                        println!("Poisoning mutex just in case any threads still try to use it.");
-                        let inner_clone = inner_clone.clone();
-                        thread::spawn(move || {
-                            let thing = inner_clone.lock().unwrap();
-                            #[allow(clippy::eq_op)]
-                            if 1 == 1 {
-                                panic!("Poisoning mutex intentionally.");
-                            }
-                            mem::drop(black_box(thing));
-                        });
-                        println!("Sleeping for 2 hours, then exiting.");
-                        thread::sleep(Duration::from_secs(3600 * 2));
-                        process::exit(255);
+            }
+        }
+        self.alloc.rebuild_free();
+        Ok(Some(cursor))
+    }
+
+    /// Re-encrypts values that still use a key from before the last rotation, continuing where
+    /// the previous batch stopped, until roughly [`REKEY_BATCH`] bytes were rewritten. Once all
+    /// values use the current key, the older ones are forgotten. Must be called right after
+    /// [`Self::save`], and saves again.
+    ///
+    /// Damaged values are left as they are, and returned as errors instead of failing the save.
+    fn rekey(&mut self) -> Result<Vec<Error>, Error> {
+        if !self.alloc.keys.retiring() || self.shutdown {
+            return Ok(Vec::new());
+        }
+        let start = match self.rekeyed.take() {
+            Some(path) => Bound::Excluded(path),
+            None => Bound::Unbounded,
+        };
+        let mut values = Vec::new();
+        let mut damaged = Vec::new();
+        let mut rewritten = 0;
+        let mut done = true;
+        for (path, allocation) in self.alloc.map.range((start, Bound::Unbounded)) {
+            if rewritten >= REKEY_BATCH {
+                done = false;
+                break;
+            }
+            self.rekeyed = Some(path.to_owned());
+            // values that are dirty in the cache get the current key when they are flushed
+            if self.cache.get(path).is_some_and(|x| x.1) {
+                continue;
+            }
+            let decoded = allocation
+                .get_stored(path, &mut *self.data)
+                .and_then(|stored| {
+                    if self.alloc.keys.is_current(&stored) {
+                        return Ok(None);
                     }
-                    break;
+                    rewritten += stored.len();
+                    allocation.decode(path, stored, &self.alloc.keys).map(Some)
+                });
+            match decoded {
+                Ok(Some(data)) => values.push((path.to_owned(), data)),
+                Ok(None) => (),
+                Err(e @ (Error::Corrupt(_) | Error::Format(_))) => {
+                    event!(warn, "a damaged value was not re-encrypted", path = path);
+                    damaged.push(e);
                 }
-                let d = inner.cache_period;
-                mem::drop(inner);
-                thread::sleep(Duration::from_millis((d * 10 + 5) as u64));
+                Err(e) => return Err(e),
             }
-        });
-        Ok(Self { inner })
+        }
+
+        // like when compacting, the values are logged so that a crash while they are rewritten
+        // in place can be repaired
+        if let Some(wal) = &mut self.wal {
+            for (path, data) in &values {
+                wal.append(&Record::Set {
+                    path: path.to_owned(),
+                    data: data.to_owned(),
+                })?;
+            }
+            wal.sync()?;
+        }
+        for (path, data) in values {
+            let allocation = unsafe { deborrow(self.alloc.get_mut(&path).unwrap()) };
+            self.alloc.store(allocation, &path, &mut *self.data, data)?;
+        }
+        if done {
+            // flush the values that were skipped, then nothing uses the old keys anymore
+            for item in self.cache.iter_mut() {
+                item.1 .0 = 0;
+            }
+            self.flush_cache(true)?;
+            self.alloc.keys.retire();
+            self.alloc.saved.consolidate = true;
+            if let Some(wal) = &mut self.wal {
+                wal.set_keys(self.alloc.keys.clone());
+            }
+            self.rekeyed = None;
+            event!(info, "re-encrypted all values with the new key");
+        }
+        self.save()?;
+        Ok(damaged)
     }
 
-    /// Loads a database. Can NOT be used to create one.
-    pub fn new<S: ToString>(data: S, alloc: S, cache_period: u128) -> Result<Self, io::Error> {
-        Self::internal_new(
-            File::options()
-                .read(true)
-                .write(true)
-                .truncate(false)
-                .create(false)
-                .open(data.to_string())?,
-            AllocationTable::new(alloc.to_string())?,
-            cache_period,
-        )
+    /// Reads a value from disk without caching it.
+    fn read_uncached(&mut self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        let Some(allocation) = self.alloc.map.get(path) else {
+            return Ok(None);
+        };
+        #[cfg(feature = "mmap")]
+        if let (Some(mapping), Some(file)) = (&mut self.mapping, &self.file) {
+            return Ok(Some(mapping.read(
+                allocation,
+                path,
+                file,
+                &self.alloc.keys,
+            )?));
+        }
+        Ok(Some(allocation.get_data(
+            path,
+            &mut *self.data,
+            &self.alloc.keys,
+        )?))
     }
 
-    /// Creates a database. Can NOT be used to load one.
-    pub fn create<S: ToString>(
-        data: S,
-        alloc: S,
-        cache_period: u128,
-        block_size: usize,
-    ) -> Result<Self, io::Error> {
-        Self::internal_new(
-            File::options()
-                .read(true)
-                .write(true)
-                .create_new(true)
-                .open(data.to_string())?,
-            AllocationTable {
-                filename: alloc.to_string(),
-                block_size,
-                blocks_reserved: 0,
-                free: Vec::new(),
-                map: BTreeMap::new(),
-            },
-            cache_period,
-        )
-        .and_then(|x| {
-            x.save()?;
-            Ok(x)
-        })
+    /// Reads a value from disk and caches it.
+    fn read(&mut self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        let Some(data) = self.read_uncached(path)? else {
+            return Ok(None);
+        };
+        // cache and return it
+        let time = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_millis();
+        self.cache
+            .insert(path.to_owned(), (time, false, data.clone()));
+        Ok(Some(data))
+    }
+
+    /// Returns the current value of a path, even if it isn't on disk yet. Reads from disk are
+    /// not cached, as they are only needed for snapshots.
+    fn current(&mut self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        match self.cache.get(path) {
+            Some(x) => Ok((!x.2.is_empty()).then(|| x.2.clone())),
+            None => self.read_uncached(path),
+        }
+    }
+
+    /// Hands the values a mutation is about to change to all snapshots that don't have their own
+    /// copy yet. Must be called before the mutation is logged.
+    fn preserve(&mut self, record: &Record) -> Result<(), Error> {
+        self.snapshots.retain(|x| x.strong_count() > 0);
+        if self.snapshots.is_empty() {
+            return Ok(());
+        }
+        let paths = match record {
+            Record::Set { path, .. } => vec![path.to_owned()],
+            Record::DeleteSubstructure { path } => self.paths(Some(path), false),
+            Record::Batch(records) => {
+                for record in records {
+                    self.preserve(record)?;
+                }
+                return Ok(());
+            }
+        };
+        for path in paths {
+            let snapshots: Vec<_> = self.snapshots.iter().filter_map(Weak::upgrade).collect();
+            let mut value = None;
+            for snapshot in snapshots {
+                let mut snapshot = snapshot.lock().map_err(|_| Error::Poisoned)?;
+                if snapshot.get(&path).is_none() {
+                    if value.is_none() {
+                        value = Some(self.current(&path)?);
+                    }
+                    snapshot.preserve(&path, value.clone().unwrap());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The sub-paths of a path, or of the root. `direct` selects only direct sub-paths.
+    fn paths(&self, path: Option<&str>, direct: bool) -> Vec<String> {
+        // deleted values stay in the table until the cache is flushed
+        let keys = self
+            .alloc
+            .map
+            .keys()
+            .filter(|x| self.cache.get(*x).is_none_or(|x| !x.2.is_empty()))
+            .filter(|x| !x.strip_prefix(RESERVED).is_some_and(|x| x.starts_with('/')));
+        match path {
+            Some(path) => {
+                let prefix = path.to_owned() + "/";
+                keys.filter(|x| {
+                    x.strip_prefix(&prefix)
+                        .is_some_and(|x| !direct || !x.contains('/'))
+                })
+                .map(|x| x.to_owned())
+                .collect()
+            }
+            None => keys
+                .filter(|x| !direct || !x.contains('/'))
+                .map(|x| x.to_owned())
+                .collect(),
+        }
+    }
+
+    /// Moves all values damaged by the problems below [`crate::QUARANTINE`] as one mutation, so
+    /// that it is logged and mirrored like any other, then recomputes the free space.
+    fn quarantine(&mut self, problems: &[Problem]) -> Result<Vec<(String, String)>, Error> {
+        let salvaged = self
+            .alloc
+            .salvage_damaged(&mut *self.data, problems, &Vec::new())?;
+        if salvaged.is_empty() {
+            return Ok(Vec::new());
+        }
+        let targets = self.alloc.quarantine_targets(&salvaged);
+        // damaged values can't be read again, so snapshots keep what could be salvaged
+        self.snapshots.retain(|x| x.strong_count() > 0);
+        for snapshot in self.snapshots.iter().filter_map(Weak::upgrade) {
+            let mut snapshot = snapshot.lock().map_err(|_| Error::Poisoned)?;
+            for (path, bytes) in &salvaged {
+                if snapshot.get(path).is_none() {
+                    snapshot.preserve(path, (!bytes.is_empty()).then(|| bytes.clone()));
+                }
+            }
+        }
+
+        let damaged: Vec<_> = salvaged.iter().map(|x| x.0.clone()).collect();
+        let mut records = Vec::new();
+        let mut quarantined = Vec::new();
+        for ((path, bytes), target) in salvaged.into_iter().zip(targets) {
+            records.push(Record::Set {
+                path: path.clone(),
+                data: Vec::new(),
+            });
+            if let Some(target) = target {
+                records.push(Record::Set {
+                    path: target.clone(),
+                    data: bytes,
+                });
+                quarantined.push((path, target));
+            }
+        }
+        self.mutate(Record::Batch(records))?;
+        // the damaged space may overlap other values, so it must not be freed as it is
+        for path in damaged {
+            self.alloc.insert(
+                path,
+                Allocation {
+                    full_size: 0,
+                    locations: Vec::new(),
+                    checksum: None,
+                    codec: compression::NONE,
+                },
+            );
+        }
+        self.alloc.rebuild_free();
+        self.save()?;
+        Ok(quarantined)
+    }
+
+    /// Logs a mutation, hands it to the mirror if there is one, and applies it.
+    fn mutate(&mut self, record: Record) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.preserve(&record)?;
+        if let Some(wal) = &mut self.wal {
+            wal.append(&record)?;
+        }
+        if let Some(mirror) = &self.mirror {
+            mirror.push(&record);
+        }
+        self.apply(record);
+        Ok(())
+    }
+
+    /// Applies a mutation to the cache. It must already be in the write-ahead log.
+    fn apply(&mut self, record: Record) {
+        let time = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_millis();
+        match record {
+            Record::Set { path, data } => {
+                if !self.alloc.map.contains_key(&path) {
+                    self.alloc.insert(
+                        path.to_owned(),
+                        Allocation {
+                            full_size: 0,
+                            locations: Vec::new(),
+                            checksum: None,
+                            codec: compression::NONE,
+                        },
+                    );
+                }
+                self.cache.insert(path, (time, true, data));
+            }
+            Record::DeleteSubstructure { path } => {
+                let prefix = path + "/";
+                for key in self.alloc.map.keys().filter(|x| x.starts_with(&prefix)) {
+                    self.cache.insert(key.to_owned(), (time, true, Vec::new()));
+                }
+            }
+            Record::Batch(records) => {
+                for record in records {
+                    self.apply(record);
+                }
+            }
+        }
+    }
+}
+
+impl FAlloc {
+    fn lock(&self) -> Result<MutexGuard<'_, InnerFAlloc>, Error> {
+        self.inner.lock().map_err(|_| Error::Poisoned)
+    }
+
+    /// Uses a data file, which is read-only if there is no write-ahead log.
+    fn internal_new(
+        data: FileBackend,
+        alloc: AllocationTable,
+        wal: Option<Wal>,
+        replay: Vec<Record>,
+        cache_period: u128,
+    ) -> Result<Self, Error> {
+        let file = data.file.try_clone()?;
+        let read_only = wal.is_none();
+        Self::with_backend(
+            Box::new(data),
+            Some(file),
+            alloc,
+            wal,
+            read_only,
+            replay,
+            cache_period,
+        )
+    }
+
+    fn with_backend(
+        data: Box<dyn StorageBackend>,
+        file: Option<File>,
+        alloc: AllocationTable,
+        wal: Option<Wal>,
+        read_only: bool,
+        replay: Vec<Record>,
+        cache_period: u128,
+    ) -> Result<Self, Error> {
+        let mut inner = InnerFAlloc {
+            cache_period,
+            data,
+            #[cfg(feature = "mmap")]
+            mapping: file.as_ref().map(|_| Mapping::default()),
+            file,
+            alloc,
+            wal,
+            read_only,
+            cache: BTreeMap::new(),
+            snapshots: Vec::new(),
+            mirror: None,
+            last_cache_check: 0,
+            rekeyed: None,
+            recovery: RecoveryPolicy::default(),
+            on_error: None,
+            shutdown: false,
+        };
+        for record in replay {
+            inner.apply(record);
+        }
+        let background = inner.background();
+        let inner = Arc::new(Mutex::new(inner));
+        if !background {
+            // nothing is ever written to disk, so there is nothing to save either
+            return Ok(Self { inner });
+        }
+        let inner_clone = inner.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(1));
+            let mut failures = 0;
+            loop {
+                let mut inner = match inner_clone.lock() {
+                    Ok(inner) => inner,
+                    Err(poisoned) => {
+                        // whatever the panic left behind must not be saved. everything that was
+                        // acknowledged is in the log, so loading the database again restores it
+                        let inner = poisoned.into_inner();
+                        event!(
+                            error,
+                            "a thread panicked while using the database, it won't be saved again"
+                        );
+                        if let Some(file) = &inner.file {
+                            let _ = file.unlock();
+                        }
+                        let on_error = inner.on_error.clone();
+                        mem::drop(inner);
+                        if let Some(on_error) = on_error {
+                            (on_error.0)(&Error::Poisoned);
+                        }
+                        break;
+                    }
+                };
+                if inner.shutdown && !inner.background() {
+                    // the database was abandoned, and must not be saved
+                    break;
+                }
+                let damaged = match inner.save().and_then(|()| inner.rekey()) {
+                    Ok(damaged) => damaged,
+                    Err(e) => {
+                        failures += 1;
+                        event!(error, "saving failed", error = e, failures = failures);
+                        let delay = inner.recovery.delay(failures);
+                        match inner.recovery {
+                            RecoveryPolicy::Retry { .. } => {
+                                if let Some(delay) = delay {
+                                    event!(warn, "retrying to save", millis = delay.as_millis());
+                                }
+                            }
+                            RecoveryPolicy::ReadOnly => {
+                                event!(error, "gave up saving, the database is read-only now");
+                                inner.read_only = true;
+                            }
+                            RecoveryPolicy::Stop => {
+                                event!(error, "gave up saving, the database is shut down now");
+                                inner.read_only = true;
+                                inner.shutdown = true;
+                            }
+                        }
+                        // a database that is shut down, or was being shut down, is done with the
+                        // file
+                        if delay.is_none() && inner.shutdown {
+                            if let Some(file) = &inner.file {
+                                let _ = file.unlock();
+                            }
+                        }
+                        // the callback may use the database, so it must not be locked
+                        let on_error = inner.on_error.clone();
+                        mem::drop(inner);
+                        if let Some(on_error) = on_error {
+                            (on_error.0)(&e);
+                        }
+                        match delay {
+                            Some(delay) => {
+                                thread::sleep(delay);
+                                continue;
+                            }
+                            None => break,
+                        }
+                    }
+                };
+                if failures > 0 {
+                    event!(info, "saving works again", failures = failures);
+                    failures = 0;
+                }
+                if inner.shutdown {
+                    // everything is saved, so a single-file database is complete without its log
+                    if inner.file.is_some()
+                        && matches!(inner.alloc.placement, Placement::Embedded { .. })
+                    {
+                        if let Some(wal) = inner.wal.take_if(|wal| wal.is_empty()) {
+                            if let Err(e) = wal.remove() {
+                                event!(warn, "the log could not be removed", error = e);
+                            }
+                        }
+                    }
+                    // the handle that shut down may be replaced by a new one right away
+                    if let Some(file) = &inner.file {
+                        let _ = file.unlock();
+                    }
+                    inner.shutdown = false;
+                    event!(debug, "shut down");
+                    break;
+                }
+                let d = inner.cache_period;
+                let on_error = inner.on_error.clone();
+                mem::drop(inner);
+                // damaged values don't keep the rest from being saved, but are reported
+                if let Some(on_error) = on_error {
+                    for e in &damaged {
+                        (on_error.0)(e);
+                    }
+                }
+                thread::sleep(Duration::from_millis((d * 10 + 5) as u64));
+            }
+        });
+        Ok(Self { inner })
+    }
+
+    /// Loads a database. Can NOT be used to create one.
+    /// Mutations that were logged but not yet saved when the database was last closed (for
+    /// example because the program crashed) are replayed.
+    pub fn new<S: ToString>(data: S, alloc: S, cache_period: u128) -> Result<Self, Error> {
+        Self::load_with(
+            data.to_string(),
+            alloc.to_string(),
+            cache_period,
+            Keys::default(),
+            Access::Exclusive,
+        )
+    }
+
+    /// Loads an encrypted database with its current key, like [`Self::new`]. If not all values
+    /// were re-encrypted after the key was last rotated, the background thread continues with
+    /// that.
+    #[cfg(feature = "encryption")]
+    pub fn new_encrypted<S: ToString>(
+        data: S,
+        alloc: S,
+        cache_period: u128,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Self::load_with(
+            data.to_string(),
+            alloc.to_string(),
+            cache_period,
+            Keys::new(key),
+            Access::Exclusive,
+        )
+    }
+
+    fn load_with(
+        data: String,
+        alloc: String,
+        cache_period: u128,
+        keys: Keys,
+        access: Access,
+    ) -> Result<Self, Error> {
+        let (table, invalid, data) = open_pair(&data, alloc.to_owned(), keys, access)?;
+        let table = AllocationTable::valid((table, invalid))?;
+        Self::loaded(data, table, alloc + ".wal", cache_period, access)
+    }
+
+    fn load_single_file_with(
+        path: String,
+        cache_period: u128,
+        keys: Keys,
+        access: Access,
+    ) -> Result<Self, Error> {
+        let mut data = open_data(&path, access)?;
+        let table = AllocationTable::valid(AllocationTable::load_embedded(&mut data, keys)?)?;
+        Self::loaded(data, table, path + ".wal", cache_period, access)
+    }
+
+    /// Finishes loading a database once its table was read, with the write-ahead log at `log`.
+    /// Without exclusive access, the log is only read.
+    fn loaded(
+        data: FileBackend,
+        table: AllocationTable,
+        log: String,
+        cache_period: u128,
+        access: Access,
+    ) -> Result<Self, Error> {
+        match access {
+            Access::Exclusive => {
+                let (wal, replay) = Wal::open(log, table.keys.clone())?;
+                Self::internal_new(data, table, Some(wal), replay, cache_period)
+            }
+            Access::Shared => {
+                let replay = Wal::read(&log, &table.keys)?;
+                Self::internal_new(data, table, None, replay, cache_period)
+            }
+            Access::ReadOnly => {
+                let replay = Wal::read(&log, &table.keys)?;
+                Self::unlocked(data, table, replay)
+            }
+        }
+    }
+
+    /// Loads a database for reading only. Any number of such handles can be open at the same
+    /// time, but none while the database is opened with [`Self::new`]. Mutations that are still
+    /// in the write-ahead log are visible, but stay in the log.
+    pub fn new_shared<S: ToString>(data: S, alloc: S, cache_period: u128) -> Result<Self, Error> {
+        Self::load_with(
+            data.to_string(),
+            alloc.to_string(),
+            cache_period,
+            Keys::default(),
+            Access::Shared,
+        )
+    }
+
+    /// Loads an encrypted database for reading only, like [`Self::new_shared`].
+    #[cfg(feature = "encryption")]
+    pub fn new_shared_encrypted<S: ToString>(
+        data: S,
+        alloc: S,
+        cache_period: u128,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Self::load_with(
+            data.to_string(),
+            alloc.to_string(),
+            cache_period,
+            Keys::new(key),
+            Access::Shared,
+        )
+    }
+
+    /// Creates a database. Can NOT be used to load one.
+    pub fn create<S: ToString>(
+        data: S,
+        alloc: S,
+        cache_period: u128,
+        block_size: usize,
+    ) -> Result<Self, Error> {
+        Self::create_with(
+            data.to_string(),
+            alloc.to_string(),
+            cache_period,
+            block_size,
+            Keys::default(),
+        )
+    }
+
+    /// Creates a database that is encrypted with `key`, like [`Self::create`]. It can only be
+    /// loaded with [`Self::new_encrypted`] and the same key, or the one it was last rotated to.
+    #[cfg(feature = "encryption")]
+    pub fn create_encrypted<S: ToString>(
+        data: S,
+        alloc: S,
+        cache_period: u128,
+        block_size: usize,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Self::create_with(
+            data.to_string(),
+            alloc.to_string(),
+            cache_period,
+            block_size,
+            Keys::new(key),
+        )
+    }
+
+    fn create_with(
+        path: String,
+        alloc: String,
+        cache_period: u128,
+        block_size: usize,
+        keys: Keys,
+    ) -> Result<Self, Error> {
+        let header = Header::generate();
+        let mut data = FileBackend::new(
+            File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?,
+        );
+        lock_data(&data.file, &path, Access::Exclusive)?;
+        header.write_data(&mut data)?;
+        let wal = Wal::create(alloc.to_owned() + ".wal", keys.clone())?;
+        let mut table = AllocationTable::empty(Placement::File(alloc), header, block_size);
+        table.keys = keys;
+        Self::internal_new(data, table, Some(wal), Vec::new(), cache_period).and_then(|x| {
+            x.save()?;
+            Ok(x)
+        })
+    }
+
+    /// Loads a single-file database. Can NOT be used to create one.
+    ///
+    /// While it is loaded, its write-ahead log is kept next to it at `<path>.wal`, and the log
+    /// is only deleted once the database was shut down cleanly. Until then, the file is NOT
+    /// complete on its own: after a crash, copy or move the log together with it, or the
+    /// mutations that weren't saved yet are lost.
+    pub fn new_single_file<S: ToString>(path: S, cache_period: u128) -> Result<Self, Error> {
+        Self::load_single_file_with(
+            path.to_string(),
+            cache_period,
+            Keys::default(),
+            Access::Exclusive,
+        )
+    }
+
+    /// Loads an encrypted single-file database with its current key, like
+    /// [`Self::new_single_file`].
+    #[cfg(feature = "encryption")]
+    pub fn new_single_file_encrypted<S: ToString>(
+        path: S,
+        cache_period: u128,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Self::load_single_file_with(
+            path.to_string(),
+            cache_period,
+            Keys::new(key),
+            Access::Exclusive,
+        )
+    }
+
+    /// Opens a database for inspecting it. The files are only opened for reading and are not
+    /// locked, so this works on read-only media and while another process has the database
+    /// loaded. Nothing is cached, and there is no background thread.
+    ///
+    /// What is read is the state the files were in when this was called. If another process
+    /// keeps writing to the database, values it has since moved may fail to load with
+    /// [`Error::Corrupt`], so such tools should open the database again to see newer values.
+    pub fn open_read_only<S: ToString>(data: S, alloc: S) -> Result<Self, Error> {
+        Self::load_with(
+            data.to_string(),
+            alloc.to_string(),
+            0,
+            Keys::default(),
+            Access::ReadOnly,
+        )
+    }
+
+    /// Opens an encrypted database for inspecting it, like [`Self::open_read_only`].
+    #[cfg(feature = "encryption")]
+    pub fn open_read_only_encrypted<S: ToString>(
+        data: S,
+        alloc: S,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Self::load_with(
+            data.to_string(),
+            alloc.to_string(),
+            0,
+            Keys::new(key),
+            Access::ReadOnly,
+        )
+    }
+
+    /// Opens a single-file database for inspecting it, like [`Self::open_read_only`].
+    pub fn open_read_only_single_file<S: ToString>(path: S) -> Result<Self, Error> {
+        Self::load_single_file_with(path.to_string(), 0, Keys::default(), Access::ReadOnly)
+    }
+
+    /// Opens an encrypted single-file database for inspecting it, like
+    /// [`Self::open_read_only`].
+    #[cfg(feature = "encryption")]
+    pub fn open_read_only_single_file_encrypted<S: ToString>(
+        path: S,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Self::load_single_file_with(path.to_string(), 0, Keys::new(key), Access::ReadOnly)
+    }
+
+    fn unlocked(
+        data: FileBackend,
+        table: AllocationTable,
+        replay: Vec<Record>,
+    ) -> Result<Self, Error> {
+        let db = Self::internal_new(data, table, None, replay, 0)?;
+        // the owner may shrink the file below a mapping, which would crash this process
+        #[cfg(feature = "mmap")]
+        {
+            db.lock()?.mapping = None;
+        }
+        Ok(db)
+    }
+
+    /// Creates a database that is only kept in memory, and is gone once it is shut down or the
+    /// last handle to it is dropped. It behaves like any other database, but nothing is
+    /// written to disk and there is no background thread.
+    pub fn in_memory() -> Result<Self, Error> {
+        let header = Header::generate();
+        let mut data = MemoryBackend::default();
+        header.write_data(&mut data)?;
+        Self::with_backend(
+            Box::new(data),
+            None,
+            AllocationTable::empty(Placement::Memory, header, MEMORY_BLOCK_SIZE),
+            None,
+            false,
+            Vec::new(),
+            0,
+        )
+    }
+
+    /// Loads a database from a [`StorageBackend`], which contains its allocation table like a
+    /// single-file database does. Can NOT be used to create one. Mutations that are still in
+    /// the write-ahead log in `log` are replayed.
+    pub fn new_with_backend<B: StorageBackend + 'static, L: StorageBackend + 'static>(
+        backend: B,
+        log: L,
+        cache_period: u128,
+    ) -> Result<Self, Error> {
+        Self::load_backend_with(
+            Box::new(backend),
+            Box::new(log),
+            cache_period,
+            Keys::default(),
+        )
+    }
+
+    /// Loads an encrypted database from a [`StorageBackend`] with its current key, like
+    /// [`Self::new_with_backend`].
+    #[cfg(feature = "encryption")]
+    pub fn new_with_backend_encrypted<B: StorageBackend + 'static, L: StorageBackend + 'static>(
+        backend: B,
+        log: L,
+        cache_period: u128,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Self::load_backend_with(
+            Box::new(backend),
+            Box::new(log),
+            cache_period,
+            Keys::new(key),
+        )
+    }
+
+    fn load_backend_with(
+        mut backend: Box<dyn StorageBackend>,
+        log: Box<dyn StorageBackend>,
+        cache_period: u128,
+        keys: Keys,
+    ) -> Result<Self, Error> {
+        let table = AllocationTable::valid(AllocationTable::load_embedded(&mut *backend, keys)?)?;
+        let (wal, replay) = Wal::open_in(log, table.keys.clone())?;
+        Self::with_backend(backend, None, table, Some(wal), false, replay, cache_period)
+    }
+
+    /// Creates a database in a [`StorageBackend`], overwriting whatever it contained. Can NOT
+    /// be used to load one.
+    ///
+    /// The write-ahead log is kept in `log`, which is overwritten as well. It has to be passed
+    /// to [`Self::new_with_backend`] together with the database, as mutations that weren't
+    /// saved yet are only in there.
+    pub fn create_with_backend<B: StorageBackend + 'static, L: StorageBackend + 'static>(
+        backend: B,
+        log: L,
+        cache_period: u128,
+        block_size: usize,
+    ) -> Result<Self, Error> {
+        Self::create_backend_with(
+            Box::new(backend),
+            Box::new(log),
+            cache_period,
+            block_size,
+            Keys::default(),
+        )
+    }
+
+    /// Creates a database in a [`StorageBackend`] that is encrypted with `key`, like
+    /// [`Self::create_with_backend`].
+    #[cfg(feature = "encryption")]
+    pub fn create_with_backend_encrypted<
+        B: StorageBackend + 'static,
+        L: StorageBackend + 'static,
+    >(
+        backend: B,
+        log: L,
+        cache_period: u128,
+        block_size: usize,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Self::create_backend_with(
+            Box::new(backend),
+            Box::new(log),
+            cache_period,
+            block_size,
+            Keys::new(key),
+        )
+    }
+
+    fn create_backend_with(
+        mut backend: Box<dyn StorageBackend>,
+        log: Box<dyn StorageBackend>,
+        cache_period: u128,
+        block_size: usize,
+        keys: Keys,
+    ) -> Result<Self, Error> {
+        let header = Header::generate();
+        header.write_single(&mut *backend)?;
+        let mut table = AllocationTable::empty(
+            Placement::Embedded {
+                slot: 1,
+                generation: 0,
+                extent: None,
+            },
+            header,
+            block_size,
+        );
+        table.keys = keys.clone();
+        let wal = Wal::create_in(log, keys)?;
+        Self::with_backend(
+            backend,
+            None,
+            table,
+            Some(wal),
+            false,
+            Vec::new(),
+            cache_period,
+        )
+        .and_then(|x| {
+            x.save()?;
+            Ok(x)
+        })
+    }
+
+    /// Loads a single-file database for reading only, like [`Self::new_shared`].
+    pub fn new_single_file_shared<S: ToString>(path: S, cache_period: u128) -> Result<Self, Error> {
+        Self::load_single_file_with(
+            path.to_string(),
+            cache_period,
+            Keys::default(),
+            Access::Shared,
+        )
+    }
+
+    /// Loads an encrypted single-file database for reading only, like [`Self::new_shared`].
+    #[cfg(feature = "encryption")]
+    pub fn new_single_file_shared_encrypted<S: ToString>(
+        path: S,
+        cache_period: u128,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Self::load_single_file_with(
+            path.to_string(),
+            cache_period,
+            Keys::new(key),
+            Access::Shared,
+        )
+    }
+
+    /// Creates a single-file database, which contains its allocation table instead of keeping
+    /// it in a separate file. Can NOT be used to load one. Like with [`Self::new_single_file`],
+    /// the write-ahead log is kept at `<path>.wal` until the database is shut down.
+    pub fn create_single_file<S: ToString>(
+        path: S,
+        cache_period: u128,
+        block_size: usize,
+    ) -> Result<Self, Error> {
+        Self::create_single_file_with(path.to_string(), cache_period, block_size, Keys::default())
+    }
+
+    /// Creates a single-file database that is encrypted with `key`, like
+    /// [`Self::create_single_file`]. It can only be loaded with
+    /// [`Self::new_single_file_encrypted`] and the same key, or the one it was last rotated to.
+    #[cfg(feature = "encryption")]
+    pub fn create_single_file_encrypted<S: ToString>(
+        path: S,
+        cache_period: u128,
+        block_size: usize,
+        key: Key,
+    ) -> Result<Self, Error> {
+        Self::create_single_file_with(path.to_string(), cache_period, block_size, Keys::new(key))
+    }
+
+    fn create_single_file_with(
+        path: String,
+        cache_period: u128,
+        block_size: usize,
+        keys: Keys,
+    ) -> Result<Self, Error> {
+        let header = Header::generate();
+        let mut data = FileBackend::new(
+            File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?,
+        );
+        lock_data(&data.file, &path, Access::Exclusive)?;
+        header.write_single(&mut data)?;
+        let mut table = AllocationTable::empty(
+            Placement::Embedded {
+                slot: 1,
+                generation: 0,
+                extent: None,
+            },
+            header,
+            block_size,
+        );
+        table.keys = keys.clone();
+        Self::internal_new(
+            data,
+            table,
+            Some(Wal::create(path + ".wal", keys)?),
+            Vec::new(),
+            cache_period,
+        )
+        .and_then(|x| {
+            x.save()?;
+            Ok(x)
+        })
     }
 
     /// Tries to find an item in cache, returning Ok(None) if it wasn't found in the cache.
     /// This is the only function where a recently-deleted element will be an empty vector
     /// instead of being a None.
-    pub fn cache_lookup(&self, path: Option<&str>) -> Result<Option<Vec<u8>>, io::Error> {
-        let mut this = self.inner.lock().unwrap();
+    pub fn cache_lookup(&self, path: Option<&str>) -> Result<Option<Vec<u8>>, Error> {
+        let mut this = self.lock()?;
         if this.shutdown {
-            return Err(io::Error::new(ErrorKind::BrokenPipe, "The database has shut down. Writes are prohibited. If you didn't do this, some kind of error was encountered that forced the DB to shut down. Recovery will be attempted at regular intervals."));
+            return Err(Error::ShutDown);
         }
         let time = this.flush_cache(false)?;
-        if this.cache_period == 0 {
+        // read-only handles keep what they replayed from the log in the cache
+        if this.cache_period == 0 && this.wal.is_some() {
             return Ok(None);
         }
         if let Some(path) = path {
@@ -424,7 +2025,7 @@ impl FAlloc {
 
     /// Gets a value. This will try to get it from cache first, and fall back
     /// to the file. If so, the item will be cached for the future.
-    pub fn get(&self, path: &str) -> Result<Option<Vec<u8>>, io::Error> {
+    pub fn get(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
         if let Some(x) = self.cache_lookup(Some(path))? {
             if x.is_empty() {
                 return Ok(None);
@@ -432,120 +2033,257 @@ impl FAlloc {
             return Ok(Some(x));
         }
 
-        let mut this = self.inner.lock().unwrap();
+        let mut this = self.lock()?;
         if this.shutdown {
-            return Err(io::Error::new(ErrorKind::BrokenPipe, "The database has shut down. Writes are prohibited. If you didn't do this, some kind of error was encountered that forced the DB to shut down. Recovery will be attempted at regular intervals."));
+            return Err(Error::ShutDown);
         }
-        let (cache, alloc, data) = deborrow!(this: cache, alloc, data);
-        let time = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_millis();
-        alloc
-            .map
-            .get(path)
-            .map(|x| {
-                // get data, cache, and return it
-                x.get_data(data).map(|x| {
-                    (
-                        cache.insert(path.to_owned(), (time, false, x.clone())),
-                        Some(x),
-                    )
-                        .1
-                })
-            })
-            .unwrap_or(Ok(None))
+        this.read(path)
     }
 
     /// Sets a value in the cache. It will be flushed to storage after
     /// some time of not being used. EMPTY INPUT DATA WILL DELETE THE ITEM.
-    pub fn set(&self, path: &str, data: Vec<u8>) -> Result<(), io::Error> {
-        let mut this = self.inner.lock().unwrap();
+    /// The change is written to the write-ahead log before this returns.
+    pub fn set(&self, path: &str, data: Vec<u8>) -> Result<(), Error> {
+        let mut this = self.lock()?;
         if this.shutdown {
-            return Err(io::Error::new(ErrorKind::BrokenPipe, "The database has shut down. Writes are prohibited. If you didn't do this, some kind of error was encountered that forced the DB to shut down. Recovery will be attempted at regular intervals."));
+            return Err(Error::ShutDown);
         }
-        let (cache, alloc) = deborrow!(this: cache, alloc);
-        let time = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_millis();
-        cache.insert(path.to_owned(), (time, true, data));
-        if !alloc.map.contains_key(path) {
-            alloc.map.insert(
-                path.to_owned(),
-                Allocation {
-                    full_size: 0,
-                    locations: Vec::new(),
-                },
-            );
+        let record = Record::Set {
+            path: path.to_owned(),
+            data,
+        };
+        this.mutate(record)
+    }
+
+    /// Applies mutations all at once. They are logged together, so after a crash either all or
+    /// none of them are replayed.
+    pub(crate) fn commit(&self, records: Vec<Record>) -> Result<(), Error> {
+        let mut this = self.lock()?;
+        if this.shutdown {
+            return Err(Error::ShutDown);
+        }
+        let record = Record::Batch(records);
+        this.mutate(record)
+    }
+
+    /// Another handle to the same allocator.
+    pub(crate) fn share(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
         }
-        Ok(())
     }
 
     /// Returns the direct sub-paths of a path, or the direct root paths.
     /// Does NOT return sub-paths of sub-paths.
-    pub fn paths(&self, path: Option<&str>) -> Result<Vec<String>, io::Error> {
-        let mut this = self.inner.lock().unwrap();
+    pub fn paths(&self, path: Option<&str>) -> Result<Vec<String>, Error> {
+        let this = self.lock()?;
         if this.shutdown {
-            return Err(io::Error::new(ErrorKind::BrokenPipe, "The database has shut down. Writes are prohibited. If you didn't do this, some kind of error was encountered that forced the DB to shut down. Recovery will be attempted at regular intervals."));
-        }
-        let (alloc,) = deborrow!(this: alloc);
-        if let Some(path) = path {
-            let path_slashes = path.chars().filter(|x| x == &'/').count();
-            Ok(alloc
-                .map
-                .keys()
-                .filter(|x| {
-                    x.starts_with(&(path.to_owned() + "/"))
-                        && x.chars().filter(|x| x == &'/').count() == path_slashes + 1
-                })
-                .map(|x| x.to_owned())
-                .collect())
-        } else {
-            Ok(alloc
-                .map
-                .keys()
-                .filter(|x| !x.contains('/'))
-                .map(|x| x.to_owned())
-                .collect())
+            return Err(Error::ShutDown);
         }
+        Ok(this.paths(path, true))
     }
 
     /// Returns all sub-paths of a path, including indirect ones.
-    pub fn all_paths(&self, path: Option<&str>) -> Result<Vec<String>, io::Error> {
-        let mut this = self.inner.lock().unwrap();
+    pub fn all_paths(&self, path: Option<&str>) -> Result<Vec<String>, Error> {
+        let this = self.lock()?;
         if this.shutdown {
-            return Err(io::Error::new(ErrorKind::BrokenPipe, "The database has shut down. Writes are prohibited. If you didn't do this, some kind of error was encountered that forced the DB to shut down. Recovery will be attempted at regular intervals."));
+            return Err(Error::ShutDown);
         }
-        let (alloc,) = deborrow!(this: alloc);
-        if let Some(path) = path {
-            Ok(alloc
-                .map
-                .keys()
-                .filter(|x| x.starts_with(&(path.to_owned() + "/")))
-                .map(|x| x.to_owned())
-                .collect())
-        } else {
-            Ok(alloc.map.keys().map(|x| x.to_owned()).collect())
+        Ok(this.paths(path, false))
+    }
+
+    /// Starts keeping the current state for a snapshot. Values are copied into it right before
+    /// they are changed.
+    pub(crate) fn snapshot(&self) -> Result<Arc<Mutex<Overlay>>, Error> {
+        Ok(self.snapshot_with(|| ())?.0)
+    }
+
+    /// Takes a snapshot, and calls `f` while nothing can be changed, so that what it returns
+    /// belongs to the same point in time.
+    pub(crate) fn snapshot_with<T>(
+        &self,
+        f: impl FnOnce() -> T,
+    ) -> Result<(Arc<Mutex<Overlay>>, T), Error> {
+        let mut this = self.lock()?;
+        if this.shutdown {
+            return Err(Error::ShutDown);
         }
+        let snapshot = Arc::new(Mutex::new(Overlay::default()));
+        this.snapshots.push(Arc::downgrade(&snapshot));
+        Ok((snapshot, f()))
     }
 
-    /// Deletes all data that is BELOW the path in the tree. The path itself is NOT deleted.
-    pub fn delete_substructure(&self, path: &str) -> Result<(), io::Error> {
-        let mut this = self.inner.lock().unwrap();
+    /// Starts or stops sending all mutations to a mirror.
+    pub(crate) fn set_mirror(&self, mirror: Option<Arc<Backlog>>) -> Result<(), Error> {
+        self.lock()?.mirror = mirror;
+        Ok(())
+    }
+
+    /// Reads a value as it was when the snapshot was taken.
+    pub(crate) fn snapshot_get(
+        &self,
+        snapshot: &Mutex<Overlay>,
+        path: &str,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        // the allocator stays locked, so the value can't change between the two lookups
+        let mut this = self.lock()?;
         if this.shutdown {
-            return Err(io::Error::new(ErrorKind::BrokenPipe, "The database has shut down. Writes are prohibited. If you didn't do this, some kind of error was encountered that forced the DB to shut down. Recovery will be attempted at regular intervals."));
+            return Err(Error::ShutDown);
         }
-        let (cache, alloc) = deborrow!(this: cache, alloc);
-        let time = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_millis();
-        for key in alloc
-            .map
-            .keys()
-            .filter(|x| x.starts_with(&(path.to_owned() + "/")))
-            .map(|x| x.to_owned())
-        {
-            cache.insert(key, (time, true, Vec::new()));
+        if let Some(data) = snapshot.lock().map_err(|_| Error::Poisoned)?.get(path) {
+            return Ok(data);
+        }
+        this.current(path)
+    }
+
+    /// Lists paths as they were when the snapshot was taken, like [`Self::paths`] and
+    /// [`Self::all_paths`].
+    pub(crate) fn snapshot_paths(
+        &self,
+        snapshot: &Mutex<Overlay>,
+        path: Option<&str>,
+        direct: bool,
+    ) -> Result<Vec<String>, Error> {
+        let this = self.lock()?;
+        if this.shutdown {
+            return Err(Error::ShutDown);
+        }
+        let found = this.paths(path, direct);
+        Ok(snapshot
+            .lock()
+            .map_err(|_| Error::Poisoned)?
+            .paths(found, path, direct))
+    }
+
+    /// Copies the database to a new pair of files while it keeps being used. The copy contains
+    /// the state the database had when this was called, and can be loaded with [`Self::new`].
+    /// The copy of an encrypted database is encrypted with its current key. Fails if the data
+    /// file already exists.
+    ///
+    /// The copy is written to temporary files that are only renamed once it is complete, so a
+    /// backup that fails leaves nothing behind that could be mistaken for a database.
+    pub fn backup_to<S: ToString>(&self, data: S, alloc: S) -> Result<(), Error> {
+        let snapshot = self.snapshot()?;
+        self.backup(
+            &snapshot,
+            Backup::Pair {
+                data: data.to_string(),
+                alloc: alloc.to_string(),
+            },
+        )
+    }
+
+    /// Copies the database to a new single-file database, like [`Self::backup_to`]. The copy
+    /// can be loaded with [`Self::new_single_file`].
+    pub fn backup_to_single_file<S: ToString>(&self, path: S) -> Result<(), Error> {
+        let snapshot = self.snapshot()?;
+        self.backup(&snapshot, Backup::Single(path.to_string()))
+    }
+
+    /// Copies the state a snapshot sees to new files, like [`Self::backup_to`].
+    pub(crate) fn backup(&self, snapshot: &Mutex<Overlay>, target: Backup) -> Result<(), Error> {
+        // the name is taken right away, but the file stays empty until the copy is complete
+        let reserved = match &target {
+            Backup::Pair { data, .. } => data,
+            Backup::Single(path) => path,
+        };
+        File::options()
+            .write(true)
+            .create_new(true)
+            .open(reserved)?;
+        let result = self.copy_to(snapshot, &target);
+        if result.is_err() {
+            let tmp = match &target {
+                Backup::Pair { data, alloc } => vec![
+                    data.to_owned() + ".tmp",
+                    alloc.to_owned() + ".tmp",
+                    alloc.to_owned() + ".tmp.tmp",
+                    alloc.to_owned() + ".tmp.wal",
+                    alloc.to_owned() + ".tmp.wal.tmp",
+                ],
+                Backup::Single(path) => vec![
+                    path.to_owned() + ".tmp",
+                    path.to_owned() + ".tmp.wal",
+                    path.to_owned() + ".tmp.wal.tmp",
+                ],
+            };
+            for file in tmp.iter().chain([reserved]) {
+                let _ = fs::remove_file(file);
+            }
+        }
+        result
+    }
+
+    /// Writes the copy of [`Self::backup`] to temporary files, and renames them once it is
+    /// complete.
+    fn copy_to(&self, snapshot: &Mutex<Overlay>, target: &Backup) -> Result<(), Error> {
+        let (block_size, keys) = {
+            let this = self.lock()?;
+            (this.alloc.block_size, this.alloc.keys.current())
+        };
+        let backup = match target {
+            Backup::Pair { data, alloc } => FAlloc::create_with(
+                data.to_owned() + ".tmp",
+                alloc.to_owned() + ".tmp",
+                1000,
+                block_size,
+                keys,
+            )?,
+            Backup::Single(path) => {
+                FAlloc::create_single_file_with(path.to_owned() + ".tmp", 1000, block_size, keys)?
+            }
+        };
+        let mut copied = 0;
+        // each value is read separately, so writers only ever wait for one of them
+        let result = self
+            .snapshot_paths(snapshot, None, false)
+            .and_then(|paths| {
+                for path in paths {
+                    let Some(data) = self.snapshot_get(snapshot, &path)? else {
+                        continue;
+                    };
+                    copied += data.len();
+                    backup.set(&path, data)?;
+                    if copied >= BACKUP_BATCH {
+                        backup.save()?;
+                        copied = 0;
+                    }
+                }
+                Ok(())
+            });
+        if let Err(e) = result {
+            backup.abandon()?;
+            return Err(e);
+        }
+        backup.shutdown()?;
+        match target {
+            Backup::Pair { data, alloc } => {
+                fs::rename(data.to_owned() + ".tmp", data)?;
+                // the log of the copy is empty, and is created again when it is loaded
+                fs::remove_file(alloc.to_owned() + ".tmp.wal")?;
+                fs::rename(alloc.to_owned() + ".tmp", alloc)?;
+            }
+            // a single-file database removes its log when it is shut down
+            Backup::Single(path) => fs::rename(path.to_owned() + ".tmp", path)?,
         }
         Ok(())
     }
 
+    /// Deletes all data that is BELOW the path in the tree. The path itself is NOT deleted.
+    pub fn delete_substructure(&self, path: &str) -> Result<(), Error> {
+        let mut this = self.lock()?;
+        if this.shutdown {
+            return Err(Error::ShutDown);
+        }
+        let record = Record::DeleteSubstructure {
+            path: path.to_owned(),
+        };
+        this.mutate(record)
+    }
+
     /// Expires the cache and flushes it.
-    pub fn sync(&self) -> Result<(), io::Error> {
-        let mut this = self.inner.lock().unwrap();
+    pub fn sync(&self) -> Result<(), Error> {
+        let mut this = self.lock()?;
         this.last_cache_check = 0;
         for item in this.cache.iter_mut() {
             item.1 .0 = 0;
@@ -555,32 +2293,170 @@ impl FAlloc {
     }
 
     /// Syncs, then saves allocations.
-    pub fn save(&self) -> Result<(), io::Error> {
+    pub fn save(&self) -> Result<(), Error> {
+        self.sync()?;
+        self.lock()?.save()
+    }
+
+    /// Rewrites all allocations contiguously at the start of the data file, rebuilds the free
+    /// list, and truncates the file to what is still in use. The database stays usable while
+    /// this runs, as the work is done in batches. Damaged values are moved as they are and
+    /// reported to the [`Self::on_error`] callback, instead of stopping the compaction.
+    pub fn compact(&self) -> Result<(), Error> {
+        self.sync()?;
+        let mut cursor = 0;
+        let mut damaged = Vec::new();
+        loop {
+            let mut this = self.lock()?;
+            if this.shutdown {
+                return Err(Error::ShutDown);
+            }
+            this.save()?;
+            // saving also gives up the space that is free at the end now
+            let Some(new_cursor) = this.compact_batch(cursor, &mut damaged)? else {
+                // the callback may use the database, so it must not be locked
+                let on_error = this.on_error.clone();
+                mem::drop(this);
+                if let Some(on_error) = on_error {
+                    for e in &damaged {
+                        (on_error.0)(e);
+                    }
+                }
+                return Ok(());
+            };
+            cursor = new_cursor;
+            this.save()?;
+        }
+    }
+
+    /// Switches an encrypted database to a new key. Once this returns, the database can only be
+    /// loaded with the new key. Values that were encrypted with the old one are re-encrypted
+    /// by the background thread, in batches so that the database stays usable. Until that is
+    /// done, the old key is kept in the allocation table, encrypted with the new one.
+    #[cfg(feature = "encryption")]
+    pub fn rotate_key(&self, key: Key) -> Result<(), Error> {
+        self.sync()?;
+        let mut this = self.lock()?;
+        if this.shutdown {
+            return Err(Error::ShutDown);
+        }
+        if this.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if !this.alloc.keys.is_encrypted() {
+            return Err(FormatError::NotEncrypted.into());
+        }
+        this.save()?;
+        this.alloc.keys.rotate(key);
+        this.alloc.saved.consolidate = true;
+        let keys = this.alloc.keys.clone();
+        if let Some(wal) = &mut this.wal {
+            wal.set_keys(keys);
+        }
+        this.rekeyed = None;
+        // the table is saved with the new key first, which can still read the old log
+        this.save()
+    }
+
+    /// Whether values are still being re-encrypted after [`Self::rotate_key`]. The old key can
+    /// be discarded once this is false.
+    #[cfg(feature = "encryption")]
+    pub fn rotating_key(&self) -> Result<bool, Error> {
+        Ok(self.lock()?.alloc.keys.retiring())
+    }
+
+    /// Sets what the background thread does when it fails to save. Retries with backoff by
+    /// default, see [`RecoveryPolicy`].
+    pub fn set_recovery_policy(&self, policy: RecoveryPolicy) -> Result<(), Error> {
+        self.lock()?.recovery = policy;
+        Ok(())
+    }
+
+    /// Calls `callback` with every error the background thread runs into, right after the
+    /// [`RecoveryPolicy`] was applied. Damaged values that could not be re-encrypted after
+    /// [`Self::rotate_key`] are reported as well, but don't count as failures, and so are the
+    /// ones [`Self::compact`] ran into, on the thread that called it. A poisoned
+    /// database reports [`Error::Poisoned`] and is never saved again, whatever the policy. The
+    /// callback runs on the background thread, which waits for it, so anything slow (like
+    /// shutting the database down) should be handed to another thread.
+    pub fn on_error<F: Fn(&Error) + Send + Sync + 'static>(
+        &self,
+        callback: F,
+    ) -> Result<(), Error> {
+        self.lock()?.on_error = Some(Callback(Arc::new(callback)));
+        Ok(())
+    }
+
+    /// Saves, then checks the allocation table and data file for inconsistencies. With `repair`,
+    /// damaged values are moved below [`crate::QUARANTINE`] and the free space is recomputed.
+    pub fn check(&self, repair: bool) -> Result<Report, Error> {
         self.sync()?;
-        let mut this = self.inner.lock().unwrap();
-        this.alloc.save()?;
-        this.data.sync_all()
+        let mut this = self.lock()?;
+        if this.shutdown {
+            return Err(Error::ShutDown);
+        }
+        if this.read_only && repair {
+            return Err(Error::ReadOnly);
+        }
+        if !this.read_only {
+            this.save()?;
+        }
+        let this = &mut *this;
+        let mut report = Report {
+            problems: this.alloc.check(&mut *this.data, &[])?,
+            quarantined: Vec::new(),
+        };
+        if repair {
+            report.quarantined = this.quarantine(&report.problems)?;
+        }
+        Ok(report)
     }
 
     /// Gracefully shuts down the allocator, saving in the process.
     /// Please use [`Self::shutdown`] instead if possible. This variant
     /// will force a shutdown across all threads without the guarantee that
     /// this is the only thread with access to it.
-    pub fn shutdown_here(&self) -> Result<(), io::Error> {
+    pub fn shutdown_here(&self) -> Result<(), Error> {
+        {
+            let mut this = self.lock()?;
+            if this.shutdown {
+                return Err(Error::ShutDown);
+            }
+            if !this.background() {
+                // there is nothing to save, and no background thread to release the lock
+                if let Some(file) = &this.file {
+                    let _ = file.unlock();
+                }
+                this.shutdown = true;
+                return Ok(());
+            }
+        }
         self.save()?;
-        self.inner.lock().unwrap().shutdown = true;
-        while self.inner.lock().unwrap().shutdown {
+        self.lock()?.shutdown = true;
+        loop {
+            let this = self.lock()?;
+            // the background thread also ends if saving fails and its recovery policy gives up
+            if !this.shutdown || !this.background() {
+                return Ok(());
+            }
+            mem::drop(this);
             thread::sleep(Duration::from_millis(5));
         }
-        Ok(())
     }
 
     /// Gracefully shuts down the DB, saving in the process.
-    pub fn shutdown(self) -> Result<(), io::Error> {
-        self.save()?;
-        self.inner.lock().unwrap().shutdown = true;
-        while self.inner.lock().unwrap().shutdown {
-            thread::sleep(Duration::from_millis(5));
+    pub fn shutdown(self) -> Result<(), Error> {
+        self.shutdown_here()
+    }
+
+    /// Shuts down without saving, for databases that are thrown away. The background thread
+    /// stops the next time it wakes up.
+    fn abandon(self) -> Result<(), Error> {
+        let mut this = self.lock()?;
+        this.read_only = true;
+        this.shutdown = true;
+        if let Some(file) = &this.file {
+            let _ = file.unlock();
         }
         Ok(())
     }
@@ -588,14 +2464,27 @@ impl FAlloc {
 
 #[cfg(test)]
 mod test {
-    use std::fs;
+    use std::{
+        fs::{self, File},
+        io::{Seek, SeekFrom, Write},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+    };
 
-    use crate::storage::FAlloc;
+    use crate::{
+        backend::FileBackend,
+        cleanup::Files,
+        format::{Slot, DATA_HEADER_LEN},
+        storage::FAlloc,
+        Corruption, Error, FormatError, MicroDB,
+    };
 
     #[test]
     fn main() {
-        let _ = fs::remove_file("test.dat");
-        let _ = fs::remove_file("test.alloc");
+        let _files = Files::db("test");
         create();
         load();
         delete_val();
@@ -632,4 +2521,571 @@ mod test {
         db.sync().unwrap();
         db.shutdown().unwrap();
     }
+
+    #[test]
+    fn replay_after_crash() {
+        let _files = ["crash", "crashed"].map(Files::db);
+        let db = FAlloc::create("crash.dat", "crash.alloc", 500, 64).unwrap();
+        db.set("a", vec![1; 100]).unwrap();
+        db.sync().unwrap(); // data is now in the data file, but the table is not saved
+        db.set("b", vec![2; 10]).unwrap();
+        db.set("a", vec![3; 200]).unwrap();
+        // copying the files now gives the same state as the program dying here
+        for ext in ["dat", "alloc", "alloc.wal"] {
+            fs::copy(format!("crash.{ext}"), format!("crashed.{ext}")).unwrap();
+        }
+        db.shutdown().unwrap();
+
+        let db = FAlloc::new("crashed.dat", "crashed.alloc", 500).unwrap();
+        assert_eq!(db.get("a").unwrap().unwrap(), vec![3_u8; 200]);
+        assert_eq!(db.get("b").unwrap().unwrap(), vec![2_u8; 10]);
+        db.shutdown().unwrap();
+        let db = FAlloc::new("crashed.dat", "crashed.alloc", 500).unwrap();
+        assert_eq!(db.get("a").unwrap().unwrap(), vec![3_u8; 200]);
+        db.shutdown().unwrap();
+        assert_eq!(fs::metadata("crashed.alloc.wal").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn locking() {
+        let _files = Files::db("lock");
+        let db = FAlloc::create("lock.dat", "lock.alloc", 0, 64).unwrap();
+        db.set("a", vec![1; 10]).unwrap();
+        assert!(matches!(
+            FAlloc::new("lock.dat", "lock.alloc", 0),
+            Err(Error::InUse { path }) if path == "lock.dat"
+        ));
+        assert!(matches!(
+            FAlloc::new_shared("lock.dat", "lock.alloc", 0),
+            Err(Error::InUse { .. })
+        ));
+        db.shutdown().unwrap();
+
+        let db = FAlloc::new("lock.dat", "lock.alloc", 0).unwrap();
+        db.set("b", vec![2; 10]).unwrap();
+        db.shutdown_here().unwrap();
+        let reader = FAlloc::new_shared("lock.dat", "lock.alloc", 0).unwrap();
+        let other = FAlloc::new_shared("lock.dat", "lock.alloc", 0).unwrap();
+        assert_eq!(reader.get("a").unwrap().unwrap(), vec![1; 10]);
+        assert_eq!(other.get("b").unwrap().unwrap(), vec![2; 10]);
+        assert!(matches!(reader.set("c", vec![3]), Err(Error::ReadOnly)));
+        assert!(matches!(
+            FAlloc::new("lock.dat", "lock.alloc", 0),
+            Err(Error::InUse { .. })
+        ));
+        reader.shutdown().unwrap();
+        other.shutdown().unwrap();
+        FAlloc::new("lock.dat", "lock.alloc", 0)
+            .unwrap()
+            .shutdown()
+            .unwrap();
+    }
+
+    #[test]
+    fn lock_changes_hands() {
+        let _files = Files::db("handover");
+        let db = FAlloc::create("handover.dat", "handover.alloc", 0, 64).unwrap();
+        db.set("a", vec![0; 100]).unwrap();
+        // nothing else is read before the lock is held
+        assert!(matches!(
+            FAlloc::new("handover.dat", "handover.missing", 0),
+            Err(Error::InUse { .. })
+        ));
+
+        // a handle that waits for the database sees the table the previous owner left behind
+        let waiting = thread::spawn(|| loop {
+            match FAlloc::new("handover.dat", "handover.alloc", 0) {
+                Ok(db) => return db,
+                Err(Error::InUse { .. }) => thread::yield_now(),
+                Err(e) => panic!("{e}"),
+            }
+        });
+        for i in 1..=50 {
+            db.set("a", vec![i; 100 + i as usize]).unwrap();
+            db.save().unwrap();
+        }
+        db.shutdown().unwrap();
+        let db = waiting.join().unwrap();
+        assert_eq!(db.get("a").unwrap(), Some(vec![50; 150]));
+        assert!(db.check(false).unwrap().is_ok());
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn read_only() {
+        let _files = Files::db("readonly");
+        let db = MicroDB::create("readonly.dat", "readonly.alloc", 100, 64).unwrap();
+        db.set_raw("a", 1_u32).unwrap();
+        db.set_com("b", vec![1_u32, 2, 3]).unwrap();
+        db.save().unwrap();
+        db.set_raw("c", 2_u32).unwrap();
+
+        // the database is still owned by db, and c is only in its log
+        let reader = MicroDB::open_read_only("readonly.dat", "readonly.alloc").unwrap();
+        assert_eq!(reader.get_raw::<u32, _>("a").unwrap(), Some(1));
+        assert_eq!(
+            reader.get_com::<Vec<u32>, _>("b").unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(reader.get_raw::<u32, _>("c").unwrap(), Some(2));
+        assert!(matches!(reader.set_raw("a", 2_u32), Err(Error::ReadOnly)));
+        assert!(matches!(reader.remove_raw("a"), Err(Error::ReadOnly)));
+        assert!(matches!(
+            reader.remove_com::<Vec<u32>, _>("b"),
+            Err(Error::ReadOnly)
+        ));
+        assert!(reader.check(false).unwrap().is_ok());
+        reader.shutdown().unwrap();
+        db.shutdown().unwrap();
+
+        // nothing is created when opening
+        fs::remove_file("readonly.alloc.wal").unwrap();
+        let reader = MicroDB::open_read_only("readonly.dat", "readonly.alloc").unwrap();
+        assert_eq!(reader.get_raw::<u32, _>("c").unwrap(), Some(2));
+        reader.shutdown().unwrap();
+        assert!(fs::metadata("readonly.alloc.wal").is_err());
+    }
+
+    #[test]
+    fn in_memory() {
+        let _files = Files::new("memory", &["dmdb", "mmdb", "mmdb.wal"]);
+        let db = MicroDB::in_memory().unwrap();
+        for i in 0..100_u32 {
+            db.set_com(format!("v/{i}"), vec![i; 50]).unwrap();
+        }
+        db.set_raw("a", 1_u32).unwrap();
+        let snapshot = db.snapshot().unwrap();
+        db.remove("v").unwrap();
+        assert!(db.get_paths(Some("v")).unwrap().is_empty());
+        assert_eq!(snapshot.get_paths(Some("v")).unwrap().len(), 100);
+        drop(snapshot);
+        db.transaction(|tx| tx.set_raw("b", 2_u32)).unwrap();
+        db.compact().unwrap();
+        assert!(db.check(false).unwrap().is_ok());
+        assert_eq!(db.get_raw::<u32, _>("a").unwrap(), Some(1));
+
+        db.backup_to("memory.dmdb", "memory.mmdb").unwrap();
+        db.shutdown().unwrap();
+        let copy = MicroDB::new("memory.dmdb", "memory.mmdb", 0).unwrap();
+        assert_eq!(copy.get_raw::<u32, _>("b").unwrap(), Some(2));
+        copy.shutdown().unwrap();
+    }
+
+    #[test]
+    fn deleted_paths() {
+        let _files = Files::db("deleted");
+        let db = FAlloc::create("deleted.dat", "deleted.alloc", 60_000, 64).unwrap();
+        db.set("a/b", vec![1]).unwrap();
+        db.set("a/c", vec![2]).unwrap();
+        db.set("x", vec![3]).unwrap();
+        db.save().unwrap();
+        // the deletions are only in the cache, which isn't flushed for a minute
+        db.set("a/b", Vec::new()).unwrap();
+        assert_eq!(db.all_paths(None).unwrap(), ["a/c", "x"]);
+        db.delete_substructure("a").unwrap();
+        assert!(db.paths(Some("a")).unwrap().is_empty());
+        assert_eq!(db.all_paths(None).unwrap(), ["x"]);
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn detect_corruption() {
+        let _files = Files::db("corrupt");
+        let db = FAlloc::create("corrupt.dat", "corrupt.alloc", 0, 64).unwrap();
+        db.set("test", vec![40; 100]).unwrap();
+        db.shutdown().unwrap();
+        let mut data = fs::read("corrupt.dat").unwrap();
+        data[DATA_HEADER_LEN as usize + 10] ^= 1;
+        fs::write("corrupt.dat", data).unwrap();
+
+        let db = FAlloc::new("corrupt.dat", "corrupt.alloc", 0).unwrap();
+        assert!(matches!(
+            db.get("test"),
+            Err(Error::Corrupt(Corruption::Checksum { path, .. })) if path == "test"
+        ));
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn mismatched_files() {
+        let _files = ["mismatch1", "mismatch2"].map(Files::db);
+        FAlloc::create("mismatch1.dat", "mismatch1.alloc", 0, 64)
+            .unwrap()
+            .shutdown()
+            .unwrap();
+        FAlloc::create("mismatch2.dat", "mismatch2.alloc", 0, 64)
+            .unwrap()
+            .shutdown()
+            .unwrap();
+        assert!(matches!(
+            FAlloc::new("mismatch1.dat", "mismatch2.alloc", 0),
+            Err(Error::Format(FormatError::Mismatch { .. }))
+        ));
+        assert!(matches!(
+            FAlloc::new("mismatch1.alloc", "mismatch1.alloc", 0),
+            Err(Error::Format(FormatError::NotADatabase))
+        ));
+        assert!(matches!(
+            FAlloc::new("mismatch0.dat", "mismatch1.alloc", 0),
+            Err(Error::NotFound { path }) if path == "mismatch0.dat"
+        ));
+    }
+
+    #[test]
+    fn upgrade_version_0() {
+        let _files = Files::db("upgrade");
+        // block_size, blocks_reserved, free_len, map_len, then "a" with one location
+        let mut meta = Vec::new();
+        for x in [64_u64, 1, 0, 1, 1] {
+            meta.extend_from_slice(&x.to_be_bytes());
+        }
+        meta.push(b'a');
+        for x in [3_u64, 1, 0, 64] {
+            meta.extend_from_slice(&x.to_be_bytes());
+        }
+        let mut data = vec![0_u8; 64];
+        data[..3].copy_from_slice(&[1, 2, 3]);
+        fs::write("upgrade.dat", &data).unwrap();
+        fs::write("upgrade.alloc", &meta).unwrap();
+
+        let db = FAlloc::new("upgrade.dat", "upgrade.alloc", 0).unwrap();
+        assert_eq!(db.get("a").unwrap().unwrap(), vec![1, 2, 3]);
+        db.shutdown().unwrap();
+
+        // an old table doesn't belong to a data file that was upgraded without it
+        fs::write("upgrade.alloc", &meta).unwrap();
+        assert!(matches!(
+            FAlloc::new("upgrade.dat", "upgrade.alloc", 0),
+            Err(Error::Format(FormatError::Mismatch { meta: 0, .. }))
+        ));
+
+        // an upgrade that was interrupted after overwriting the start of the file is finished
+        // from its copy
+        let upgraded = fs::read("upgrade.dat").unwrap();
+        let mut torn = data.clone();
+        torn.resize(DATA_HEADER_LEN as usize + data.len(), 0xff);
+        fs::write("upgrade.dat.upgrade", &upgraded[..torn.len()]).unwrap();
+        fs::write("upgrade.dat", torn).unwrap();
+        fs::write("upgrade.alloc", meta).unwrap();
+        let db = FAlloc::new("upgrade.dat", "upgrade.alloc", 0).unwrap();
+        assert_eq!(db.get("a").unwrap().unwrap(), vec![1, 2, 3]);
+        assert!(fs::metadata("upgrade.dat.upgrade").is_err());
+        db.set("b", vec![4; 100]).unwrap();
+        db.shutdown().unwrap();
+        let db = FAlloc::new("upgrade.dat", "upgrade.alloc", 0).unwrap();
+        assert_eq!(db.get("a").unwrap().unwrap(), vec![1, 2, 3]);
+        assert_eq!(db.get("b").unwrap().unwrap(), vec![4; 100]);
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn garbage_table() {
+        let _files = Files::db("garbage");
+        FAlloc::create("garbage.dat", "garbage.alloc", 0, 64)
+            .unwrap()
+            .shutdown()
+            .unwrap();
+        // without a header, this is read as a version 0 table with huge lengths
+        for lengths in [[64, 1, u64::MAX, 1, 1], [64, 1, 0, 1, u64::MAX]] {
+            let meta: Vec<_> = lengths.iter().flat_map(|x| x.to_be_bytes()).collect();
+            fs::write("garbage.alloc", meta).unwrap();
+            assert!(matches!(
+                FAlloc::new("garbage.dat", "garbage.alloc", 0),
+                Err(Error::Format(FormatError::NotADatabase))
+            ));
+        }
+    }
+
+    #[test]
+    fn compaction() {
+        fn expected(i: u8) -> Option<Vec<u8>> {
+            match i % 3 {
+                0 => Some(vec![i; i as usize * 7 + 40]),
+                1 => None,
+                _ => Some(vec![i; i as usize * 7 + 1]),
+            }
+        }
+        let _files = Files::db("compact");
+        let db = FAlloc::create("compact.dat", "compact.alloc", 0, 16).unwrap();
+        for i in 0..50_u8 {
+            db.set(&format!("v{i}"), vec![i; i as usize * 7 + 1])
+                .unwrap();
+        }
+        db.sync().unwrap();
+        // growing values spreads them over multiple places, deleting them leaves holes
+        for i in 0..50_u8 {
+            db.set(&format!("v{i}"), expected(i).unwrap_or_default())
+                .unwrap();
+        }
+        db.sync().unwrap();
+        let before = fs::metadata("compact.dat").unwrap().len();
+        db.compact().unwrap();
+        let after = fs::metadata("compact.dat").unwrap().len();
+        assert!(after < before);
+        {
+            let this = db.lock().unwrap();
+            assert!(this.alloc.free.iter().next().is_none());
+            let mut used = 0;
+            for allocation in this.alloc.map.values() {
+                assert_eq!(allocation.locations.len(), 1);
+                used += allocation.locations[0].1;
+            }
+            assert_eq!(after, DATA_HEADER_LEN + used as u64);
+        }
+        for i in 0..50_u8 {
+            assert_eq!(db.get(&format!("v{i}")).unwrap(), expected(i));
+        }
+        db.shutdown().unwrap();
+        let db = FAlloc::new("compact.dat", "compact.alloc", 0).unwrap();
+        for i in 0..50_u8 {
+            assert_eq!(db.get(&format!("v{i}")).unwrap(), expected(i));
+        }
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn compact_past_damage() {
+        let _files = Files::db("compactdamage");
+        let db = FAlloc::create("compactdamage.dat", "compactdamage.alloc", 0, 16).unwrap();
+        db.set("a", vec![1; 100]).unwrap();
+        db.set("b", vec![2; 100]).unwrap();
+        db.set("c", vec![3; 100]).unwrap();
+        db.shutdown().unwrap();
+        let mut data = fs::read("compactdamage.dat").unwrap();
+        data[DATA_HEADER_LEN as usize + 112 + 10] ^= 1;
+        fs::write("compactdamage.dat", data).unwrap();
+
+        // the damaged value is moved into the hole along with the others, and stays damaged
+        let db = FAlloc::new("compactdamage.dat", "compactdamage.alloc", 0).unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let reported = errors.clone();
+        db.on_error(move |e| {
+            reported.lock().unwrap().push(match e {
+                Error::Corrupt(c) => Some(c.clone()),
+                _ => None,
+            })
+        })
+        .unwrap();
+        db.set("a", Vec::new()).unwrap();
+        db.compact().unwrap();
+        assert!(matches!(
+            &errors.lock().unwrap()[..],
+            [Some(Corruption::Checksum { path, .. })] if path == "b"
+        ));
+        db.shutdown().unwrap();
+        let db = FAlloc::new("compactdamage.dat", "compactdamage.alloc", 0).unwrap();
+        assert_eq!(db.lock().unwrap().alloc.map["b"].locations, [(0, 112)]);
+        assert!(matches!(
+            db.get("b"),
+            Err(Error::Corrupt(Corruption::Checksum { path, .. })) if path == "b"
+        ));
+        assert_eq!(db.get("c").unwrap(), Some(vec![3; 100]));
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn reclaim_tail() {
+        let _files = Files::db("tail");
+        let db = FAlloc::create("tail.dat", "tail.alloc", 0, 16).unwrap();
+        db.set("a", vec![1; 100]).unwrap();
+        db.set("b", vec![2; 100_000]).unwrap();
+        db.save().unwrap();
+        let full = fs::metadata("tail.dat").unwrap().len();
+        db.set("b", Vec::new()).unwrap();
+        db.save().unwrap();
+        let len = fs::metadata("tail.dat").unwrap().len();
+        assert!(len < full);
+        assert_eq!(len, DATA_HEADER_LEN + 112);
+        db.set("b", vec![3; 1000]).unwrap();
+        db.shutdown().unwrap();
+        let db = FAlloc::new("tail.dat", "tail.alloc", 0).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some(vec![1; 100]));
+        assert_eq!(db.get("b").unwrap(), Some(vec![3; 1000]));
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn single_file() {
+        let _files = Files::new("single", &["mdb", "mdb.wal"]);
+        let db = FAlloc::create_single_file("single.mdb", 0, 16).unwrap();
+        for i in 0..100_u8 {
+            db.set(&format!("v{i}"), vec![i; i as usize * 3 + 1])
+                .unwrap();
+        }
+        db.save().unwrap();
+        for i in (0..100_u8).step_by(2) {
+            db.set(&format!("v{i}"), Vec::new()).unwrap();
+        }
+        db.compact().unwrap();
+        assert!(fs::metadata("single.mdb.wal").is_ok());
+        db.shutdown().unwrap();
+        // the log is only needed while the database is loaded
+        assert!(fs::metadata("single.mdb.wal").is_err());
+        assert!(crate::check_single_file("single.mdb", false)
+            .unwrap()
+            .is_ok());
+
+        // a save that was interrupted while writing the other slot doesn't matter
+        let mut data = File::options()
+            .read(true)
+            .write(true)
+            .open("single.mdb")
+            .unwrap();
+        let slots = Slot::read_all(&mut FileBackend::new(data.try_clone().unwrap())).unwrap();
+        let current = (slots[1].unwrap().generation > slots[0].unwrap().generation) as usize;
+        data.seek(SeekFrom::Start([1024, 2048][1 - current]))
+            .unwrap();
+        data.write_all(&[0xff; 20]).unwrap();
+        drop(data);
+
+        let db = FAlloc::new_single_file("single.mdb", 0).unwrap();
+        for i in 0..100_u8 {
+            let expected = (i % 2 == 1).then(|| vec![i; i as usize * 3 + 1]);
+            assert_eq!(db.get(&format!("v{i}")).unwrap(), expected);
+        }
+        db.shutdown().unwrap();
+        assert!(fs::metadata("single.mdb.wal").is_err());
+    }
+
+    #[test]
+    fn journal() {
+        let _files = Files::db("journal");
+        let db = FAlloc::create("journal.dat", "journal.alloc", 0, 16).unwrap();
+        for i in 0..200_u8 {
+            db.set(&format!("v{i}"), vec![i; 20]).unwrap();
+        }
+        db.save().unwrap();
+        // holes between the values make the free list long
+        for i in (1..200_u8).step_by(2) {
+            db.set(&format!("v{i}"), Vec::new()).unwrap();
+        }
+        db.save().unwrap();
+        let table = fs::metadata("journal.alloc").unwrap().len();
+        // only the changed entry and the changes of the free list are written
+        db.set("v7", vec![1; 100]).unwrap();
+        db.save().unwrap();
+        let journal = fs::metadata("journal.alloc").unwrap().len();
+        assert!(journal > table && journal - table < 200);
+        db.save().unwrap();
+        assert_eq!(fs::metadata("journal.alloc").unwrap().len(), journal);
+        db.set("v8", Vec::new()).unwrap();
+        db.shutdown().unwrap();
+
+        // a batch torn by a crash is ignored, and the next save rewrites the table
+        let mut file = File::options().append(true).open("journal.alloc").unwrap();
+        file.write_all(&[0, 0, 0, 0, 0, 0, 1, 0, 1, 2]).unwrap();
+        drop(file);
+        let db = FAlloc::new("journal.dat", "journal.alloc", 0).unwrap();
+        assert_eq!(db.get("v7").unwrap(), Some(vec![1; 100]));
+        assert_eq!(db.get("v8").unwrap(), None);
+        assert_eq!(db.get("v9").unwrap(), None);
+        assert_eq!(db.get("v10").unwrap(), Some(vec![10; 20]));
+        // the free list is journaled too, so nothing is lost track of
+        assert!(db.check(false).unwrap().is_ok());
+        db.save().unwrap();
+        assert!(fs::metadata("journal.alloc").unwrap().len() < journal);
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn churn() {
+        let _files = Files::db("churn");
+        let mut rng = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |max: u64| {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng % max
+        };
+        let db = FAlloc::create("churn.dat", "churn.alloc", 0, 8).unwrap();
+        let mut expected = vec![Vec::new(); 64];
+        for round in 0..40 {
+            for _ in 0..200 {
+                let key = next(64) as usize;
+                let value = vec![round as u8; next(300) as usize];
+                db.set(&format!("k{key}"), value.clone()).unwrap();
+                expected[key] = value;
+            }
+            db.sync().unwrap();
+            let this = db.lock().unwrap();
+            // allocations and free space together must cover the file exactly once
+            let mut extents: Vec<_> = this.alloc.free.iter().collect();
+            for allocation in this.alloc.map.values() {
+                let capacity: usize = allocation.locations.iter().map(|x| x.1).sum();
+                assert!(capacity >= allocation.full_size);
+                assert!(capacity < allocation.full_size + this.alloc.block_size);
+                extents.extend(&allocation.locations);
+            }
+            extents.sort();
+            let mut pos = 0;
+            for (start, len) in extents {
+                assert_eq!(start, pos);
+                pos += len;
+            }
+            assert_eq!(pos, this.alloc.blocks_reserved * this.alloc.block_size);
+        }
+        for (key, value) in expected.into_iter().enumerate() {
+            let value = (!value.is_empty()).then_some(value);
+            assert_eq!(db.get(&format!("k{key}")).unwrap(), value);
+        }
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn hot_backup() {
+        let _files = ["test", "copy"]
+            .map(|x| Files::new(&format!("backup.{x}"), &["dmdb", "mmdb", "mmdb.wal"]));
+        let _single = Files::new("backup.single", &["mdb", "mdb.wal"]);
+        let db = MicroDB::create("backup.test.dmdb", "backup.test.mmdb", 100, 64).unwrap();
+        for i in 0..200_u32 {
+            db.set_raw(format!("v/{i}"), vec![i; 100]).unwrap();
+        }
+        db.set_raw("a", 0_u32).unwrap();
+        db.set_raw("b", 0_u32).unwrap();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            // keeps a and b equal, but only as a whole
+            s.spawn(|| {
+                let mut i = 0_u32;
+                while !done.load(Ordering::Relaxed) {
+                    i += 1;
+                    db.transaction(|tx| {
+                        tx.set_raw("a", i)?;
+                        tx.set_raw("b", i)
+                    })
+                    .unwrap();
+                    db.remove_raw(format!("v/{}", i % 200)).unwrap();
+                }
+            });
+            db.backup_to("backup.copy.dmdb", "backup.copy.mmdb")
+                .unwrap();
+            done.store(true, Ordering::Relaxed);
+        });
+        db.shutdown().unwrap();
+
+        let copy = MicroDB::new("backup.copy.dmdb", "backup.copy.mmdb", 100).unwrap();
+        let a = copy.get_raw::<u32, _>("a").unwrap();
+        assert!(a.is_some());
+        assert_eq!(a, copy.get_raw::<u32, _>("b").unwrap());
+        for path in copy.get_paths(Some("v")).unwrap() {
+            assert_eq!(
+                copy.get_raw::<Vec<u32>, _>(path).unwrap().unwrap().len(),
+                100
+            );
+        }
+        assert!(copy.check(false).unwrap().is_ok());
+
+        // a backup that fails partway leaves nothing behind
+        assert!(copy
+            .backup_to("backup.failed.dmdb", "missing/backup.failed.mmdb")
+            .is_err());
+        assert!(fs::metadata("backup.failed.dmdb").is_err());
+        assert!(fs::metadata("backup.failed.dmdb.tmp").is_err());
+        copy.backup_to_single_file("backup.single.mdb").unwrap();
+        copy.shutdown().unwrap();
+        assert!(fs::metadata("backup.single.mdb.tmp").is_err());
+        let single = MicroDB::new_single_file("backup.single.mdb", 0).unwrap();
+        assert_eq!(single.get_raw::<u32, _>("a").unwrap(), a);
+        single.shutdown().unwrap();
+    }
 }
@@ -0,0 +1,189 @@
+//! Buffered writes of a [`crate::MicroDB::transaction`], and preserved values of a
+//! [`crate::MicroDB::snapshot`].
+
+use std::collections::BTreeMap;
+
+use crate::wal::Record;
+
+/// The changes a transaction made so far, or the values a snapshot had before they were changed.
+/// Reads look here first.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Overlay {
+    /// Values written by the transaction. Empty ones are deleted.
+    writes: BTreeMap<String, Vec<u8>>,
+    /// Prefixes (including the trailing slash) whose substructure was deleted.
+    deleted: Vec<String>,
+    /// Everything in order, to be applied on commit.
+    pub(crate) records: Vec<Record>,
+}
+
+impl Overlay {
+    /// Returns Some if the transaction changed the value, with None if it is deleted.
+    pub(crate) fn get(&self, path: &str) -> Option<Option<Vec<u8>>> {
+        if let Some(data) = self.writes.get(path) {
+            return Some((!data.is_empty()).then(|| data.to_owned()));
+        }
+        if self.deleted.iter().any(|x| path.starts_with(x)) {
+            return Some(None);
+        }
+        None
+    }
+
+    pub(crate) fn set(&mut self, path: &str, data: Vec<u8>) {
+        self.writes.insert(path.to_owned(), data.clone());
+        self.records.push(Record::Set {
+            path: path.to_owned(),
+            data,
+        });
+    }
+
+    /// Keeps the value a path has before it changes. Empty means it doesn't exist.
+    pub(crate) fn preserve(&mut self, path: &str, data: Option<Vec<u8>>) {
+        self.writes
+            .insert(path.to_owned(), data.unwrap_or_default());
+    }
+
+    pub(crate) fn delete_substructure(&mut self, path: &str) {
+        let prefix = path.to_owned() + "/";
+        self.writes.retain(|x, _| !x.starts_with(&prefix));
+        self.deleted.push(prefix);
+        self.records.push(Record::DeleteSubstructure {
+            path: path.to_owned(),
+        });
+    }
+
+    /// Adjusts paths found in the storage to what the transaction sees. `direct` selects
+    /// only direct sub-paths, like [`crate::FAlloc::paths`].
+    pub(crate) fn paths(
+        &self,
+        found: Vec<String>,
+        path: Option<&str>,
+        direct: bool,
+    ) -> Vec<String> {
+        let matches = |x: &str| {
+            let rest = match path {
+                Some(path) => match x.strip_prefix(path).and_then(|x| x.strip_prefix('/')) {
+                    Some(rest) => rest,
+                    None => return false,
+                },
+                None => x,
+            };
+            !direct || !rest.contains('/')
+        };
+        let mut paths: Vec<_> = found
+            .into_iter()
+            .filter(|x| self.get(x).is_none_or(|x| x.is_some()))
+            .chain(
+                self.writes
+                    .iter()
+                    .filter(|x| !x.1.is_empty() && matches(x.0))
+                    .map(|x| x.0.to_owned()),
+            )
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{cleanup::Files, data::Path, Error, MicroDB};
+
+    #[test]
+    fn commit_and_rollback() {
+        let _files = Files::new("tx.test", &["dmdb", "mmdb", "mmdb.wal"]);
+        let db = MicroDB::create("tx.test.dmdb", "tx.test.mmdb", 100, 16).unwrap();
+        db.set_com("users/0", vec![1_u32, 2]).unwrap();
+
+        let result = db.transaction(|tx| {
+            tx.set_com("users/0", vec![3_u32])?;
+            tx.set_raw("index/3", "users/0".to_owned())?;
+            // the transaction sees its own writes, nobody else does yet
+            assert_eq!(tx.get_com::<Vec<u32>, _>("users/0")?, Some(vec![3]));
+            assert_eq!(
+                tx.get_paths(Some("users/0"))?,
+                vec!["users/0".sub_path(0_u64)]
+            );
+            assert_eq!(db.get_com::<Vec<u32>, _>("users/0")?, Some(vec![1, 2]));
+            assert_eq!(db.get_raw::<String, _>("index/3")?, None);
+            Ok(7)
+        });
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(db.get_com::<Vec<u32>, _>("users/0").unwrap(), Some(vec![3]));
+        assert_eq!(
+            db.get_raw::<String, _>("index/3").unwrap(),
+            Some("users/0".to_owned())
+        );
+
+        let result = db.transaction(|tx| {
+            tx.remove("users/0")?;
+            tx.transaction(|inner| inner.set_raw("index/4", 4_u8))?;
+            assert_eq!(tx.get_raw::<u8, _>("index/4")?, Some(4));
+            Err::<(), _>(Error::ShutDown)
+        });
+        assert!(matches!(result, Err(Error::ShutDown)));
+        assert_eq!(db.get_com::<Vec<u32>, _>("users/0").unwrap(), Some(vec![3]));
+        assert_eq!(db.get_raw::<u8, _>("index/4").unwrap(), None);
+        db.shutdown().unwrap();
+
+        // the committed transaction survives reloading
+        let db = MicroDB::new("tx.test.dmdb", "tx.test.mmdb", 100).unwrap();
+        assert_eq!(db.get_com::<Vec<u32>, _>("users/0").unwrap(), Some(vec![3]));
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn snapshot() {
+        let _files = Files::new("snap.test", &["dmdb", "mmdb", "mmdb.wal"]);
+        let db = MicroDB::create("snap.test.dmdb", "snap.test.mmdb", 0, 16).unwrap();
+        db.set_com("list", vec![1_u32, 2, 3]).unwrap();
+        db.set_raw("other", 1_u8).unwrap();
+        let snapshot = db.snapshot().unwrap();
+
+        db.set_com("list", vec![4_u32]).unwrap();
+        db.set_raw("new", 2_u8).unwrap();
+        db.remove("other").unwrap();
+        db.save().unwrap();
+        assert_eq!(
+            snapshot.get_com::<Vec<u32>, _>("list").unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(snapshot.get_raw::<u8, _>("other").unwrap(), Some(1));
+        assert_eq!(snapshot.get_raw::<u8, _>("new").unwrap(), None);
+        assert_eq!(
+            snapshot.get_paths(None::<&str>).unwrap(),
+            vec!["list", "other"]
+        );
+        assert!(matches!(
+            snapshot.set_raw("new", 3_u8),
+            Err(Error::ReadOnly)
+        ));
+        assert_eq!(db.get_com::<Vec<u32>, _>("list").unwrap(), Some(vec![4]));
+        assert_eq!(db.get_paths(None::<&str>).unwrap(), vec!["list", "new"]);
+
+        drop(snapshot);
+        db.shutdown().unwrap();
+    }
+
+    #[test]
+    fn snapshot_leaves_database_alone() {
+        let _files = Files::new("snap-alone.test", &["dmdb", "mmdb", "mmdb.wal"]);
+        let db = MicroDB::create("snap-alone.test.dmdb", "snap-alone.test.mmdb", 0, 16).unwrap();
+        db.set_raw("a", 1_u8).unwrap();
+        let snapshot = db.snapshot().unwrap();
+        assert!(matches!(snapshot.check(true), Err(Error::ReadOnly)));
+        assert!(matches!(snapshot.compact(), Err(Error::ReadOnly)));
+        assert!(matches!(snapshot.save(), Err(Error::ReadOnly)));
+        snapshot.shutdown().unwrap();
+        let result = db.transaction(|tx| tx.check(false));
+        assert!(matches!(result, Err(Error::Unsupported)));
+
+        // the database itself keeps working
+        db.set_raw("b", 2_u8).unwrap();
+        db.save().unwrap();
+        assert_eq!(db.get_raw::<u8, _>("a").unwrap(), Some(1));
+        assert!(db.check(false).unwrap().problems.is_empty());
+        db.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,611 @@
+//! The write-ahead log of a [`crate::FAlloc`].
+//!
+//! Every mutation is appended here before it is acknowledged, so that a crash between writing
+//! values to the data file and saving the allocation table can be repaired by replaying the log.
+//! Records are framed with their length and a CRC32, which means a record that was only partially
+//! written before a crash is detected and dropped as a whole. The records of an encrypted
+//! database are sealed with its current key inside of their frame.
+//!
+//! Logs in a file are replaced as a whole when they are checkpointed. Logs in a
+//! [`StorageBackend`] are written in place instead: two slots at its start point at where the
+//! current records begin, and are written alternately so that one of them is always intact. A
+//! checkpoint writes the remaining records where they don't overlap the current ones before
+//! switching to them. Every record also carries the random id of its slot, so that records left
+//! over from earlier checkpoints are never mistaken for current ones.
+
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    backend::StorageBackend,
+    encryption::{self, Keys},
+    format::Header,
+};
+
+/// A single logged mutation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Record {
+    /// Sets a value. Empty data deletes the item.
+    Set { path: String, data: Vec<u8> },
+    /// Deletes everything below the path.
+    DeleteSubstructure { path: String },
+    /// Several mutations that are applied together or not at all.
+    Batch(Vec<Record>),
+}
+
+const SET: u8 = 0;
+const DELETE_SUBSTRUCTURE: u8 = 1;
+const BATCH: u8 = 2;
+
+impl Record {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Record::Set { path, data } => {
+                buf.push(SET);
+                buf.extend_from_slice(&(path.len() as u64).to_be_bytes());
+                buf.extend_from_slice(path.as_bytes());
+                buf.extend_from_slice(&(data.len() as u64).to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            Record::DeleteSubstructure { path } => {
+                buf.push(DELETE_SUBSTRUCTURE);
+                buf.extend_from_slice(&(path.len() as u64).to_be_bytes());
+                buf.extend_from_slice(path.as_bytes());
+            }
+            Record::Batch(records) => {
+                buf.push(BATCH);
+                buf.extend_from_slice(&(records.len() as u64).to_be_bytes());
+                for record in records {
+                    record.encode(buf);
+                }
+            }
+        }
+    }
+
+    fn decode(mut buf: &[u8]) -> Option<Self> {
+        let record = Self::decode_from(&mut buf)?;
+        buf.is_empty().then_some(record)
+    }
+
+    fn decode_from(buf: &mut &[u8]) -> Option<Self> {
+        fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+            if buf.len() < n {
+                return None;
+            }
+            let (x, rest) = buf.split_at(n);
+            *buf = rest;
+            Some(x)
+        }
+        fn take_u64(buf: &mut &[u8]) -> Option<usize> {
+            Some(u64::from_be_bytes(take(buf, 8)?.try_into().ok()?) as usize)
+        }
+
+        fn take_path(buf: &mut &[u8]) -> Option<String> {
+            let path_len = take_u64(buf)?;
+            String::from_utf8(take(buf, path_len)?.to_vec()).ok()
+        }
+
+        let kind = take(buf, 1)?[0];
+        Some(match kind {
+            SET => {
+                let path = take_path(buf)?;
+                let data_len = take_u64(buf)?;
+                Record::Set {
+                    path,
+                    data: take(buf, data_len)?.to_vec(),
+                }
+            }
+            DELETE_SUBSTRUCTURE => Record::DeleteSubstructure {
+                path: take_path(buf)?,
+            },
+            BATCH => {
+                let len = take_u64(buf)?;
+                let mut records = Vec::new();
+                for _ in 0..len {
+                    records.push(Self::decode_from(buf)?);
+                }
+                Record::Batch(records)
+            }
+            _ => return None,
+        })
+    }
+
+    /// Encodes the record including its length and checksum.
+    pub(crate) fn frame(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        self.encode(&mut payload);
+        frame(payload)
+    }
+
+    /// Reads a record that was encoded with [`Self::frame`] from a stream.
+    pub(crate) fn read_frame(r: &mut impl Read) -> Result<Self, io::Error> {
+        let mut header = [0_u8; 12];
+        r.read_exact(&mut header)?;
+        let len = u64::from_be_bytes(header[0..8].try_into().unwrap());
+        let crc = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let mut payload = Vec::new();
+        r.take(len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if crc32fast::hash(&payload) != crc {
+            return Err(ErrorKind::InvalidData.into());
+        }
+        Self::decode(&payload).ok_or(ErrorKind::InvalidData.into())
+    }
+}
+
+/// Prefixes a payload with its length and checksum.
+fn frame(mut payload: Vec<u8>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 12);
+    frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    frame.append(&mut payload);
+    frame
+}
+
+/// Decodes all records that were completely written. Returns them together with the number of
+/// bytes they take up. Records in a [`StorageBackend`] must start with the `id` of their slot.
+fn parse(bytes: &[u8], keys: &Keys, id: Option<u64>) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut valid = 0;
+    let mut rest = bytes;
+    while rest.len() >= 12 {
+        let len = u64::from_be_bytes(rest[0..8].try_into().unwrap()) as usize;
+        let crc = u32::from_be_bytes(rest[8..12].try_into().unwrap());
+        if rest.len() - 12 < len {
+            break;
+        }
+        let mut payload = &rest[12..12 + len];
+        if crc32fast::hash(payload) != crc {
+            break;
+        }
+        if let Some(id) = id {
+            match payload.split_first_chunk() {
+                Some((found, rest)) if *found == id.to_be_bytes() => payload = rest,
+                _ => break,
+            }
+        }
+        let Some(payload) = keys.open(encryption::LOG, payload.to_vec()) else {
+            break;
+        };
+        let Some(record) = Record::decode(&payload) else {
+            break;
+        };
+        records.push(record);
+        rest = &rest[12 + len..];
+        valid += 12 + len;
+    }
+    (records, valid)
+}
+
+/// Where a log is kept.
+#[derive(Debug)]
+enum Storage {
+    /// A file next to the allocation table.
+    File { filename: String, file: File },
+    /// A backend, with the slot that is current and its index.
+    Backend {
+        backend: Box<dyn StorageBackend>,
+        slot: LogSlot,
+        index: usize,
+    },
+}
+
+const LOG_SLOT_LEN: u64 = 32;
+/// Where the records of a log in a [`StorageBackend`] can start.
+const LOG_HEADER_LEN: u64 = 2 * LOG_SLOT_LEN;
+
+/// Points to the current records of a log in a [`StorageBackend`].
+#[derive(Debug, Clone, Copy)]
+struct LogSlot {
+    /// Incremented on every checkpoint. The valid slot with the highest one is current.
+    generation: u64,
+    /// Random, and part of every record written while this slot is current.
+    id: u64,
+    /// Where the records start.
+    start: u64,
+}
+
+impl LogSlot {
+    fn new(generation: u64, start: u64) -> Self {
+        Self {
+            generation,
+            id: Header::generate().uuid as u64,
+            start,
+        }
+    }
+
+    /// Writes the slot with the given index. Its own checksum makes a torn write detectable.
+    fn write(&self, f: &mut dyn StorageBackend, index: usize) -> Result<(), io::Error> {
+        let mut buf = Vec::with_capacity(LOG_SLOT_LEN as usize);
+        buf.extend_from_slice(&self.generation.to_be_bytes());
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.start.to_be_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&buf).to_be_bytes());
+        buf.resize(LOG_SLOT_LEN as usize, 0);
+        f.write_at(index as u64 * LOG_SLOT_LEN, &buf)
+    }
+
+    /// Reads the current slot and its index. None if neither slot is intact.
+    fn read_current(f: &mut dyn StorageBackend) -> Result<Option<(Self, usize)>, io::Error> {
+        let mut current: Option<(Self, usize)> = None;
+        for index in 0..2 {
+            let mut buf = [0_u8; 28];
+            match f.read_at(index as u64 * LOG_SLOT_LEN, &mut buf) {
+                Ok(()) => (),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => continue,
+                Err(e) => return Err(e),
+            }
+            let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+            if crc32fast::hash(&buf[..24]) != u32::from_be_bytes(buf[24..].try_into().unwrap()) {
+                continue;
+            }
+            let slot = Self {
+                generation: u64_at(0),
+                id: u64_at(8),
+                start: u64_at(16),
+            };
+            if current.is_none_or(|x| x.0.generation < slot.generation) {
+                current = Some((slot, index));
+            }
+        }
+        Ok(current)
+    }
+}
+
+/// An append-only log of [`Record`]s next to the allocation table, or in a [`StorageBackend`].
+#[derive(Debug)]
+pub(crate) struct Wal {
+    storage: Storage,
+    len: u64,
+    unsynced: bool,
+    /// Set when a failed append could not be undone, so that nothing is appended after it.
+    failed: bool,
+    keys: Keys,
+}
+
+impl Wal {
+    /// Creates an empty log, replacing any leftover one.
+    pub(crate) fn create(filename: String, keys: Keys) -> Result<Self, io::Error> {
+        let file = File::create(&filename)?;
+        file.sync_all()?;
+        Ok(Self {
+            storage: Storage::File { filename, file },
+            len: 0,
+            unsynced: false,
+            failed: false,
+            keys,
+        })
+    }
+
+    /// Creates an empty log in a backend, overwriting whatever it contained.
+    pub(crate) fn create_in(
+        mut backend: Box<dyn StorageBackend>,
+        keys: Keys,
+    ) -> Result<Self, io::Error> {
+        let slot = LogSlot::new(0, LOG_HEADER_LEN);
+        backend.set_len(LOG_HEADER_LEN)?;
+        backend.write_at(0, &[0; LOG_HEADER_LEN as usize])?;
+        slot.write(&mut *backend, 0)?;
+        backend.sync()?;
+        Ok(Self {
+            storage: Storage::Backend {
+                backend,
+                slot,
+                index: 0,
+            },
+            len: 0,
+            unsynced: false,
+            failed: false,
+            keys,
+        })
+    }
+
+    /// Opens a log and returns all records that were completely written. A torn record at the
+    /// end (from a crash during appending) is cut off. A missing log is treated as empty.
+    pub(crate) fn open(filename: String, keys: Keys) -> Result<(Self, Vec<Record>), io::Error> {
+        let mut bytes = Vec::new();
+        match File::open(&filename) {
+            Ok(mut f) => {
+                f.read_to_end(&mut bytes)?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok((Self::create(filename, keys)?, Vec::new()))
+            }
+            Err(e) => return Err(e),
+        }
+
+        let (records, valid) = parse(&bytes, &keys, None);
+        let file = File::options().append(true).open(&filename)?;
+        if valid != bytes.len() {
+            file.set_len(valid as u64)?;
+            file.sync_all()?;
+        }
+        Ok((
+            Self {
+                storage: Storage::File { filename, file },
+                len: valid as u64,
+                unsynced: false,
+                failed: false,
+                keys,
+            },
+            records,
+        ))
+    }
+
+    /// Opens a log in a backend, like [`Self::open`]. A backend that doesn't contain one yet
+    /// is treated as empty.
+    pub(crate) fn open_in(
+        mut backend: Box<dyn StorageBackend>,
+        keys: Keys,
+    ) -> Result<(Self, Vec<Record>), io::Error> {
+        let Some((slot, index)) = LogSlot::read_current(&mut *backend)? else {
+            return Ok((Self::create_in(backend, keys)?, Vec::new()));
+        };
+        let mut bytes = vec![0_u8; backend.len()?.saturating_sub(slot.start) as usize];
+        backend.read_at(slot.start, &mut bytes)?;
+        // whatever follows the valid records is overwritten by the next ones
+        let (records, valid) = parse(&bytes, &keys, Some(slot.id));
+        Ok((
+            Self {
+                storage: Storage::Backend {
+                    backend,
+                    slot,
+                    index,
+                },
+                len: valid as u64,
+                unsynced: false,
+                failed: false,
+                keys,
+            },
+            records,
+        ))
+    }
+
+    /// Returns all records that were completely written, without changing the log. A missing
+    /// log is treated as empty.
+    pub(crate) fn read(filename: &str, keys: &Keys) -> Result<Vec<Record>, io::Error> {
+        match fs::read(filename) {
+            Ok(bytes) => Ok(parse(&bytes, keys, None).0),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Appends a record. It is written to the OS immediately, so it survives the process
+    /// crashing. Use [`Self::sync`] to make it survive power loss.
+    pub(crate) fn append(&mut self, record: &Record) -> Result<(), io::Error> {
+        if self.failed {
+            return Err(io::Error::other(
+                "an earlier record could not be removed from the log after it failed",
+            ));
+        }
+        let frame = self.frame(record, self.id());
+        match &mut self.storage {
+            Storage::File { file, .. } => {
+                if let Err(e) = file.write_all(&frame) {
+                    self.discard_tail();
+                    return Err(e);
+                }
+            }
+            // a torn record is overwritten by the next one
+            Storage::Backend { backend, slot, .. } => {
+                backend.write_at(slot.start + self.len, &frame)?
+            }
+        }
+        self.len += frame.len() as u64;
+        self.unsynced = true;
+        Ok(())
+    }
+
+    /// Cuts off what a failed append left of its record. Otherwise, the records appended after
+    /// it would be dropped together with it when the log is opened. If even that fails, nothing
+    /// can be appended until the next checkpoint.
+    fn discard_tail(&mut self) {
+        if let Storage::File { file, .. } = &mut self.storage {
+            let len = self.len;
+            if file
+                .set_len(len)
+                .and_then(|()| file.seek(SeekFrom::Start(len)))
+                .is_err()
+            {
+                self.failed = true;
+            }
+        }
+    }
+
+    /// The id records must start with, if the log is in a backend.
+    fn id(&self) -> Option<u64> {
+        match &self.storage {
+            Storage::File { .. } => None,
+            Storage::Backend { slot, .. } => Some(slot.id),
+        }
+    }
+
+    /// Frames a record, sealing it if the database is encrypted.
+    fn frame(&self, record: &Record, id: Option<u64>) -> Vec<u8> {
+        let mut payload = Vec::new();
+        record.encode(&mut payload);
+        let sealed = self.keys.seal(encryption::LOG, payload);
+        match id {
+            Some(id) => frame([&id.to_be_bytes()[..], &sealed].concat()),
+            None => frame(sealed),
+        }
+    }
+
+    /// Switches the keys new records are sealed with, after the key was rotated.
+    pub(crate) fn set_keys(&mut self, keys: Keys) {
+        self.keys = keys;
+    }
+
+    /// Makes all appended records durable. Must be called before the data file is modified.
+    pub(crate) fn sync(&mut self) -> Result<(), io::Error> {
+        if self.unsynced {
+            match &mut self.storage {
+                Storage::File { file, .. } => file.sync_data()?,
+                Storage::Backend { backend, .. } => backend.sync()?,
+            }
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Replaces the log with only the given records. Call this once everything else has been
+    /// durably saved, passing the mutations that are not yet on disk.
+    pub(crate) fn checkpoint<'a>(
+        &mut self,
+        pending: impl Iterator<Item = (&'a String, &'a Vec<u8>)>,
+    ) -> Result<(), io::Error> {
+        let mut pending = pending.peekable();
+        if self.len == 0 && !self.failed && pending.peek().is_none() {
+            return Ok(());
+        }
+        let next = match &self.storage {
+            Storage::File { .. } => None,
+            Storage::Backend { slot, .. } => Some(LogSlot::new(slot.generation + 1, 0)),
+        };
+        let mut bytes = Vec::new();
+        for (path, data) in pending {
+            let record = Record::Set {
+                path: path.to_owned(),
+                data: data.to_owned(),
+            };
+            bytes.extend_from_slice(&self.frame(&record, next.map(|x| x.id)));
+        }
+        match &mut self.storage {
+            Storage::File { filename, file } => {
+                let tmp = filename.to_owned() + ".tmp";
+                let mut new = File::create(&tmp)?;
+                new.write_all(&bytes)?;
+                new.sync_all()?;
+                fs::rename(&tmp, &*filename)?;
+                *file = File::options().append(true).open(&*filename)?;
+            }
+            Storage::Backend {
+                backend,
+                slot,
+                index,
+            } => {
+                // the current records stay intact until the other slot points past them
+                let mut next = next.unwrap();
+                next.start = if LOG_HEADER_LEN + bytes.len() as u64 <= slot.start {
+                    LOG_HEADER_LEN
+                } else {
+                    slot.start + self.len
+                };
+                backend.write_at(next.start, &bytes)?;
+                backend.sync()?;
+                next.write(&mut **backend, 1 - *index)?;
+                backend.sync()?;
+                if next.start == LOG_HEADER_LEN {
+                    backend.set_len(next.start + bytes.len() as u64)?;
+                }
+                *slot = next;
+                *index = 1 - *index;
+            }
+        }
+        self.len = bytes.len() as u64;
+        self.unsynced = false;
+        self.failed = false;
+        Ok(())
+    }
+
+    /// Whether the log contains no records.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Deletes the log file, once nothing in it is needed anymore.
+    pub(crate) fn remove(self) -> Result<(), io::Error> {
+        match self.storage {
+            Storage::File { filename, file } => {
+                drop(file);
+                fs::remove_file(filename)
+            }
+            Storage::Backend { .. } => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, io::Write};
+
+    use super::{Keys, Record, Storage, Wal};
+    use crate::cleanup::Files;
+
+    #[test]
+    fn torn_tail() {
+        let _files = Files::new("wal.test", &["wal"]);
+        let mut wal = Wal::create("wal.test.wal".to_owned(), Keys::default()).unwrap();
+        let a = Record::Set {
+            path: "a".to_owned(),
+            data: vec![1, 2, 3],
+        };
+        let b = Record::Batch(vec![
+            Record::DeleteSubstructure {
+                path: "b".to_owned(),
+            },
+            a.clone(),
+        ]);
+        wal.append(&a).unwrap();
+        wal.append(&b).unwrap();
+        // half of a third record, as if the process died while writing it
+        let torn = Record::Set {
+            path: "c".to_owned(),
+            data: vec![4; 100],
+        }
+        .frame();
+        let Storage::File { file, .. } = &mut wal.storage else {
+            unreachable!()
+        };
+        file.write_all(&torn[..torn.len() / 2]).unwrap();
+        drop(wal);
+
+        let (mut wal, records) = Wal::open("wal.test.wal".to_owned(), Keys::default()).unwrap();
+        assert_eq!(records, vec![a.clone(), b]);
+        wal.append(&a).unwrap();
+        drop(wal);
+        let (_, records) = Wal::open("wal.test.wal".to_owned(), Keys::default()).unwrap();
+        assert_eq!(records.len(), 3);
+    }
+
+    #[test]
+    fn failed_append() {
+        let _files = Files::new("wal.failed", &["wal"]);
+        let mut wal = Wal::create("wal.failed.wal".to_owned(), Keys::default()).unwrap();
+        let record = |path: &str| Record::Set {
+            path: path.to_owned(),
+            data: vec![1; 100],
+        };
+        wal.append(&record("a")).unwrap();
+        // what is left of a record that failed halfway
+        let torn = record("b").frame();
+        let Storage::File { file, .. } = &mut wal.storage else {
+            unreachable!()
+        };
+        file.write_all(&torn[..torn.len() / 2]).unwrap();
+        wal.discard_tail();
+        wal.append(&record("c")).unwrap();
+
+        // a log that can't be cut back takes nothing more until it is checkpointed
+        let Storage::File { file, .. } = &mut wal.storage else {
+            unreachable!()
+        };
+        *file = File::open("wal.failed.wal").unwrap();
+        assert!(wal.append(&record("d")).is_err());
+        assert!(wal.failed);
+        drop(wal);
+        let (mut wal, records) = Wal::open("wal.failed.wal".to_owned(), Keys::default()).unwrap();
+        assert_eq!(records, vec![record("a"), record("c")]);
+        wal.failed = true;
+        wal.checkpoint([].into_iter()).unwrap();
+        wal.append(&record("e")).unwrap();
+        drop(wal);
+        let (_, records) = Wal::open("wal.failed.wal".to_owned(), Keys::default()).unwrap();
+        assert_eq!(records, vec![record("e")]);
+    }
+}
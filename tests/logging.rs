@@ -0,0 +1,163 @@
+//! Diagnostics are recorded by a global logger or subscriber, so these tests have a process of
+//! their own, where no other test emits events.
+
+#![cfg(any(feature = "log", feature = "tracing"))]
+
+use std::fs;
+
+use microdb::FAlloc;
+
+/// Removes the files of a database when dropped, even if the test panicked.
+struct Files<'a>(&'a str);
+
+impl Drop for Files<'_> {
+    fn drop(&mut self) {
+        for ext in ["dat", "alloc", "alloc.wal"] {
+            let _ = fs::remove_file(format!("{}.{ext}", self.0));
+        }
+    }
+}
+
+/// Saves a database, which flushes the cache and appends to the allocation table's journal.
+fn save_something(name: &str) {
+    let _files = Files(name);
+    let db = FAlloc::create(format!("{name}.dat"), format!("{name}.alloc"), 0, 16).unwrap();
+    db.set("a", vec![1; 100]).unwrap();
+    db.save().unwrap();
+    db.shutdown().unwrap();
+}
+
+#[cfg(all(feature = "log", not(feature = "tracing")))]
+mod log {
+    use std::sync::Mutex;
+
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+
+    static EVENTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct Recorder;
+
+    impl Log for Recorder {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Debug
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                EVENTS.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn events() {
+        log::set_logger(&Recorder).unwrap();
+        log::set_max_level(LevelFilter::Debug);
+        super::save_something("logging.log");
+        let events = EVENTS.lock().unwrap();
+        assert!(events.iter().any(|x| x == "flushed the cache"));
+        assert!(events.iter().any(|x| x.contains("allocation table")));
+    }
+}
+
+#[cfg(feature = "tracing")]
+mod tracing {
+    use std::{
+        cell::RefCell,
+        fmt::Debug,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        },
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    /// An event: its message, the names of its fields, and the span it happened in.
+    type Recorded = (String, Vec<&'static str>, Option<&'static str>);
+
+    static EVENTS: Mutex<Vec<Recorded>> = Mutex::new(Vec::new());
+    static SPANS: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    thread_local! {
+        /// The spans this thread is in, innermost last.
+        static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+    }
+
+    #[derive(Default)]
+    struct Fields {
+        message: String,
+        names: Vec<&'static str>,
+    }
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            match field.name() {
+                "message" => self.message = format!("{value:?}"),
+                name => self.names.push(name),
+            }
+        }
+    }
+
+    struct Recorder {
+        next: AtomicU64,
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            let mut spans = SPANS.lock().unwrap();
+            spans.push(span.metadata().name());
+            span::Id::from_u64(self.next.fetch_add(1, Ordering::Relaxed))
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let span = ENTERED.with(|x| x.borrow().last().copied());
+            let span = span.map(|id| SPANS.lock().unwrap()[id as usize - 1]);
+            EVENTS
+                .lock()
+                .unwrap()
+                .push((fields.message, fields.names, span));
+        }
+
+        fn enter(&self, id: &span::Id) {
+            ENTERED.with(|x| x.borrow_mut().push(id.into_u64()));
+        }
+
+        fn exit(&self, _: &span::Id) {
+            ENTERED.with(|x| x.borrow_mut().pop());
+        }
+    }
+
+    #[test]
+    fn events_in_spans() {
+        tracing::subscriber::set_global_default(Recorder {
+            next: AtomicU64::new(1),
+        })
+        .unwrap();
+        super::save_something("logging.tracing");
+        let events = EVENTS.lock().unwrap();
+        assert!(events.iter().any(|(message, names, span)| {
+            message == "flushed the cache"
+                && names.contains(&"bytes")
+                && *span == Some("flush_cache")
+        }));
+        assert!(events.iter().any(|(message, _, span)| {
+            message.contains("allocation table") && *span == Some("save_table")
+        }));
+    }
+}